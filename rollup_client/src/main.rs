@@ -26,6 +26,17 @@ pub struct GetTransaction {
     pub get_tx: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PrepareDelegationTransaction {
+    pub owner: String,
+    pub amount: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignedDelegationTransaction {
+    pub transaction: Transaction,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "status", content = "data")]
 pub enum TransactionResponse {
@@ -49,30 +60,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Initial Sender {} balance: {} SOL", sender.pubkey(), sender_balance as f64 / 1_000_000_000.0);
     println!("Initial Receiver {} balance: {} SOL", receiver.pubkey(), receiver_balance as f64 / 1_000_000_000.0);
 
-    // Initialize delegation service for sender
-    println!("\nInitializing delegation service for sender...");
+    // Delegate funds for sender and receiver. The rollup prepares the
+    // transaction, we sign it locally and hand it back to be relayed to L1,
+    // so our keys never leave this machine.
     let client = reqwest::Client::new();
-    let response = client
-        .post("http://127.0.0.1:8080/init_delegation_service")
-        .body(sender.to_bytes().to_vec())
-        .send()
-        .await?;
-    println!("Sender delegation service init response: {:?}", response.text().await?);
-
-    // Wait for initialization
-    tokio::time::sleep(Duration::from_secs(2)).await;
-
-    // Initialize delegation service for receiver
-    println!("\nInitializing delegation service for receiver...");
-    let response = client
-        .post("http://127.0.0.1:8080/add_delegation_signer")
-        .body(receiver.to_bytes().to_vec())
-        .send()
-        .await?;
-    println!("Receiver delegation service init response: {:?}", response.text().await?);
+    for (name, keypair) in [("sender", &sender), ("receiver", &receiver)] {
+        println!("\nDelegating for {}...", name);
+        let mut delegation_tx: Transaction = client
+            .post("http://127.0.0.1:8080/prepare_delegation")
+            .json(&PrepareDelegationTransaction {
+                owner: keypair.pubkey().to_string(),
+                amount: LAMPORTS_PER_SOL,
+            })
+            .send()
+            .await?
+            .json()
+            .await?;
+        let blockhash = delegation_tx.message.recent_blockhash;
+        delegation_tx.try_sign(&[keypair], blockhash)?;
 
-    // Wait for initialization
-    tokio::time::sleep(Duration::from_secs(2)).await;
+        let response = client
+            .post("http://127.0.0.1:8080/submit_delegation")
+            .json(&SignedDelegationTransaction { transaction: delegation_tx })
+            .send()
+            .await?;
+        println!("{} delegation response: {:?}", name, response.text().await?);
+    }
 
     // Create test transactions
     let amounts = vec![5, -3, 9, -10, 1, -10, 4, -3, 9, -6];
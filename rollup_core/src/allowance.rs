@@ -0,0 +1,138 @@
+//! Spending allowances. A user can move at most what they delegated on L1 out
+//! of their accounts on the rollup. The ledger keeps what each owner already
+//! spent, and every transaction's outgoing lamports are checked against the
+//! remainder before it executes.
+//!
+//! Lamports an account receives on the rollup are never credited to its
+//! allowance. They are backed by the sender's delegation PDA, not the
+//! receiver's, so spending them would let the receiver move more than its own
+//! PDA holds. The receiver has to delegate to spend on the rollup.
+
+use std::{collections::HashMap, fmt, fs, path::PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{pubkey::Pubkey, transaction::Transaction};
+use solana_sdk_ids::system_program;
+use solana_system_interface::instruction::SystemInstruction;
+
+/// An owner's delegated lamports and how much of them is left to spend.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Allowance {
+    pub owner: Pubkey,
+    pub delegated: u64,
+    pub spent: u64,
+    pub remaining: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowanceError {
+    NoDelegation { owner: Pubkey },
+    Exceeded { owner: Pubkey, requested: u64, remaining: u64 },
+}
+
+impl fmt::Display for AllowanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoDelegation { owner } => {
+                write!(f, "{} has no delegation, delegate through /prepare_delegation first", owner)
+            }
+            Self::Exceeded { owner, requested, remaining } => write!(
+                f,
+                "{} spends {} lamports but only {} of its delegation are left, top up through /prepare_delegation",
+                owner, requested, remaining
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AllowanceError {}
+
+/// Lamports each account sends out in a transaction: system transfers and
+/// account creations it funds, plus the fee for the fee payer. Other programs
+/// can only debit accounts they own, so they never touch a user's wallet.
+pub fn outgoing_lamports(tx: &Transaction, lamports_per_signature: u64) -> HashMap<Pubkey, u64> {
+    let account_keys = &tx.message.account_keys;
+    let mut outgoing = fee_lamports(tx, lamports_per_signature);
+    for ix in tx.message.instructions.iter() {
+        if account_keys.get(ix.program_id_index as usize) != Some(&system_program::ID) {
+            continue;
+        }
+        let lamports = match bincode::deserialize::<SystemInstruction>(&ix.data) {
+            Ok(SystemInstruction::Transfer { lamports })
+            | Ok(SystemInstruction::TransferWithSeed { lamports, .. })
+            | Ok(SystemInstruction::CreateAccount { lamports, .. })
+            | Ok(SystemInstruction::CreateAccountWithSeed { lamports, .. }) => lamports,
+            _ => continue,
+        };
+        if let Some(from) = ix.accounts.first().and_then(|index| account_keys.get(*index as usize)) {
+            let entry = outgoing.entry(*from).or_insert(0u64);
+            *entry = entry.saturating_add(lamports);
+        }
+    }
+    outgoing.retain(|_, lamports| *lamports > 0);
+    outgoing
+}
+
+/// The fee the fee payer is charged, all a failed transaction spends.
+pub fn fee_lamports(tx: &Transaction, lamports_per_signature: u64) -> HashMap<Pubkey, u64> {
+    let mut outgoing = HashMap::new();
+    if let Some(fee_payer) = tx.message.account_keys.first() {
+        let fee = lamports_per_signature.saturating_mul(tx.message.header.num_required_signatures as u64);
+        outgoing.insert(*fee_payer, fee);
+    }
+    outgoing.retain(|_, lamports| *lamports > 0);
+    outgoing
+}
+
+/// Lamports each owner spent on the rollup, persisted so a restart doesn't
+/// hand out a fresh allowance.
+pub struct AllowanceLedger {
+    path: PathBuf,
+    spent: HashMap<Pubkey, u64>,
+}
+
+impl AllowanceLedger {
+    /// Fails on a file that doesn't parse, starting over would hand every
+    /// owner their whole delegation again.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut ledger = Self {
+            path,
+            spent: HashMap::new(),
+        };
+        if !ledger.path.exists() {
+            return Ok(ledger);
+        }
+        // Pubkeys aren't JSON map keys, the file holds (owner, spent) pairs
+        let bytes = fs::read(&ledger.path)?;
+        let spent: Vec<(Pubkey, u64)> = serde_json::from_slice(&bytes)
+            .with_context(|| format!("allowance ledger {} is corrupt", ledger.path.display()))?;
+        ledger.spent = spent.into_iter().collect();
+        Ok(ledger)
+    }
+
+    pub fn spent(&self, owner: &Pubkey) -> u64 {
+        self.spent.get(owner).copied().unwrap_or(0)
+    }
+
+    pub fn record(&mut self, outgoing: &HashMap<Pubkey, u64>) {
+        for (owner, lamports) in outgoing.iter() {
+            let spent = self.spent.entry(*owner).or_insert(0);
+            *spent = spent.saturating_add(*lamports);
+        }
+        self.persist();
+    }
+
+    fn persist(&self) {
+        let spent: Vec<(&Pubkey, &u64)> = self.spent.iter().collect();
+        // Renamed over the ledger, a crash never leaves it half written
+        let tmp_path = self.path.with_extension("tmp");
+        let result = serde_json::to_vec(&spent)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| fs::write(&tmp_path, bytes).map_err(anyhow::Error::from))
+            .and_then(|_| fs::rename(&tmp_path, &self.path).map_err(anyhow::Error::from));
+        if let Err(e) = result {
+            log::error!("Failed to persist allowance ledger: {}", e);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{clock::Slot, hash::Hash, instruction::Instruction, pubkey::Pubkey, signature::Signature};
+
+use crate::{da::DaCommitment, settle::SettlementStatus, withdrawal::Withdrawal};
+
+/// Everything needed to audit a batch, from the rollup transactions it
+/// contained down to the L1 transaction(s) that settled it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchRecord {
+    pub batch_id: u64,
+    pub transactions: Vec<Signature>,
+    pub pre_state_root: Hash,
+    pub post_state_root: Hash,
+    pub netted_instructions: Vec<Instruction>,
+    /// Withdrawals paid out on L1 by this batch's settlement.
+    #[serde(default)]
+    pub withdrawals: Vec<Withdrawal>,
+    /// Inbox entries below this index were included by this batch or earlier ones.
+    #[serde(default)]
+    pub inbox_index: u64,
+    /// Accounts this batch cloned from L1, and the L1 slot they were read at.
+    #[serde(default)]
+    pub cloned_accounts: Vec<(Pubkey, Slot)>,
+    /// Last block in the batch's data, blocks after the previous batch's up to
+    /// this one.
+    #[serde(default)]
+    pub last_slot: Slot,
+    /// Where the batch's data was published, set once it is.
+    #[serde(default)]
+    pub da: Option<DaCommitment>,
+    /// Last L1 slot the batch's state root can be challenged in, set once
+    /// it settled. The batch is final after it.
+    #[serde(default)]
+    pub challenge_ends_at: Option<Slot>,
+    /// Every L1 signature the batch was sent with, the last one is the one that landed.
+    pub settlement_signatures: Vec<String>,
+    pub status: SettlementStatus,
+}
+
+// message format used by the frontend to look up batches
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BatchQuery {
+    ById(u64),
+    ByTransaction(Signature),
+    Latest(usize),
+}
+
+/// Append-only JSON-lines file of batch records. A record is appended every
+/// time its status changes, the last line for a batch id wins on load.
+pub struct BatchJournal {
+    path: PathBuf,
+}
+
+impl BatchJournal {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn append(&self, record: &BatchRecord) -> Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    pub fn load(&self) -> Result<BTreeMap<u64, BatchRecord>> {
+        let mut records = BTreeMap::new();
+        if !self.path.exists() {
+            return Ok(records);
+        }
+        for line in fs::read_to_string(&self.path)?.lines().filter(|line| !line.trim().is_empty()) {
+            let record: BatchRecord = serde_json::from_str(line)?;
+            records.insert(record.batch_id, record);
+        }
+        Ok(records)
+    }
+}
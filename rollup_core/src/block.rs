@@ -0,0 +1,246 @@
+//! Rollup blocks. The sequencer groups the transactions it executed, in
+//! order, into blocks and RollupDB seals each one on top of the previous:
+//! the block commits to its parent, its slot, its transactions, the state
+//! root after them and the time it was produced, so history can be replayed
+//! and referenced by position.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    account::AccountSharedData,
+    clock::{Slot, UnixTimestamp},
+    hash::{hashv, Hash},
+    pubkey::Pubkey,
+    signature::Signature,
+    transaction::Transaction,
+};
+
+use crate::loader::AccountOrigin;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Block {
+    pub slot: Slot,
+    pub parent_hash: Hash,
+    /// In execution order.
+    pub transactions: Vec<Signature>,
+    /// Root of the rollup's accounts after the block's transactions.
+    pub state_root: Hash,
+    pub timestamp: UnixTimestamp,
+    pub hash: Hash,
+}
+
+impl Block {
+    pub fn new(slot: Slot, parent_hash: Hash, transactions: Vec<Signature>, state_root: Hash, timestamp: UnixTimestamp) -> Self {
+        let hash = Self::compute_hash(slot, &parent_hash, &transactions, &state_root, timestamp);
+        Self {
+            slot,
+            parent_hash,
+            transactions,
+            state_root,
+            timestamp,
+            hash,
+        }
+    }
+
+    fn compute_hash(slot: Slot, parent_hash: &Hash, transactions: &[Signature], state_root: &Hash, timestamp: UnixTimestamp) -> Hash {
+        let slot = slot.to_le_bytes();
+        let timestamp = timestamp.to_le_bytes();
+        let mut fields: Vec<&[u8]> = vec![parent_hash.as_ref(), &slot, state_root.as_ref(), &timestamp];
+        fields.extend(transactions.iter().map(|signature| signature.as_ref()));
+        hashv(&fields)
+    }
+
+    /// Whether the hash matches the block's contents.
+    pub fn verify(&self) -> bool {
+        self.hash == Self::compute_hash(self.slot, &self.parent_hash, &self.transactions, &self.state_root, self.timestamp)
+    }
+}
+
+/// A change to the rollup's state, in the order RollupDB committed it.
+/// Replaying a block's entries on top of its parent's state reproduces its
+/// state root. `writes_hash` commits to the accounts each entry wrote, so a
+/// verifier can point at the exact entry that diverged.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BlockEntry {
+    /// Executed through the SVM. `l1_accounts` are the accounts it read from
+    /// L1 rather than the rollup's state, as they were read, and `credits`
+    /// the deposits that arrived while its accounts were locked.
+    Transaction {
+        transaction: Transaction,
+        l1_accounts: Vec<(Pubkey, AccountSharedData, AccountOrigin)>,
+        credits: Vec<(Pubkey, u64)>,
+        writes_hash: Hash,
+    },
+    /// Burns the amount, or is rejected if the balance is too low or it is
+    /// more than `payable`, what the owner's delegation PDA could still pay
+    /// out.
+    Withdrawal {
+        transaction: Transaction,
+        l1_account: Option<(AccountSharedData, AccountOrigin)>,
+        payable: u64,
+        writes_hash: Hash,
+    },
+    /// Lamports deposited on L1 and credited to `owner`.
+    Deposit {
+        owner: Pubkey,
+        lamports: u64,
+        writes_hash: Hash,
+    },
+    /// A burned withdrawal whose batch failed to settle, credited back to `owner`.
+    Refund {
+        withdrawal: Signature,
+        owner: Pubkey,
+        lamports: u64,
+        writes_hash: Hash,
+    },
+}
+
+impl BlockEntry {
+    /// What the accounts the entry wrote hash to, see `replay::writes_hash`.
+    pub fn writes_hash(&self) -> Hash {
+        match self {
+            Self::Transaction { writes_hash, .. }
+            | Self::Withdrawal { writes_hash, .. }
+            | Self::Deposit { writes_hash, .. }
+            | Self::Refund { writes_hash, .. } => *writes_hash,
+        }
+    }
+
+    pub fn signature(&self) -> Option<Signature> {
+        match self {
+            Self::Transaction { transaction, .. } | Self::Withdrawal { transaction, .. } => {
+                transaction.signatures.first().copied()
+            }
+            Self::Deposit { .. } | Self::Refund { .. } => None,
+        }
+    }
+}
+
+/// A block as journaled, with everything needed to replay it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockRecord {
+    #[serde(flatten)]
+    pub block: Block,
+    #[serde(default)]
+    pub entries: Vec<BlockEntry>,
+}
+
+/// Sent by the sequencer when a block is complete, RollupDB fills in the
+/// slot, parent and state root.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SealBlock {
+    pub transactions: Vec<Signature>,
+    pub timestamp: UnixTimestamp,
+}
+
+// message format used by the frontend to look up blocks
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BlockQuery {
+    BySlot(Slot),
+    ByTransaction(Signature),
+    Latest(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockConfig {
+    pub max_transactions: usize,
+    /// A block with transactions in it is sealed at the latest this long
+    /// after its first one.
+    pub interval: Duration,
+}
+
+/// The block the sequencer is filling.
+pub struct BlockBuilder {
+    config: BlockConfig,
+    transactions: Vec<Signature>,
+    opened_at: Instant,
+}
+
+impl BlockBuilder {
+    pub fn new(config: BlockConfig) -> Self {
+        Self {
+            config,
+            transactions: Vec::new(),
+            opened_at: Instant::now(),
+        }
+    }
+
+    pub fn push(&mut self, signature: Signature) {
+        if self.transactions.is_empty() {
+            self.opened_at = Instant::now();
+        }
+        self.transactions.push(signature);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Whether the block is full or has been open for the block interval.
+    pub fn is_due(&self) -> bool {
+        !self.transactions.is_empty()
+            && (self.transactions.len() >= self.config.max_transactions || self.opened_at.elapsed() >= self.config.interval)
+    }
+
+    /// How long the sequencer can wait for transactions before the block is due.
+    pub fn time_left(&self) -> Duration {
+        if self.transactions.is_empty() {
+            return self.config.interval;
+        }
+        self.config.interval.saturating_sub(self.opened_at.elapsed())
+    }
+
+    pub fn seal(&mut self) -> SealBlock {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as UnixTimestamp)
+            .unwrap_or_default();
+        SealBlock {
+            transactions: std::mem::take(&mut self.transactions),
+            timestamp,
+        }
+    }
+}
+
+/// Append-only JSON-lines file of sealed blocks and their entries.
+pub struct BlockJournal {
+    path: PathBuf,
+}
+
+impl BlockJournal {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, record: &BlockRecord) -> Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    pub fn load(&self) -> Result<BTreeMap<Slot, BlockRecord>> {
+        let mut records = BTreeMap::new();
+        if !self.path.exists() {
+            return Ok(records);
+        }
+        for line in fs::read_to_string(&self.path)?.lines().filter(|line| !line.trim().is_empty()) {
+            let record: BlockRecord = serde_json::from_str(line)?;
+            records.insert(record.block.slot, record);
+        }
+        Ok(records)
+    }
+}
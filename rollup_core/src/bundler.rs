@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 
-use solana_sdk::{instruction::{CompiledInstruction, Instruction}, pubkey::Pubkey, system_instruction::{self, SystemInstruction}, system_program, transaction::Transaction};
+use solana_sdk::{instruction::{CompiledInstruction, Instruction}, pubkey::Pubkey, transaction::Transaction};
+use solana_sdk_ids::system_program;
+use solana_system_interface::instruction::{self as system_instruction, SystemInstruction};
 use bincode::deserialize;
 
 pub fn get_transaction_instructions(tx: &Transaction) -> Vec<CompiledInstruction>{
@@ -0,0 +1,832 @@
+//! Node configuration. Values come from an optional TOML file, CLI flags
+//! override the file, and everything is validated once at startup.
+
+use std::{fs, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    commitment_config::{CommitmentConfig, CommitmentLevel},
+    pubkey::Pubkey,
+    signature::Keypair,
+};
+
+use crate::da::DaBackend;
+use crate::follower::BlockSource;
+use crate::loader::ClonePolicy;
+use crate::plugin::PluginConfig;
+use crate::signer::{self, OperatorSigner};
+
+#[derive(Parser, Debug)]
+#[command(name = "rollup_core", about = "SVM rollup node")]
+pub struct Cli {
+    /// Path to a TOML config file
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    /// L1 JSON RPC url
+    #[arg(long)]
+    pub rpc_url: Option<String>,
+    /// Run against the in-process mock L1 instead of a cluster
+    #[arg(long)]
+    pub mock_l1: bool,
+    /// HTTP bind address, e.g. 127.0.0.1:8080
+    #[arg(long)]
+    pub bind: Option<String>,
+    /// Directory for journals and other node data
+    #[arg(long)]
+    pub storage_path: Option<PathBuf>,
+    /// Solana CLI keypair file of the operator
+    #[arg(long)]
+    pub operator_keypair: Option<PathBuf>,
+    /// Number of transactions per settled batch
+    #[arg(long)]
+    pub batch_size: Option<u32>,
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Snapshot archive to boot from
+    #[arg(long)]
+    pub restore_snapshot: Option<PathBuf>,
+    /// Genesis file of the rollup instance
+    #[arg(long)]
+    pub genesis: Option<PathBuf>,
+    /// Whether the node sequences transactions or follows a sequencer
+    #[arg(long, value_enum)]
+    pub mode: Option<NodeMode>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Encrypts a Solana CLI keypair file into a keystore, the password is
+    /// read from the ROLLUP_KEYSTORE_PASSWORD environment variable
+    Keystore {
+        /// Keypair file to encrypt
+        keypair: PathBuf,
+        /// Where to write the keystore
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Re-executes the block journal from the genesis, or from a snapshot,
+    /// and checks every block reproduces its state root
+    Replay {
+        /// Block journal to replay, storage/blocks.jsonl by default
+        #[arg(long)]
+        blocks: Option<PathBuf>,
+        /// Snapshot to start from instead of the genesis
+        #[arg(long)]
+        snapshot: Option<PathBuf>,
+    },
+    /// Rebuilds the block journal from the batches an operator settled on L1,
+    /// fetching their data from the backend each one names, for `replay --blocks`
+    FetchDa {
+        /// Operator pubkey the batches were settled by
+        operator: String,
+        /// Block journal to write, must not exist yet
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Re-executes the batches an operator settles on L1 and, at the first
+    /// one that doesn't reproduce, writes a fraud challenge and exits
+    Verify {
+        /// Operator pubkey the batches are settled by
+        operator: String,
+        /// Where to write challenges, storage/challenges by default
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// Keep following new settlements instead of stopping at the last one
+        #[arg(long)]
+        follow: bool,
+    },
+}
+
+/// What the node does with the rollup's state.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeMode {
+    /// Sequences and executes transactions, seals blocks and settles batches.
+    #[default]
+    Sequencer,
+    /// Executes the sequencer's blocks and serves the read-only RPC.
+    Follower,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub rpc_url: String,
+    /// Websocket endpoint for subscriptions, derived from `rpc_url` if unset
+    pub ws_url: Option<String>,
+    /// One of processed, confirmed, finalized
+    pub commitment: String,
+    pub mock: bool,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            rpc_url: "https://api.devnet.solana.com".to_string(),
+            ws_url: None,
+            commitment: "confirmed".to_string(),
+            mock: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub worker_threads: usize,
+    pub max_blocking_threads: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "127.0.0.1:8080".to_string(),
+            worker_threads: 4,
+            max_blocking_threads: 2,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// Worker threads of the runtime the sequencer and RollupDB run on
+    pub worker_threads: usize,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self { worker_threads: 4 }
+    }
+}
+
+/// Source of the operator key, at most one of the three may be set. A
+/// throwaway keypair is used when none is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// Solana CLI keypair file
+    pub operator_keypair: Option<PathBuf>,
+    /// Encrypted keystore, see `rollup_core keystore --help`
+    pub operator_keystore: Option<PathBuf>,
+    /// Environment variable holding the keystore password
+    pub keystore_password_env: String,
+    pub remote_signer_url: Option<String>,
+    pub remote_signer_pubkey: Option<String>,
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        Self {
+            operator_keypair: None,
+            operator_keystore: None,
+            keystore_password_env: "ROLLUP_KEYSTORE_PASSWORD".to_string(),
+            remote_signer_url: None,
+            remote_signer_pubkey: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub path: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self { path: PathBuf::from(".") }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    /// Transactions executed before a batch is bundled and settled
+    pub max_transactions: u32,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self { max_transactions: 10 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlocksConfig {
+    /// Transactions after which a block is sealed
+    pub max_transactions: usize,
+    /// A block is sealed at the latest this long after its first transaction
+    pub interval_ms: u64,
+}
+
+impl Default for BlocksConfig {
+    fn default() -> Self {
+        Self {
+            max_transactions: 64,
+            interval_ms: 400,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeConfig {
+    pub lamports_per_signature: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DelegationConfig {
+    pub program_id: String,
+    /// Whether the program has the rollup's instructions (post_state_root,
+    /// settle_withdrawal, force_exit, enqueue_transaction, challenge_state_root).
+    /// The deployed program only has initialize_delegate, top_up and withdraw,
+    /// the mock L1 has all of them.
+    pub rollup_instructions: bool,
+}
+
+impl Default for DelegationConfig {
+    fn default() -> Self {
+        Self {
+            program_id: "5MSF4TiUfD7dVm7P1ahPYJfEBLCUQn7hEPYXYHocVwzh".to_string(),
+            rollup_instructions: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SettlementSection {
+    /// Commitment a settlement has to reach, one of processed, confirmed, finalized
+    pub commitment: String,
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub poll_interval_ms: u64,
+    /// L1 slots a settled batch can be challenged for, about a day by default
+    pub challenge_period_slots: u64,
+}
+
+impl Default for SettlementSection {
+    fn default() -> Self {
+        Self {
+            commitment: "confirmed".to_string(),
+            max_attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            poll_interval_ms: 500,
+            challenge_period_slots: 216_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DepositsConfig {
+    /// Whether deposits into the delegation program are credited on the rollup
+    pub enabled: bool,
+    pub poll_interval_ms: u64,
+    /// Slots a deposit has to be behind the L1 tip before it is credited
+    pub confirmation_depth: u64,
+}
+
+impl Default for DepositsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            poll_interval_ms: 2_000,
+            confirmation_depth: 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct InboxConfig {
+    /// Whether transactions queued in the L1 inbox are force-included
+    pub enabled: bool,
+    pub poll_interval_ms: u64,
+}
+
+impl Default for InboxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            poll_interval_ms: 2_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    /// Whether accounts the rollup doesn't hold yet are taken from L1, one of
+    /// never, on_first_read, read_only_mirror
+    pub l1_clone: ClonePolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotsConfig {
+    /// A snapshot is taken every this many batches, 0 disables them
+    pub interval_batches: u64,
+    /// Snapshots kept in the storage directory, older ones are deleted
+    pub keep: usize,
+    /// Snapshot archive to boot from
+    pub restore_from: Option<PathBuf>,
+}
+
+impl Default for SnapshotsConfig {
+    fn default() -> Self {
+        Self {
+            interval_batches: 100,
+            keep: 3,
+            restore_from: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaConfig {
+    /// Where batch data is published, one of l1_memo, l1_buffer, file, http
+    pub backend: DaBackend,
+    /// Directory of the file backend, storage/da if unset
+    pub dir: Option<PathBuf>,
+    /// Base url of the http backend's blob store
+    pub url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FollowerSection {
+    /// Where blocks come from, one of sequencer, da
+    pub source: BlockSource,
+    /// Node whose /stream_blocks is followed, with the sequencer source
+    pub sequencer_url: String,
+    /// Operator whose settled batches are followed, with the da source
+    pub operator: Option<String>,
+    /// Wait before reconnecting, or between L1 polls
+    pub poll_interval_ms: u64,
+}
+
+impl Default for FollowerSection {
+    fn default() -> Self {
+        Self {
+            source: BlockSource::default(),
+            sequencer_url: "http://127.0.0.1:8080".to_string(),
+            operator: None,
+            poll_interval_ms: 2_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub log_level: String,
+    pub mode: NodeMode,
+    /// Genesis file, the rollup starts out empty without one
+    pub genesis: Option<PathBuf>,
+    pub cluster: ClusterConfig,
+    pub server: ServerConfig,
+    pub runtime: RuntimeConfig,
+    pub keys: KeysConfig,
+    pub storage: StorageConfig,
+    pub batch: BatchConfig,
+    pub blocks: BlocksConfig,
+    pub fees: FeeConfig,
+    pub delegation: DelegationConfig,
+    pub settlement: SettlementSection,
+    pub deposits: DepositsConfig,
+    pub inbox: InboxConfig,
+    pub accounts: AccountsConfig,
+    pub snapshots: SnapshotsConfig,
+    pub da: DaConfig,
+    pub follower: FollowerSection,
+    /// `[[plugins]]` entries, notified of every state change in commit order
+    pub plugins: Vec<PluginConfig>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            log_level: "debug".to_string(),
+            mode: NodeMode::default(),
+            genesis: None,
+            cluster: ClusterConfig::default(),
+            server: ServerConfig::default(),
+            runtime: RuntimeConfig::default(),
+            keys: KeysConfig::default(),
+            storage: StorageConfig::default(),
+            batch: BatchConfig::default(),
+            blocks: BlocksConfig::default(),
+            fees: FeeConfig::default(),
+            delegation: DelegationConfig::default(),
+            settlement: SettlementSection::default(),
+            deposits: DepositsConfig::default(),
+            inbox: InboxConfig::default(),
+            accounts: AccountsConfig::default(),
+            snapshots: SnapshotsConfig::default(),
+            da: DaConfig::default(),
+            follower: FollowerSection::default(),
+            plugins: Vec::new(),
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Reads the config file given on the command line (if any), applies
+    /// the CLI overrides and validates the result.
+    pub fn load(cli: Cli) -> Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read config file {}", path.display()))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("failed to parse config file {}", path.display()))?
+            }
+            None => NodeConfig::default(),
+        };
+
+        if let Some(rpc_url) = cli.rpc_url {
+            config.cluster.rpc_url = rpc_url;
+        }
+        if cli.mock_l1 {
+            config.cluster.mock = true;
+        }
+        if let Some(bind) = cli.bind {
+            config.server.bind_address = bind;
+        }
+        if let Some(storage_path) = cli.storage_path {
+            config.storage.path = storage_path;
+        }
+        if let Some(operator_keypair) = cli.operator_keypair {
+            config.keys.operator_keypair = Some(operator_keypair);
+        }
+        if let Some(batch_size) = cli.batch_size {
+            config.batch.max_transactions = batch_size;
+        }
+        if let Some(log_level) = cli.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(genesis) = cli.genesis {
+            config.genesis = Some(genesis);
+        }
+        if let Some(restore_snapshot) = cli.restore_snapshot {
+            config.snapshots.restore_from = Some(restore_snapshot);
+        }
+        if let Some(mode) = cli.mode {
+            config.mode = mode;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        log::LevelFilter::from_str(&self.log_level)
+            .map_err(|_| anyhow!("invalid log_level {:?}", self.log_level))?;
+        if !self.cluster.mock && !self.cluster.rpc_url.starts_with("http") {
+            return Err(anyhow!("cluster.rpc_url must be an http(s) url, got {:?}", self.cluster.rpc_url));
+        }
+        if let Some(ws_url) = &self.cluster.ws_url {
+            if !ws_url.starts_with("ws") {
+                return Err(anyhow!("cluster.ws_url must be a ws(s) url, got {:?}", ws_url));
+            }
+        }
+        parse_commitment(&self.cluster.commitment).context("cluster.commitment")?;
+        parse_commitment(&self.settlement.commitment).context("settlement.commitment")?;
+        self.server
+            .bind_address
+            .parse::<SocketAddr>()
+            .with_context(|| format!("invalid server.bind_address {:?}", self.server.bind_address))?;
+        if self.server.worker_threads == 0 || self.runtime.worker_threads == 0 {
+            return Err(anyhow!("worker thread counts must be at least 1"));
+        }
+        let key_sources = [
+            self.keys.operator_keypair.is_some(),
+            self.keys.operator_keystore.is_some(),
+            self.keys.remote_signer_url.is_some(),
+        ];
+        if key_sources.iter().filter(|set| **set).count() > 1 {
+            return Err(anyhow!("only one of keys.operator_keypair, keys.operator_keystore and keys.remote_signer_url may be set"));
+        }
+        for path in [&self.keys.operator_keypair, &self.keys.operator_keystore].into_iter().flatten() {
+            if !path.is_file() {
+                return Err(anyhow!("key file {} does not exist", path.display()));
+            }
+        }
+        if self.keys.remote_signer_url.is_some() {
+            let pubkey = self
+                .keys
+                .remote_signer_pubkey
+                .as_deref()
+                .ok_or_else(|| anyhow!("keys.remote_signer_pubkey is required with keys.remote_signer_url"))?;
+            Pubkey::from_str(pubkey).map_err(|_| anyhow!("invalid keys.remote_signer_pubkey {:?}", pubkey))?;
+        }
+        if self.storage.path.exists() && !self.storage.path.is_dir() {
+            return Err(anyhow!("storage.path {} is not a directory", self.storage.path.display()));
+        }
+        if self.batch.max_transactions == 0 {
+            return Err(anyhow!("batch.max_transactions must be at least 1"));
+        }
+        if self.blocks.max_transactions == 0 {
+            return Err(anyhow!("blocks.max_transactions must be at least 1"));
+        }
+        if self.blocks.interval_ms == 0 {
+            return Err(anyhow!("blocks.interval_ms must be greater than 0"));
+        }
+        Pubkey::from_str(&self.delegation.program_id)
+            .map_err(|_| anyhow!("invalid delegation.program_id {:?}", self.delegation.program_id))?;
+        if self.settlement.max_attempts == 0 {
+            return Err(anyhow!("settlement.max_attempts must be at least 1"));
+        }
+        if self.deposits.poll_interval_ms == 0 {
+            return Err(anyhow!("deposits.poll_interval_ms must be greater than 0"));
+        }
+        // A cloned wallet brings its L1 lamports along, on top of what it deposited
+        if self.deposits.enabled && self.accounts.l1_clone != ClonePolicy::Never {
+            return Err(anyhow!("accounts.l1_clone must be never while deposits are enabled"));
+        }
+        if self.inbox.poll_interval_ms == 0 {
+            return Err(anyhow!("inbox.poll_interval_ms must be greater than 0"));
+        }
+        if let Some(path) = &self.genesis {
+            if !path.is_file() {
+                return Err(anyhow!("genesis file {} does not exist", path.display()));
+            }
+        }
+        if self.snapshots.interval_batches > 0 && self.snapshots.keep == 0 {
+            return Err(anyhow!("snapshots.keep must be at least 1 while snapshots are enabled"));
+        }
+        if let Some(path) = &self.snapshots.restore_from {
+            if !path.is_file() {
+                return Err(anyhow!("snapshot {} does not exist", path.display()));
+            }
+        }
+        if self.da.backend == DaBackend::Http {
+            match &self.da.url {
+                Some(url) if url.starts_with("http") => {}
+                Some(url) => return Err(anyhow!("da.url must be an http(s) url, got {:?}", url)),
+                None => return Err(anyhow!("da.url is required with the http backend")),
+            }
+        }
+        if self.mode == NodeMode::Follower {
+            match self.follower.source {
+                BlockSource::Sequencer if !self.follower.sequencer_url.starts_with("http") => {
+                    return Err(anyhow!("follower.sequencer_url must be an http(s) url, got {:?}", self.follower.sequencer_url));
+                }
+                BlockSource::Da => {
+                    let operator = self
+                        .follower
+                        .operator
+                        .as_deref()
+                        .ok_or_else(|| anyhow!("follower.operator is required with the da source"))?;
+                    Pubkey::from_str(operator).map_err(|_| anyhow!("invalid follower.operator {:?}", operator))?;
+                }
+                BlockSource::Sequencer => {}
+            }
+            if self.follower.poll_interval_ms == 0 {
+                return Err(anyhow!("follower.poll_interval_ms must be greater than 0"));
+            }
+        }
+        if self.mode == NodeMode::Follower && !self.plugins.is_empty() {
+            return Err(anyhow!("plugins are only supported in sequencer mode"));
+        }
+        if self.settlement.initial_backoff_ms > self.settlement.max_backoff_ms {
+            return Err(anyhow!("settlement.initial_backoff_ms is larger than settlement.max_backoff_ms"));
+        }
+        Ok(())
+    }
+
+    pub fn cluster_commitment(&self) -> CommitmentConfig {
+        CommitmentConfig { commitment: parse_commitment(&self.cluster.commitment).unwrap() }
+    }
+
+    pub fn delegation_program_id(&self) -> Pubkey {
+        Pubkey::from_str(&self.delegation.program_id).unwrap()
+    }
+
+    /// `delegation.rollup_instructions`, always on against the mock L1.
+    pub fn rollup_instructions(&self) -> bool {
+        self.delegation.rollup_instructions || self.cluster.mock
+    }
+
+    pub fn operator_signer(&self) -> Result<OperatorSigner> {
+        if let Some(path) = &self.keys.operator_keypair {
+            return signer::keypair_file_signer(path);
+        }
+        if let Some(path) = &self.keys.operator_keystore {
+            let password = std::env::var(&self.keys.keystore_password_env)
+                .with_context(|| format!("keystore password not set in {}", self.keys.keystore_password_env))?;
+            return signer::keystore_signer(path, &password);
+        }
+        if let Some(url) = &self.keys.remote_signer_url {
+            let pubkey = Pubkey::from_str(self.keys.remote_signer_pubkey.as_deref().unwrap())?;
+            return Ok(signer::remote_signer(url, pubkey));
+        }
+        // Only the mock L1 doesn't care who the operator is
+        if !self.cluster.mock {
+            return Err(anyhow!(
+                "no operator key configured, set keys.operator_keypair, keys.operator_keystore or keys.remote_signer_url"
+            ));
+        }
+        log::warn!("No operator key configured, using a throwaway keypair against the mock L1");
+        Ok(std::sync::Arc::new(Keypair::new()))
+    }
+
+    /// `cluster.ws_url`, or the websocket counterpart of `cluster.rpc_url`.
+    pub fn cluster_ws_url(&self) -> String {
+        if let Some(ws_url) = &self.cluster.ws_url {
+            return ws_url.clone();
+        }
+        let rpc_url = &self.cluster.rpc_url;
+        if let Some(rest) = rpc_url.strip_prefix("https://") {
+            format!("wss://{}", rest)
+        } else if let Some(rest) = rpc_url.strip_prefix("http://") {
+            // Local validators serve websockets on the port after RPC
+            match rest.rsplit_once(':').and_then(|(host, port)| Some((host, port.trim_end_matches('/').parse::<u16>().ok()?))) {
+                Some((host, port)) => format!("ws://{}:{}", host, port + 1),
+                None => format!("ws://{}", rest),
+            }
+        } else {
+            rpc_url.clone()
+        }
+    }
+
+    /// Path of a file inside the storage directory.
+    pub fn storage_file(&self, name: &str) -> PathBuf {
+        self.storage.path.join(name)
+    }
+
+    pub fn settlement_config(&self) -> crate::settle::SettlementConfig {
+        crate::settle::SettlementConfig {
+            commitment: CommitmentConfig { commitment: parse_commitment(&self.settlement.commitment).unwrap() },
+            max_attempts: self.settlement.max_attempts,
+            initial_backoff: Duration::from_millis(self.settlement.initial_backoff_ms),
+            max_backoff: Duration::from_millis(self.settlement.max_backoff_ms),
+            poll_interval: Duration::from_millis(self.settlement.poll_interval_ms),
+            challenge_period_slots: self.settlement.challenge_period_slots,
+            journal_path: self.storage_file("pending_settlements.json"),
+        }
+    }
+
+    pub fn deposit_watcher_config(&self) -> crate::deposit_watcher::DepositWatcherConfig {
+        crate::deposit_watcher::DepositWatcherConfig {
+            poll_interval: Duration::from_millis(self.deposits.poll_interval_ms),
+            confirmation_depth: self.deposits.confirmation_depth,
+            cursor_path: self.storage_file("deposit_cursor.json"),
+        }
+    }
+
+    pub fn block_config(&self) -> crate::block::BlockConfig {
+        crate::block::BlockConfig {
+            max_transactions: self.blocks.max_transactions,
+            interval: Duration::from_millis(self.blocks.interval_ms),
+        }
+    }
+
+    pub fn snapshot_config(&self) -> crate::snapshot::SnapshotConfig {
+        crate::snapshot::SnapshotConfig {
+            dir: self.storage_file("snapshots"),
+            interval_batches: self.snapshots.interval_batches,
+            keep: self.snapshots.keep,
+        }
+    }
+
+    /// Directory of the file DA backend.
+    pub fn da_dir(&self) -> PathBuf {
+        self.da.dir.clone().unwrap_or_else(|| self.storage_file("da"))
+    }
+
+    pub fn inbox_watcher_config(&self) -> crate::inbox::InboxWatcherConfig {
+        crate::inbox::InboxWatcherConfig {
+            poll_interval: Duration::from_millis(self.inbox.poll_interval_ms),
+        }
+    }
+
+    pub fn follower_config(&self) -> crate::follower::FollowerConfig {
+        crate::follower::FollowerConfig {
+            source: self.follower.source,
+            sequencer_url: self.follower.sequencer_url.trim_end_matches('/').to_string(),
+            operator: self.follower.operator.as_deref().map(|operator| Pubkey::from_str(operator).unwrap()),
+            poll_interval: Duration::from_millis(self.follower.poll_interval_ms),
+        }
+    }
+}
+
+fn parse_commitment(commitment: &str) -> Result<CommitmentLevel> {
+    CommitmentLevel::from_str(commitment).map_err(|_| anyhow!("invalid commitment {:?}", commitment))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(change: impl FnOnce(&mut NodeConfig)) -> String {
+        let mut config = NodeConfig::default();
+        change(&mut config);
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        NodeConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(rejected(|config| config.log_level = "loud".to_string()).contains("log_level"));
+        assert!(rejected(|config| config.cluster.rpc_url = "localhost:8899".to_string()).contains("cluster.rpc_url"));
+        assert!(rejected(|config| config.cluster.ws_url = Some("http://localhost:8900".to_string())).contains("cluster.ws_url"));
+        assert!(rejected(|config| config.settlement.commitment = "final".to_string()).contains("settlement.commitment"));
+        assert!(rejected(|config| config.server.bind_address = "localhost".to_string()).contains("server.bind_address"));
+        assert!(rejected(|config| config.runtime.worker_threads = 0).contains("worker thread"));
+        assert!(rejected(|config| config.batch.max_transactions = 0).contains("batch.max_transactions"));
+        assert!(rejected(|config| config.blocks.interval_ms = 0).contains("blocks.interval_ms"));
+        assert!(rejected(|config| config.delegation.program_id = "nope".to_string()).contains("delegation.program_id"));
+        assert!(rejected(|config| config.accounts.l1_clone = ClonePolicy::OnFirstRead).contains("accounts.l1_clone"));
+        assert!(rejected(|config| config.genesis = Some(PathBuf::from("/nonexistent/genesis.json"))).contains("genesis file"));
+        assert!(rejected(|config| config.da.backend = DaBackend::Http).contains("da.url is required"));
+        assert!(rejected(|config| {
+            config.settlement.initial_backoff_ms = config.settlement.max_backoff_ms + 1;
+        })
+        .contains("initial_backoff_ms"));
+    }
+
+    #[test]
+    fn rejects_conflicting_keys() {
+        assert!(rejected(|config| {
+            config.keys.operator_keypair = Some(PathBuf::from("operator.json"));
+            config.keys.remote_signer_url = Some("http://signer".to_string());
+        })
+        .contains("only one of"));
+        assert!(rejected(|config| config.keys.operator_keypair = Some(PathBuf::from("/nonexistent/operator.json"))).contains("key file"));
+        assert!(rejected(|config| config.keys.remote_signer_url = Some("http://signer".to_string())).contains("remote_signer_pubkey is required"));
+    }
+
+    #[test]
+    fn follower_settings_are_checked_in_follower_mode_only() {
+        let follower = |config: &mut NodeConfig| {
+            config.mode = NodeMode::Follower;
+            config.follower.source = BlockSource::Da;
+        };
+        let mut config = NodeConfig::default();
+        follower(&mut config);
+        assert!(config.validate().unwrap_err().to_string().contains("follower.operator is required"));
+        config.follower.operator = Some(Pubkey::new_unique().to_string());
+        config.validate().unwrap();
+
+        config.mode = NodeMode::Sequencer;
+        config.follower.operator = None;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn files_fill_in_defaults_and_the_cli_overrides_them() {
+        let path = std::env::temp_dir().join(format!("rollup-config-{}.toml", std::process::id()));
+        fs::write(&path, "[batch]\nmax_transactions = 3\n\n[cluster]\nmock = true\n").unwrap();
+        let config = NodeConfig::load(Cli::parse_from(["rollup_core", "--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(config.batch.max_transactions, 3);
+        assert!(config.cluster.mock);
+        assert_eq!(config.blocks.max_transactions, NodeConfig::default().blocks.max_transactions);
+
+        let cli = Cli::parse_from(["rollup_core", "--config", path.to_str().unwrap(), "--batch-size", "7"]);
+        assert_eq!(NodeConfig::load(cli).unwrap().batch.max_transactions, 7);
+        let cli = Cli::parse_from(["rollup_core", "--config", path.to_str().unwrap(), "--batch-size", "0"]);
+        assert!(NodeConfig::load(cli).is_err());
+
+        // Misspelled keys are errors, not silently ignored
+        fs::write(&path, "[batch]\nmax_transaction = 3\n").unwrap();
+        assert!(NodeConfig::load(Cli::parse_from(["rollup_core", "--config", path.to_str().unwrap()])).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn websocket_url_follows_the_rpc_url() {
+        let mut config = NodeConfig::default();
+        config.cluster.rpc_url = "https://api.devnet.solana.com".to_string();
+        assert_eq!(config.cluster_ws_url(), "wss://api.devnet.solana.com");
+        config.cluster.rpc_url = "http://127.0.0.1:8899".to_string();
+        assert_eq!(config.cluster_ws_url(), "ws://127.0.0.1:8900");
+        config.cluster.ws_url = Some("ws://other:1".to_string());
+        assert_eq!(config.cluster_ws_url(), "ws://other:1");
+    }
+
+    #[test]
+    fn operator_key_is_required_off_the_mock_l1() {
+        let mut config = NodeConfig::default();
+        assert!(config.operator_signer().is_err());
+
+        config.cluster.mock = true;
+        assert!(config.operator_signer().is_ok());
+    }
+}
@@ -0,0 +1,779 @@
+//! Data availability. Settlement only posts netted transfers, so every batch
+//! also publishes the blocks it covers, with the entries needed to replay
+//! them. The batch data is encoded as a short uncompressed header (magic and
+//! format version) followed by zstd-compressed bincode, and handed to a
+//! `DataAvailability` backend ahead of the settlement transaction. The
+//! backend returns a `DaCommitment`, which the settlement memo and the batch
+//! record carry, and which is all a verifier needs to get the data back.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    clock::Slot,
+    hash::{hash, Hash},
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    signature::{Keypair, Signature},
+    signer::Signer,
+    transaction::{Transaction, VersionedTransaction},
+};
+use solana_loader_v3_interface::{instruction as bpf_loader_upgradeable, state::UpgradeableLoaderState};
+
+use crate::{
+    block::{Block, BlockEntry, BlockJournal, BlockRecord},
+    l1_client::L1Client,
+    settle::MEMO_PROGRAM_ID,
+    signer::OperatorSigner,
+};
+
+const MAGIC: &[u8; 4] = b"RDAB";
+pub const FORMAT_VERSION: u32 = 3;
+const COMPRESSION_LEVEL: i32 = 9;
+const MEMO_PREFIX: &str = "rollup-da";
+const SETTLEMENT_MEMO_PREFIX: &str = "rollup-batch";
+/// Raw bytes per memo chunk or buffer write, small enough for an L1 transaction.
+pub const CHUNK_BYTES: usize = 600;
+const SIGNATURE_PAGE_LIMIT: usize = 1000;
+
+/// A block and its entries. `BlockRecord` flattens the block for the
+/// journal, which bincode can't encode.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchBlock {
+    pub block: Block,
+    pub entries: Vec<BlockEntry>,
+}
+
+impl From<BlockRecord> for BatchBlock {
+    fn from(record: BlockRecord) -> Self {
+        Self {
+            block: record.block,
+            entries: record.entries,
+        }
+    }
+}
+
+impl From<BatchBlock> for BlockRecord {
+    fn from(block: BatchBlock) -> Self {
+        Self {
+            block: block.block,
+            entries: block.entries,
+        }
+    }
+}
+
+/// What a batch publishes: the blocks sealed since the previous batch,
+/// enough to rebuild the rollup's state from its parent's.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchData {
+    pub batch_id: u64,
+    pub pre_state_root: Hash,
+    pub post_state_root: Hash,
+    pub inbox_index: u64,
+    pub blocks: Vec<BatchBlock>,
+}
+
+impl BatchData {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        bytes.write_all(MAGIC)?;
+        bytes.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        let mut encoder = zstd::Encoder::new(bytes, COMPRESSION_LEVEL)?;
+        bincode::serialize_into(&mut encoder, self)?;
+        Ok(encoder.finish()?)
+    }
+
+    pub fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        bytes.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(anyhow!("not rollup batch data"));
+        }
+        let mut version = [0u8; 4];
+        bytes.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(anyhow!("batch data has format version {}, this node reads version {}", version, FORMAT_VERSION));
+        }
+        let decoder = zstd::Decoder::new(bytes)?;
+        Ok(bincode::deserialize_from(decoder)?)
+    }
+}
+
+/// Which backend a node publishes its batch data to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DaBackend {
+    /// Operator-signed memos on L1.
+    #[default]
+    L1Memo,
+    /// A BPF loader buffer account on L1, one per batch.
+    L1Buffer,
+    /// A local directory, for tests and development.
+    File,
+    /// A blob store that takes `PUT <url>/<hash>` and serves `GET` on it.
+    Http,
+}
+
+/// Where a batch's data was published, and the hash of the encoded data.
+/// Written as `<backend>/<hash>/<location>` into the settlement memo.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DaCommitment {
+    L1Memo { data_hash: Hash, operator: Pubkey },
+    L1Buffer { data_hash: Hash, account: Pubkey, len: usize },
+    File { data_hash: Hash, path: PathBuf },
+    Http { data_hash: Hash, url: String },
+}
+
+impl DaCommitment {
+    pub fn data_hash(&self) -> Hash {
+        match self {
+            Self::L1Memo { data_hash, .. }
+            | Self::L1Buffer { data_hash, .. }
+            | Self::File { data_hash, .. }
+            | Self::Http { data_hash, .. } => *data_hash,
+        }
+    }
+}
+
+impl fmt::Display for DaCommitment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::L1Memo { data_hash, operator } => write!(f, "l1-memo/{}/{}", data_hash, operator),
+            Self::L1Buffer { data_hash, account, len } => write!(f, "l1-buffer/{}/{}/{}", data_hash, account, len),
+            Self::File { data_hash, path } => write!(f, "file/{}/{}", data_hash, path.display()),
+            Self::Http { data_hash, url } => write!(f, "http/{}/{}", data_hash, url),
+        }
+    }
+}
+
+impl FromStr for DaCommitment {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid DA commitment {:?}", s);
+        let mut fields = s.splitn(3, '/');
+        let backend = fields.next().ok_or_else(invalid)?;
+        let data_hash: Hash = fields.next().ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+        let location = fields.next().ok_or_else(invalid)?;
+        Ok(match backend {
+            "l1-memo" => Self::L1Memo {
+                data_hash,
+                operator: location.parse().map_err(|_| invalid())?,
+            },
+            "l1-buffer" => {
+                let (account, len) = location.split_once('/').ok_or_else(invalid)?;
+                Self::L1Buffer {
+                    data_hash,
+                    account: account.parse().map_err(|_| invalid())?,
+                    len: len.parse().map_err(|_| invalid())?,
+                }
+            }
+            "file" => Self::File {
+                data_hash,
+                path: PathBuf::from(location),
+            },
+            "http" => Self::Http {
+                data_hash,
+                url: location.to_string(),
+            },
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+/// Where batch data goes. The settlement worker publishes every batch before
+/// settling it, and verifiers retrieve it through the backend named in the
+/// batch's commitment.
+pub trait DataAvailability: Send + Sync {
+    /// Stores an encoded batch, returns where to find it.
+    fn publish(&self, batch_id: u64, data: &[u8]) -> Result<DaCommitment>;
+
+    /// The encoded batch behind a commitment of this backend's kind.
+    fn retrieve(&self, commitment: &DaCommitment) -> Result<Vec<u8>>;
+}
+
+/// Retrieves and decodes a batch from whichever backend it was published to,
+/// checking the data against the commitment's hash.
+pub fn retrieve(l1_client: &Arc<dyn L1Client>, commitment: &DaCommitment) -> Result<BatchData> {
+    let backend: Box<dyn DataAvailability> = match commitment {
+        DaCommitment::L1Memo { .. } => Box::new(L1MemoDa::new(l1_client.clone(), None)),
+        DaCommitment::L1Buffer { .. } => Box::new(L1BufferDa::new(l1_client.clone(), None)),
+        DaCommitment::File { path, .. } => Box::new(FileDa::new(path.parent().unwrap_or(Path::new(".")).to_path_buf())),
+        DaCommitment::Http { url, .. } => Box::new(HttpDa::new(url)),
+    };
+    let data = backend.retrieve(commitment)?;
+    if hash(&data) != commitment.data_hash() {
+        return Err(anyhow!("data retrieved for {} doesn't match its hash", commitment));
+    }
+    BatchData::decode(&data).with_context(|| format!("undecodable batch data behind {}", commitment))
+}
+
+fn send(l1_client: &dyn L1Client, instructions: &[Instruction], signers: &[&dyn Signer]) -> Result<Signature> {
+    let payer = signers[0].try_pubkey()?;
+    let (recent_blockhash, _) = l1_client.get_latest_blockhash()?;
+    let mut tx = Transaction::new_with_payer(instructions, Some(&payer));
+    tx.try_sign(signers, recent_blockhash)?;
+    l1_client.send_and_confirm_transaction(&tx)
+}
+
+/// One piece of an encoded batch, as posted in a memo:
+/// `rollup-da:<batch id>:<data hash>:<index>:<count>:<base64>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DaChunk {
+    pub batch_id: u64,
+    pub data_hash: Hash,
+    pub index: u32,
+    pub count: u32,
+    pub data: Vec<u8>,
+}
+
+impl DaChunk {
+    pub fn to_memo(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}:{}",
+            MEMO_PREFIX,
+            self.batch_id,
+            self.data_hash,
+            self.index,
+            self.count,
+            base64::engine::general_purpose::STANDARD.encode(&self.data)
+        )
+    }
+
+    /// `None` for memos that aren't DA chunks.
+    pub fn parse_memo(memo: &str) -> Option<Self> {
+        let mut fields = memo.splitn(6, ':');
+        if fields.next()? != MEMO_PREFIX {
+            return None;
+        }
+        let chunk = Self {
+            batch_id: fields.next()?.parse().ok()?,
+            data_hash: fields.next()?.parse().ok()?,
+            index: fields.next()?.parse().ok()?,
+            count: fields.next()?.parse().ok()?,
+            data: base64::engine::general_purpose::STANDARD.decode(fields.next()?).ok()?,
+        };
+        (chunk.index < chunk.count).then_some(chunk)
+    }
+}
+
+fn memo_instruction(signer: &Pubkey, memo: &str) -> Instruction {
+    Instruction {
+        program_id: MEMO_PROGRAM_ID,
+        accounts: vec![AccountMeta::new_readonly(*signer, true)],
+        data: memo.as_bytes().to_vec(),
+    }
+}
+
+type SlotMemo = (Slot, String);
+
+/// Every memo in `address`'s successful L1 transactions after `until`,
+/// oldest first with the slot it landed in, and the newest signature seen.
+fn scan_memos(
+    l1_client: &dyn L1Client,
+    address: &Pubkey,
+    until: Option<Signature>,
+) -> Result<(Vec<SlotMemo>, Option<Signature>)> {
+    let mut memos = Vec::new();
+    let mut newest = None;
+    let mut before = None;
+    loop {
+        let infos = l1_client.get_signatures_for_address(address, before, until, SIGNATURE_PAGE_LIMIT)?;
+        let Some(oldest) = infos.last() else {
+            break;
+        };
+        newest = newest.or(infos.first().map(|info| info.signature));
+        before = Some(oldest.signature);
+        for info in infos.iter().filter(|info| info.err.is_none()) {
+            let Some(tx) = l1_client.get_transaction(&info.signature)? else {
+                log::warn!("L1 transaction {} is not available", info.signature);
+                continue;
+            };
+            // Reversed below, memos of one transaction stay in order
+            memos.extend(transaction_memos(&tx.transaction, address).rev().map(|memo| (tx.slot, memo.to_string())));
+        }
+    }
+    memos.reverse();
+    Ok((memos, newest.or(until)))
+}
+
+/// The memos in `tx` that `signer` signed. Anyone can send a memo that
+/// mentions the operator, only the ones it signed are its own.
+fn transaction_memos<'a>(tx: &'a VersionedTransaction, signer: &'a Pubkey) -> impl DoubleEndedIterator<Item = &'a str> {
+    let message = &tx.message;
+    let account_keys = message.static_account_keys();
+    message
+        .instructions()
+        .iter()
+        .filter(move |ix| account_keys.get(ix.program_id_index as usize) == Some(&MEMO_PROGRAM_ID))
+        .filter(move |ix| {
+            ix.accounts
+                .iter()
+                .any(|&index| account_keys.get(index as usize) == Some(signer) && message.is_signer(index as usize))
+        })
+        .filter_map(|ix| std::str::from_utf8(&ix.data).ok())
+}
+
+/// Chunks resent after a failed publish show up more than once, the first
+/// copy of an index wins. Only the operator's own memos get here, so every
+/// copy carries the same data.
+fn assemble(chunks: impl IntoIterator<Item = DaChunk>, data_hash: &Hash) -> Result<Vec<u8>> {
+    let mut pieces = BTreeMap::new();
+    for chunk in chunks.into_iter().filter(|chunk| chunk.data_hash == *data_hash) {
+        pieces.entry(chunk.index).or_insert(chunk);
+    }
+    let count = pieces.values().next().map_or(0, |chunk| chunk.count);
+    if count == 0 || pieces.len() != count as usize {
+        return Err(anyhow!("{} of {} chunks of {} are on L1", pieces.len(), count, data_hash));
+    }
+    let data: Vec<u8> = pieces.into_values().flat_map(|chunk| chunk.data).collect();
+    if hash(&data) != *data_hash {
+        return Err(anyhow!("chunks of {} don't match their hash", data_hash));
+    }
+    Ok(data)
+}
+
+/// Posts the data as operator-signed memos, `CHUNK_BYTES` per transaction.
+/// Retrieval walks the operator's L1 history.
+pub struct L1MemoDa {
+    l1_client: Arc<dyn L1Client>,
+    /// Only needed to publish.
+    operator: Option<OperatorSigner>,
+}
+
+impl L1MemoDa {
+    pub fn new(l1_client: Arc<dyn L1Client>, operator: Option<OperatorSigner>) -> Self {
+        Self { l1_client, operator }
+    }
+}
+
+impl DataAvailability for L1MemoDa {
+    fn publish(&self, batch_id: u64, data: &[u8]) -> Result<DaCommitment> {
+        let operator = self.operator.as_ref().ok_or_else(|| anyhow!("publishing needs the operator key"))?;
+        let operator_pubkey = operator.try_pubkey()?;
+        let data_hash = hash(data);
+        let count = data.len().div_ceil(CHUNK_BYTES) as u32;
+        for (index, data) in data.chunks(CHUNK_BYTES).enumerate() {
+            let memo = DaChunk {
+                batch_id,
+                data_hash,
+                index: index as u32,
+                count,
+                data: data.to_vec(),
+            }
+            .to_memo();
+            let signers: [&dyn Signer; 1] = [operator.as_ref()];
+            send(self.l1_client.as_ref(), &[memo_instruction(&operator_pubkey, &memo)], &signers)?;
+        }
+        Ok(DaCommitment::L1Memo {
+            data_hash,
+            operator: operator_pubkey,
+        })
+    }
+
+    fn retrieve(&self, commitment: &DaCommitment) -> Result<Vec<u8>> {
+        let DaCommitment::L1Memo { data_hash, operator } = commitment else {
+            return Err(anyhow!("{} is not an L1 memo commitment", commitment));
+        };
+        let (memos, _) = scan_memos(self.l1_client.as_ref(), operator, None)?;
+        assemble(memos.iter().filter_map(|(_, memo)| DaChunk::parse_memo(memo)), data_hash)
+    }
+}
+
+/// Writes the data into a fresh BPF loader buffer account owned by the
+/// operator. A publish that fails halfway is retried into a new buffer.
+pub struct L1BufferDa {
+    l1_client: Arc<dyn L1Client>,
+    /// Only needed to publish.
+    operator: Option<OperatorSigner>,
+}
+
+impl L1BufferDa {
+    pub fn new(l1_client: Arc<dyn L1Client>, operator: Option<OperatorSigner>) -> Self {
+        Self { l1_client, operator }
+    }
+}
+
+impl DataAvailability for L1BufferDa {
+    fn publish(&self, _batch_id: u64, data: &[u8]) -> Result<DaCommitment> {
+        let operator = self.operator.as_ref().ok_or_else(|| anyhow!("publishing needs the operator key"))?;
+        let operator_pubkey = operator.try_pubkey()?;
+        let buffer = Keypair::new();
+        let lamports = self
+            .l1_client
+            .get_minimum_balance_for_rent_exemption(UpgradeableLoaderState::size_of_buffer(data.len()))?;
+        let create = bpf_loader_upgradeable::create_buffer(
+            &operator_pubkey,
+            &buffer.pubkey(),
+            &operator_pubkey,
+            lamports,
+            data.len(),
+        )?;
+        let signers: [&dyn Signer; 2] = [operator.as_ref(), &buffer];
+        send(self.l1_client.as_ref(), &create, &signers)?;
+        for (index, data) in data.chunks(CHUNK_BYTES).enumerate() {
+            let write = bpf_loader_upgradeable::write(
+                &buffer.pubkey(),
+                &operator_pubkey,
+                (index * CHUNK_BYTES) as u32,
+                data.to_vec(),
+            );
+            let signers: [&dyn Signer; 1] = [operator.as_ref()];
+            send(self.l1_client.as_ref(), &[write], &signers)?;
+        }
+        Ok(DaCommitment::L1Buffer {
+            data_hash: hash(data),
+            account: buffer.pubkey(),
+            len: data.len(),
+        })
+    }
+
+    fn retrieve(&self, commitment: &DaCommitment) -> Result<Vec<u8>> {
+        let DaCommitment::L1Buffer { account, len, .. } = commitment else {
+            return Err(anyhow!("{} is not an L1 buffer commitment", commitment));
+        };
+        let buffer = self
+            .l1_client
+            .get_account(account)?
+            .ok_or_else(|| anyhow!("buffer {} doesn't exist on L1", account))?;
+        let start = UpgradeableLoaderState::size_of_buffer_metadata();
+        buffer
+            .data
+            .get(start..start + len)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| anyhow!("buffer {} is shorter than {} bytes", account, len))
+    }
+}
+
+/// One file per batch in a local directory.
+pub struct FileDa {
+    dir: PathBuf,
+}
+
+impl FileDa {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl DataAvailability for FileDa {
+    fn publish(&self, batch_id: u64, data: &[u8]) -> Result<DaCommitment> {
+        let data_hash = hash(data);
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("batch-{:020}-{}.rda", batch_id, data_hash));
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(DaCommitment::File { data_hash, path })
+    }
+
+    fn retrieve(&self, commitment: &DaCommitment) -> Result<Vec<u8>> {
+        let DaCommitment::File { path, .. } = commitment else {
+            return Err(anyhow!("{} is not a file commitment", commitment));
+        };
+        fs::read(path).with_context(|| format!("failed to read batch data {}", path.display()))
+    }
+}
+
+/// A generic blob store over HTTP, blobs are addressed by their hash.
+///
+/// Uses a blocking HTTP client, so it must not be called from inside an
+/// async context.
+pub struct HttpDa {
+    url: String,
+    client: reqwest::blocking::Client,
+}
+
+impl HttpDa {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+}
+
+impl DataAvailability for HttpDa {
+    fn publish(&self, _batch_id: u64, data: &[u8]) -> Result<DaCommitment> {
+        let data_hash = hash(data);
+        let url = format!("{}/{}", self.url, data_hash);
+        self.client.put(&url).body(data.to_vec()).send()?.error_for_status()?;
+        Ok(DaCommitment::Http { data_hash, url })
+    }
+
+    fn retrieve(&self, commitment: &DaCommitment) -> Result<Vec<u8>> {
+        let DaCommitment::Http { url, .. } = commitment else {
+            return Err(anyhow!("{} is not an HTTP commitment", commitment));
+        };
+        Ok(self.client.get(url).send()?.error_for_status()?.bytes()?.to_vec())
+    }
+}
+
+/// A settlement memo,
+/// `rollup-batch:<batch id>:<state root>:<digest>:<commitment>`.
+pub fn parse_settlement_memo(memo: &str) -> Option<(u64, Hash, DaCommitment)> {
+    let mut fields = memo.splitn(5, ':');
+    if fields.next()? != SETTLEMENT_MEMO_PREFIX {
+        return None;
+    }
+    let batch_id = fields.next()?.parse().ok()?;
+    let state_root = fields.next()?.parse().ok()?;
+    let commitment = fields.nth(1)?.parse().ok()?;
+    Some((batch_id, state_root, commitment))
+}
+
+/// A batch as settled on L1, with its data retrieved.
+#[derive(Debug, Clone)]
+pub struct SettledBatch {
+    pub batch_id: u64,
+    /// The state root the settlement posted.
+    pub state_root: Hash,
+    pub commitment: DaCommitment,
+    /// L1 slot the settlement landed in.
+    pub l1_slot: Slot,
+    pub data: BatchData,
+}
+
+/// Follows the batches an operator settles on L1. Each call picks up where
+/// the previous one stopped, chunks of memo-published data are kept until
+/// their batch's settlement shows up.
+pub struct SettlementReader {
+    l1_client: Arc<dyn L1Client>,
+    operator: Pubkey,
+    newest: Option<Signature>,
+    chunks: HashMap<Hash, Vec<DaChunk>>,
+}
+
+impl SettlementReader {
+    pub fn new(l1_client: Arc<dyn L1Client>, operator: Pubkey) -> Self {
+        Self {
+            l1_client,
+            operator,
+            newest: None,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Batches settled since the last call, oldest first. A batch settled
+    /// more than once is returned each time.
+    pub fn next_batches(&mut self) -> Result<Vec<SettledBatch>> {
+        let (memos, newest) = scan_memos(self.l1_client.as_ref(), &self.operator, self.newest)?;
+        let mut batches = Vec::new();
+        for (l1_slot, memo) in memos {
+            if let Some(chunk) = DaChunk::parse_memo(&memo) {
+                self.chunks.entry(chunk.data_hash).or_default().push(chunk);
+                continue;
+            }
+            let Some((batch_id, state_root, commitment)) = parse_settlement_memo(&memo) else {
+                continue;
+            };
+            let data = match &commitment {
+                DaCommitment::L1Memo { data_hash, .. } => {
+                    let data = assemble(self.chunks.remove(data_hash).unwrap_or_default(), data_hash)?;
+                    BatchData::decode(&data)?
+                }
+                _ => retrieve(&self.l1_client, &commitment)?,
+            };
+            if data.batch_id != batch_id {
+                return Err(anyhow!("batch {} settled with the data of batch {}", batch_id, data.batch_id));
+            }
+            batches.push(SettledBatch {
+                batch_id,
+                state_root,
+                commitment,
+                l1_slot,
+                data,
+            });
+        }
+        // Only moved on once everything up to it was read
+        self.newest = newest;
+        Ok(batches)
+    }
+}
+
+/// `rollup_core fetch-da`: finds the batches `operator` settled on L1,
+/// retrieves their data from the backends they name and writes the blocks
+/// to a block journal at `out`, which `rollup_core replay --blocks` can
+/// verify.
+pub fn fetch(l1_client: &Arc<dyn L1Client>, operator: &Pubkey, out: &Path) -> Result<()> {
+    if out.exists() {
+        return Err(anyhow!("{} already exists", out.display()));
+    }
+    // The last settlement of a batch wins
+    let batches: BTreeMap<u64, SettledBatch> = SettlementReader::new(l1_client.clone(), *operator)
+        .next_batches()?
+        .into_iter()
+        .map(|batch| (batch.batch_id, batch))
+        .collect();
+
+    let journal = BlockJournal::new(out.to_path_buf());
+    let mut blocks = 0usize;
+    for batch in batches.into_values() {
+        log::info!("Batch {} published {} blocks to {}", batch.batch_id, batch.data.blocks.len(), batch.commitment);
+        for block in batch.data.blocks {
+            journal.append(&BlockRecord::from(block))?;
+            blocks += 1;
+        }
+    }
+    log::info!("Wrote {} blocks to {}", blocks, out.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(data: &[u8], size: usize) -> Vec<DaChunk> {
+        let data_hash = hash(data);
+        let count = data.len().div_ceil(size) as u32;
+        data.chunks(size)
+            .enumerate()
+            .map(|(index, data)| DaChunk {
+                batch_id: 7,
+                data_hash,
+                index: index as u32,
+                count,
+                data: data.to_vec(),
+            })
+            .collect()
+    }
+
+    fn batch_data() -> BatchData {
+        let payer = Keypair::new();
+        let transfer = solana_system_interface::instruction::transfer(&payer.pubkey(), &Pubkey::new_unique(), 10);
+        let transaction = Transaction::new_signed_with_payer(&[transfer], Some(&payer.pubkey()), &[&payer], Hash::default());
+        let block = Block::new(1, Hash::new_unique(), vec![transaction.signatures[0]], Hash::new_unique(), 1_700_000_000);
+        BatchData {
+            batch_id: 4,
+            pre_state_root: Hash::new_unique(),
+            post_state_root: block.state_root,
+            inbox_index: 2,
+            blocks: vec![BatchBlock {
+                block,
+                entries: vec![
+                    BlockEntry::Deposit {
+                        owner: payer.pubkey(),
+                        lamports: 100,
+                        writes_hash: Hash::new_unique(),
+                    },
+                    BlockEntry::Transaction {
+                        transaction,
+                        l1_accounts: Vec::new(),
+                        credits: vec![(payer.pubkey(), 5)],
+                        writes_hash: Hash::new_unique(),
+                    },
+                ],
+            }],
+        }
+    }
+
+    #[test]
+    fn batch_data_round_trips() {
+        let data = batch_data();
+        let encoded = data.encode().unwrap();
+        assert_eq!(&encoded[..4], MAGIC);
+        let decoded = BatchData::decode(&encoded).unwrap();
+        assert_eq!(bincode::serialize(&decoded).unwrap(), bincode::serialize(&data).unwrap());
+        assert_eq!(decoded.blocks[0].block.hash, data.blocks[0].block.hash);
+    }
+
+    #[test]
+    fn rejects_foreign_newer_and_truncated_batch_data() {
+        let encoded = batch_data().encode().unwrap();
+
+        let mut foreign = encoded.clone();
+        foreign[..4].copy_from_slice(b"NOPE");
+        assert_eq!(BatchData::decode(&foreign).unwrap_err().to_string(), "not rollup batch data");
+
+        let mut newer = encoded.clone();
+        newer[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(BatchData::decode(&newer).unwrap_err().to_string().contains("format version"));
+
+        assert!(BatchData::decode(&encoded[..encoded.len() / 2]).is_err());
+        assert!(BatchData::decode(&encoded[..2]).is_err());
+    }
+
+    #[test]
+    fn commitments_round_trip_through_their_string() {
+        let data_hash = hash(b"batch");
+        let commitments = [
+            DaCommitment::L1Memo { data_hash, operator: Pubkey::new_unique() },
+            DaCommitment::L1Buffer { data_hash, account: Pubkey::new_unique(), len: 1234 },
+            DaCommitment::File { data_hash, path: PathBuf::from("/tmp/da/batch-4.bin") },
+            DaCommitment::Http { data_hash, url: "https://da.example/blobs".to_string() },
+        ];
+        for commitment in commitments {
+            assert_eq!(commitment.to_string().parse::<DaCommitment>().unwrap(), commitment);
+        }
+        assert!("ipfs/abc/def".parse::<DaCommitment>().is_err());
+    }
+
+    #[test]
+    fn chunk_memos_round_trip() {
+        let chunk = chunks(b"rollup batch data", 5).remove(1);
+        assert_eq!(DaChunk::parse_memo(&chunk.to_memo()), Some(chunk.clone()));
+
+        let past_count = DaChunk { index: chunk.count, ..chunk.clone() };
+        assert_eq!(DaChunk::parse_memo(&past_count.to_memo()), None);
+        assert_eq!(DaChunk::parse_memo(&chunk.to_memo().replacen(MEMO_PREFIX, "other", 1)), None);
+        assert_eq!(DaChunk::parse_memo("rollup-da:7:not-a-hash:0:1:AA=="), None);
+    }
+
+    #[test]
+    fn assembles_chunks_in_any_order_with_resends() {
+        let data = b"rollup batch data, in several chunks".to_vec();
+        let data_hash = hash(&data);
+        let mut all = chunks(&data, 8);
+        all.reverse();
+        all.extend(chunks(&data, 8));
+        all.extend(chunks(b"another batch", 8));
+        assert_eq!(assemble(all.clone(), &data_hash).unwrap(), data);
+
+        let missing: Vec<DaChunk> = all.iter().filter(|chunk| chunk.index != 2).cloned().collect();
+        assert!(assemble(missing, &data_hash).is_err());
+
+        let mut tampered = chunks(&data, 8);
+        tampered[0].data[0] ^= 1;
+        assert!(assemble(tampered, &data_hash).is_err());
+    }
+
+    #[test]
+    fn only_memos_the_operator_signed_count() {
+        let operator = Keypair::new();
+        let sender = Keypair::new();
+        let mentioning_operator = Instruction {
+            program_id: MEMO_PROGRAM_ID,
+            accounts: vec![AccountMeta::new_readonly(operator.pubkey(), false)],
+            data: b"forged".to_vec(),
+        };
+        let tx = Transaction::new_signed_with_payer(
+            &[mentioning_operator, memo_instruction(&sender.pubkey(), "sender's")],
+            Some(&sender.pubkey()),
+            &[&sender],
+            Hash::default(),
+        );
+        let tx = VersionedTransaction::from(tx);
+        assert_eq!(transaction_memos(&tx, &operator.pubkey()).count(), 0);
+        assert_eq!(transaction_memos(&tx, &sender.pubkey()).collect::<Vec<_>>(), vec!["sender's"]);
+
+        let tx = Transaction::new_signed_with_payer(
+            &[memo_instruction(&operator.pubkey(), "operator's")],
+            Some(&sender.pubkey()),
+            &[&sender, &operator],
+            Hash::default(),
+        );
+        let tx = VersionedTransaction::from(tx);
+        assert_eq!(transaction_memos(&tx, &operator.pubkey()).collect::<Vec<_>>(), vec!["operator's"]);
+    }
+}
@@ -1,13 +1,129 @@
+//! Typed client for the on-chain delegation program. Every instruction,
+//! account and event has a borsh type here, and a test in `idl.rs` checks
+//! them against the program's Anchor IDL (`idl/delegation.json`).
+//! Account data is only decoded after its discriminator matched, so a wrong
+//! account or a layout change fails loudly.
+
 use sha2::{Sha256, Digest};
 use solana_sdk::{
     pubkey::Pubkey,
     instruction::{AccountMeta, Instruction},
-    system_program,
 };
-use borsh::{BorshSerialize, BorshDeserialize};
+use solana_sdk_ids::system_program;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use borsh::{BorshSerialize, BorshDeserialize, BorshSchema};
+use std::{fmt, sync::OnceLock};
+
+/// Anchor discriminator, the first 8 bytes of `sha256("<namespace>:<name>")`.
+/// Instructions use the `global` namespace, accounts the `account` namespace
+/// and events the `event` namespace.
+pub fn anchor_discriminator(preimage: &str) -> [u8; 8] {
+    let mut hasher = Sha256::new();
+    hasher.update(preimage.as_bytes());
+    let result = hasher.finalize();
+    let mut disc = [0u8; 8];
+    disc.copy_from_slice(&result[..8]);
+    disc
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DelegationError {
+    /// Data shorter than a discriminator.
+    TooShort { expected: &'static str, len: usize },
+    DiscriminatorMismatch { expected: &'static str, found: [u8; 8] },
+    UnknownInstruction { discriminator: [u8; 8] },
+    UnknownEvent { discriminator: [u8; 8] },
+    Decode { name: &'static str, reason: String },
+}
+
+impl fmt::Display for DelegationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort { expected, len } => write!(f, "{} data is only {} bytes", expected, len),
+            Self::DiscriminatorMismatch { expected, found } => {
+                write!(f, "expected a {} but found discriminator {:?}", expected, found)
+            }
+            Self::UnknownInstruction { discriminator } => {
+                write!(f, "unknown delegation instruction {:?}", discriminator)
+            }
+            Self::UnknownEvent { discriminator } => write!(f, "unknown delegation event {:?}", discriminator),
+            Self::Decode { name, reason } => write!(f, "failed to decode {}: {}", name, reason),
+        }
+    }
+}
+
+impl std::error::Error for DelegationError {}
+
+/// Splits off and checks the discriminator, then decodes the rest. Trailing
+/// bytes are allowed, accounts are often allocated larger than their data.
+fn decode_with_discriminator<T: BorshDeserialize>(
+    name: &'static str,
+    discriminator: [u8; 8],
+    data: &[u8],
+) -> Result<T, DelegationError> {
+    if data.len() < 8 {
+        return Err(DelegationError::TooShort { expected: name, len: data.len() });
+    }
+    let found: [u8; 8] = data[..8].try_into().unwrap();
+    if found != discriminator {
+        return Err(DelegationError::DiscriminatorMismatch { expected: name, found });
+    }
+    T::deserialize(&mut &data[8..]).map_err(|e| DelegationError::Decode { name, reason: e.to_string() })
+}
+
+/// An account owned by the delegation program.
+pub trait ProgramAccount: BorshSerialize + BorshDeserialize {
+    const NAME: &'static str;
+
+    fn discriminator() -> [u8; 8] {
+        anchor_discriminator(&format!("account:{}", Self::NAME))
+    }
+
+    fn try_from_account_data(data: &[u8]) -> Result<Self, DelegationError> {
+        decode_with_discriminator(Self::NAME, Self::discriminator(), data)
+    }
+
+    fn to_account_data(&self) -> Vec<u8> {
+        let mut data = Self::discriminator().to_vec();
+        data.extend(self.try_to_vec().unwrap());
+        data
+    }
+}
 
+/// The arguments of a delegation program instruction.
+pub trait ProgramInstruction: BorshSerialize + BorshDeserialize {
+    const NAME: &'static str;
+    /// Account names in the order the program expects them.
+    const ACCOUNTS: &'static [&'static str];
 
-#[derive(BorshSerialize, BorshDeserialize)]
+    fn discriminator() -> [u8; 8] {
+        anchor_discriminator(&format!("global:{}", Self::NAME))
+    }
+
+    fn instruction(&self, accounts: Vec<AccountMeta>) -> Instruction {
+        assert_eq!(accounts.len(), Self::ACCOUNTS.len(), "{} takes {:?}", Self::NAME, Self::ACCOUNTS);
+        let mut data = Self::discriminator().to_vec();
+        data.extend(self.try_to_vec().unwrap());
+        Instruction {
+            program_id: get_delegation_program_id(),
+            accounts,
+            data,
+        }
+    }
+}
+
+/// An event the delegation program emits with `emit!`.
+pub trait ProgramEvent: BorshDeserialize {
+    const NAME: &'static str;
+
+    fn discriminator() -> [u8; 8] {
+        anchor_discriminator(&format!("event:{}", Self::NAME))
+    }
+}
+
+// Accounts
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
 pub struct DelegatedAccount {
     pub owner: Pubkey,
     pub delegated_amount: u64,
@@ -15,16 +131,368 @@ pub struct DelegatedAccount {
     pub bump: u8,
 }
 
+impl ProgramAccount for DelegatedAccount {
+    const NAME: &'static str = "DelegatedAccount";
+}
+
+/// Latest state root the operator posted, stored in the `rollup_state` PDA.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct RollupState {
+    pub operator: Pubkey,
+    pub batch_id: u64,
+    pub state_root: [u8; 32],
+    /// L1 slot the root was posted in.
+    pub slot: u64,
+}
+
+impl ProgramAccount for RollupState {
+    const NAME: &'static str = "RollupState";
+}
+
+/// An L2 transaction submitted on L1, the sequencer has to include it.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct InboxEntry {
+    pub index: u64,
+    pub sender: Pubkey,
+    /// Last posted batch when the entry was enqueued.
+    pub batch_id: u64,
+    pub slot: u64,
+    /// Bincode encoded, signed L2 transaction.
+    pub transaction: Vec<u8>,
+}
+
+/// The forced inclusion queue, stored in the `inbox` PDA.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone, Default)]
+pub struct Inbox {
+    pub next_index: u64,
+    pub entries: Vec<InboxEntry>,
+}
+
+impl ProgramAccount for Inbox {
+    const NAME: &'static str = "Inbox";
+}
+
+/// Marks an owner as exited, so a forced exit can only happen once.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct ExitRecord {
+    pub batch_id: u64,
+}
+
+impl ProgramAccount for ExitRecord {
+    const NAME: &'static str = "ExitRecord";
+}
+
+// Instructions
 
-#[derive(BorshSerialize)]
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
 pub struct InitializeDelegateArgs {
     pub amount: u64,
 }
 
+impl ProgramInstruction for InitializeDelegateArgs {
+    const NAME: &'static str = "initialize_delegate";
+    const ACCOUNTS: &'static [&'static str] = &["owner", "delegated_account", "system_program"];
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct TopUpArgs {
+    pub amount: u64,
+}
+
+impl ProgramInstruction for TopUpArgs {
+    const NAME: &'static str = "top_up";
+    const ACCOUNTS: &'static [&'static str] = &["owner", "delegated_account", "system_program"];
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct WithdrawArgs {
+    pub amount: u64,
+}
+
+impl ProgramInstruction for WithdrawArgs {
+    const NAME: &'static str = "withdraw";
+    const ACCOUNTS: &'static [&'static str] = &["owner", "delegated_account", "system_program"];
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct SettleWithdrawalArgs {
+    pub amount: u64,
+}
+
+impl ProgramInstruction for SettleWithdrawalArgs {
+    const NAME: &'static str = "settle_withdrawal";
+    const ACCOUNTS: &'static [&'static str] = &["owner", "delegated_account", "operator"];
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct PostStateRootArgs {
+    pub batch_id: u64,
+    pub state_root: [u8; 32],
+    /// Inbox entries below this index were included by the batch or earlier
+    /// ones, they are popped from the inbox.
+    pub inbox_index: u64,
+}
+
+impl ProgramInstruction for PostStateRootArgs {
+    const NAME: &'static str = "post_state_root";
+    const ACCOUNTS: &'static [&'static str] = &["operator", "rollup_state", "inbox", "system_program"];
+}
+
+/// The owner's rollup account as committed to in the state root, and the
+/// merkle path from it to the root.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct ForceExitArgs {
+    pub batch_id: u64,
+    pub lamports: u64,
+    pub account_owner: Pubkey,
+    pub executable: bool,
+    pub rent_epoch: u64,
+    pub data: Vec<u8>,
+    /// Sibling hashes from the leaf up, and whether each one is the left node.
+    pub proof: Vec<([u8; 32], bool)>,
+}
+
+impl ProgramInstruction for ForceExitArgs {
+    const NAME: &'static str = "force_exit";
+    const ACCOUNTS: &'static [&'static str] =
+        &["owner", "delegated_account", "rollup_state", "exit_record", "system_program"];
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct EnqueueTransactionArgs {
+    pub transaction: Vec<u8>,
+}
+
+impl ProgramInstruction for EnqueueTransactionArgs {
+    const NAME: &'static str = "enqueue_transaction";
+    const ACCOUNTS: &'static [&'static str] = &["sender", "inbox", "rollup_state", "system_program"];
+}
+
+/// A rollup account as committed to in a state root, and its merkle path.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct ProvenAccount {
+    pub pubkey: Pubkey,
+    pub lamports: u64,
+    pub account_owner: Pubkey,
+    pub executable: bool,
+    pub rent_epoch: u64,
+    pub data: Vec<u8>,
+    /// Sibling hashes from the leaf up, and whether each one is the left node.
+    pub proof: Vec<([u8; 32], bool)>,
+}
+
+/// Disputes a settled state root while its challenge window is open: the
+/// block entry that doesn't reproduce, the accounts it touches proven
+/// against the state before it, and what executing it should have written.
+/// An `entry_index` equal to the block's entry count disputes the root the
+/// block or batch claims after all its entries reproduced.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct ChallengeStateRootArgs {
+    pub batch_id: u64,
+    pub block_slot: u64,
+    pub entry_index: u32,
+    /// Bincode encoded `BlockEntry`.
+    pub entry: Vec<u8>,
+    pub pre_state_root: [u8; 32],
+    pub pre_state: Vec<ProvenAccount>,
+    pub claimed_writes_hash: [u8; 32],
+    pub expected_writes_hash: [u8; 32],
+    pub expected_state_root: [u8; 32],
+}
+
+impl ProgramInstruction for ChallengeStateRootArgs {
+    const NAME: &'static str = "challenge_state_root";
+    const ACCOUNTS: &'static [&'static str] = &["challenger", "rollup_state"];
+}
+
+/// A decoded delegation program instruction.
+#[derive(Debug, Clone)]
+pub enum DelegationInstruction {
+    InitializeDelegate(InitializeDelegateArgs),
+    TopUp(TopUpArgs),
+    Withdraw(WithdrawArgs),
+    SettleWithdrawal(SettleWithdrawalArgs),
+    PostStateRoot(PostStateRootArgs),
+    ForceExit(ForceExitArgs),
+    EnqueueTransaction(EnqueueTransactionArgs),
+    ChallengeStateRoot(ChallengeStateRootArgs),
+}
+
+impl DelegationInstruction {
+    pub fn decode(data: &[u8]) -> Result<Self, DelegationError> {
+        fn args<T: ProgramInstruction>(data: &[u8]) -> Result<T, DelegationError> {
+            decode_with_discriminator(T::NAME, T::discriminator(), data)
+        }
+
+        if data.len() < 8 {
+            return Err(DelegationError::TooShort { expected: "instruction", len: data.len() });
+        }
+        let discriminator: [u8; 8] = data[..8].try_into().unwrap();
+        Ok(if discriminator == InitializeDelegateArgs::discriminator() {
+            Self::InitializeDelegate(args(data)?)
+        } else if discriminator == TopUpArgs::discriminator() {
+            Self::TopUp(args(data)?)
+        } else if discriminator == WithdrawArgs::discriminator() {
+            Self::Withdraw(args(data)?)
+        } else if discriminator == SettleWithdrawalArgs::discriminator() {
+            Self::SettleWithdrawal(args(data)?)
+        } else if discriminator == PostStateRootArgs::discriminator() {
+            Self::PostStateRoot(args(data)?)
+        } else if discriminator == ForceExitArgs::discriminator() {
+            Self::ForceExit(args(data)?)
+        } else if discriminator == EnqueueTransactionArgs::discriminator() {
+            Self::EnqueueTransaction(args(data)?)
+        } else if discriminator == ChallengeStateRootArgs::discriminator() {
+            Self::ChallengeStateRoot(args(data)?)
+        } else {
+            return Err(DelegationError::UnknownInstruction { discriminator });
+        })
+    }
+}
+
+// Events
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct DepositedEvent {
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub delegated_amount: u64,
+}
+
+impl ProgramEvent for DepositedEvent {
+    const NAME: &'static str = "Deposited";
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct WithdrawnEvent {
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub delegated_amount: u64,
+}
+
+impl ProgramEvent for WithdrawnEvent {
+    const NAME: &'static str = "Withdrawn";
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct StateRootPostedEvent {
+    pub batch_id: u64,
+    pub state_root: [u8; 32],
+    pub inbox_index: u64,
+}
+
+impl ProgramEvent for StateRootPostedEvent {
+    const NAME: &'static str = "StateRootPosted";
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct TransactionEnqueuedEvent {
+    pub index: u64,
+    pub sender: Pubkey,
+}
+
+impl ProgramEvent for TransactionEnqueuedEvent {
+    const NAME: &'static str = "TransactionEnqueued";
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct ForcedExitEvent {
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub batch_id: u64,
+}
+
+impl ProgramEvent for ForcedExitEvent {
+    const NAME: &'static str = "ForcedExit";
+}
+
+#[derive(Debug, Clone)]
+pub enum DelegationEvent {
+    Deposited(DepositedEvent),
+    Withdrawn(WithdrawnEvent),
+    StateRootPosted(StateRootPostedEvent),
+    TransactionEnqueued(TransactionEnqueuedEvent),
+    ForcedExit(ForcedExitEvent),
+}
+
+impl DelegationEvent {
+    pub fn decode(data: &[u8]) -> Result<Self, DelegationError> {
+        fn event<T: ProgramEvent>(data: &[u8]) -> Result<T, DelegationError> {
+            decode_with_discriminator(T::NAME, T::discriminator(), data)
+        }
+
+        if data.len() < 8 {
+            return Err(DelegationError::TooShort { expected: "event", len: data.len() });
+        }
+        let discriminator: [u8; 8] = data[..8].try_into().unwrap();
+        Ok(if discriminator == DepositedEvent::discriminator() {
+            Self::Deposited(event(data)?)
+        } else if discriminator == WithdrawnEvent::discriminator() {
+            Self::Withdrawn(event(data)?)
+        } else if discriminator == StateRootPostedEvent::discriminator() {
+            Self::StateRootPosted(event(data)?)
+        } else if discriminator == TransactionEnqueuedEvent::discriminator() {
+            Self::TransactionEnqueued(event(data)?)
+        } else if discriminator == ForcedExitEvent::discriminator() {
+            Self::ForcedExit(event(data)?)
+        } else {
+            return Err(DelegationError::UnknownEvent { discriminator });
+        })
+    }
+
+    /// Events in a transaction's log messages, `Program data: <base64>` lines.
+    /// Lines of other programs that fail to decode are skipped.
+    pub fn parse_logs(logs: &[String]) -> Vec<Self> {
+        logs.iter()
+            .filter_map(|line| line.strip_prefix("Program data: "))
+            .filter_map(|data| BASE64.decode(data).ok())
+            .filter_map(|data| Self::decode(&data).ok())
+            .collect()
+    }
+}
+
+// Program id and PDAs
+
+/// Slots without a new state root after which owners can force their exit
+/// on L1, about a day at 400ms slots.
+pub const FORCE_EXIT_TIMEOUT_SLOTS: u64 = 216_000;
+
+/// Batches an inbox entry may wait before the program refuses new state
+/// roots that don't include it.
+pub const FORCED_INCLUSION_BATCHES: u64 = 4;
+
+static DELEGATION_PROGRAM_ID: OnceLock<Pubkey> = OnceLock::new();
+
+/// Overrides the default program id, has to be called once at startup before
+/// any delegation instruction is built.
+pub fn set_delegation_program_id(program_id: Pubkey) {
+    if DELEGATION_PROGRAM_ID.set(program_id).is_err() {
+        log::warn!("Delegation program id was already set");
+    }
+}
+
 pub fn get_delegation_program_id() -> Pubkey {
-    "5MSF4TiUfD7dVm7P1ahPYJfEBLCUQn7hEPYXYHocVwzh"
-        .parse()
-        .unwrap()
+    *DELEGATION_PROGRAM_ID.get_or_init(|| {
+        "5MSF4TiUfD7dVm7P1ahPYJfEBLCUQn7hEPYXYHocVwzh"
+            .parse()
+            .unwrap()
+    })
+}
+
+static ROLLUP_INSTRUCTIONS: OnceLock<bool> = OnceLock::new();
+
+/// Whether the deployed program has the rollup's own instructions,
+/// `post_state_root`, `settle_withdrawal`, `force_exit`, `enqueue_transaction`
+/// and `challenge_state_root`, next to `initialize_delegate`, `top_up` and
+/// `withdraw`. Set once at startup like the program id.
+pub fn set_rollup_instructions(enabled: bool) {
+    if ROLLUP_INSTRUCTIONS.set(enabled).is_err() {
+        log::warn!("Delegation program instructions were already set");
+    }
+}
+
+pub fn has_rollup_instructions() -> bool {
+    *ROLLUP_INSTRUCTIONS.get_or_init(|| false)
 }
 
 pub fn find_delegation_pda(owner: &Pubkey) -> (Pubkey, u8) {
@@ -34,80 +502,110 @@ pub fn find_delegation_pda(owner: &Pubkey) -> (Pubkey, u8) {
     )
 }
 
+pub fn find_rollup_state_pda() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"rollup_state"], &get_delegation_program_id())
+}
+
+pub fn find_inbox_pda() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"inbox"], &get_delegation_program_id())
+}
+
+pub fn find_exit_record_pda(owner: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"exit", owner.as_ref()],
+        &get_delegation_program_id()
+    )
+}
+
+// Instruction builders
+
 pub fn create_delegation_instruction(owner: &Pubkey, amount: u64) -> Instruction {
     let (pda, _) = find_delegation_pda(owner);
-    
-    let discriminator = {
-        let mut hasher = Sha256::new();
-        hasher.update(b"global:initialize_delegate");
-        let result = hasher.finalize();
-        let mut disc = [0u8; 8];
-        disc.copy_from_slice(&result[..8]);
-        disc
-    };
-    
-    let mut ix_data = discriminator.to_vec();
-    ix_data.extend(InitializeDelegateArgs { amount }.try_to_vec().unwrap());
-
-    Instruction {
-        program_id: get_delegation_program_id(),
-        accounts: vec![
-            AccountMeta::new(*owner, true),              // Owner must be signer
-            AccountMeta::new(pda, false),                // PDA account
-            AccountMeta::new_readonly(system_program::id(), false), // System program
-        ],
-        data: ix_data,
-    }
+    InitializeDelegateArgs { amount }.instruction(vec![
+        AccountMeta::new(*owner, true),              // Owner must be signer
+        AccountMeta::new(pda, false),                // PDA account
+        AccountMeta::new_readonly(system_program::id(), false), // System program
+    ])
 }
 
 pub fn create_topup_instruction(owner: &Pubkey, amount: u64) -> Instruction {
     let (pda, _) = find_delegation_pda(owner);
-    
-    // Calculate Anchor discriminator for "top_up"
-    let discriminator = {
-        let mut hasher = Sha256::new();
-        hasher.update(b"global:top_up");
-        let result = hasher.finalize();
-        let mut disc = [0u8; 8];
-        disc.copy_from_slice(&result[..8]);
-        disc
-    };
-    
-    let mut ix_data = discriminator.to_vec();
-    ix_data.extend(amount.to_le_bytes());
-
-    Instruction {
-        program_id: get_delegation_program_id(),
-        accounts: vec![
-            AccountMeta::new(*owner, true),              // Owner must be signer
-            AccountMeta::new(pda, false),                // PDA to be topped up
-            AccountMeta::new_readonly(system_program::id(), false), // System program
-        ],
-        data: ix_data,
-    }
+    TopUpArgs { amount }.instruction(vec![
+        AccountMeta::new(*owner, true),              // Owner must be signer
+        AccountMeta::new(pda, false),                // PDA to be topped up
+        AccountMeta::new_readonly(system_program::id(), false), // System program
+    ])
 }
 
+/// The `withdraw` of an L2 withdrawal. Wallets build it, the node only parses
+/// it (`withdrawal::parse_withdrawal`).
+#[allow(dead_code)]
 pub fn create_withdrawal_instruction(pda: &Pubkey, owner: &Pubkey, amount: u64) -> Instruction {
-    // Calculate Anchor discriminator for "withdraw"
-    let discriminator = {
-        let mut hasher = Sha256::new();
-        hasher.update(b"global:withdraw");
-        let result = hasher.finalize();
-        let mut disc = [0u8; 8];
-        disc.copy_from_slice(&result[..8]);
-        disc
-    };
-    
-    let mut ix_data = discriminator.to_vec();
-    ix_data.extend(amount.to_le_bytes());
-
-    Instruction {
-        program_id: get_delegation_program_id(),
-        accounts: vec![
-            AccountMeta::new(*owner, true),         // Owner must be a signer
-            AccountMeta::new(*pda, false),          // PDA account
-            AccountMeta::new_readonly(system_program::id(), false), // System program
-        ],
-        data: ix_data,
-    }
-} 
\ No newline at end of file
+    WithdrawArgs { amount }.instruction(vec![
+        AccountMeta::new(*owner, true),         // Owner must be a signer
+        AccountMeta::new(*pda, false),          // PDA account
+        AccountMeta::new_readonly(system_program::id(), false), // System program
+    ])
+}
+
+/// Pays out an L2 withdrawal from the owner's PDA. Signed by the rollup
+/// operator, the program only accepts it from the operator key.
+pub fn create_settle_withdrawal_instruction(operator: &Pubkey, owner: &Pubkey, amount: u64) -> Instruction {
+    let (pda, _) = find_delegation_pda(owner);
+    SettleWithdrawalArgs { amount }.instruction(vec![
+        AccountMeta::new(*owner, false),             // Receives the payout
+        AccountMeta::new(pda, false),                // PDA account
+        AccountMeta::new_readonly(*operator, true),  // Rollup operator must be signer
+    ])
+}
+
+/// Posts a settled batch's state root, the root forced exits are proven
+/// against, and pops the inbox entries it included.
+pub fn create_post_state_root_instruction(operator: &Pubkey, batch_id: u64, state_root: [u8; 32], inbox_index: u64) -> Instruction {
+    let (rollup_state, _) = find_rollup_state_pda();
+    let (inbox, _) = find_inbox_pda();
+    PostStateRootArgs { batch_id, state_root, inbox_index }.instruction(vec![
+        AccountMeta::new(*operator, true),           // Rollup operator must be signer
+        AccountMeta::new(rollup_state, false),       // Rollup state PDA
+        AccountMeta::new(inbox, false),              // Inbox PDA
+        AccountMeta::new_readonly(system_program::id(), false), // System program
+    ])
+}
+
+/// Withdraws the owner's proven rollup balance straight from their PDA, only
+/// accepted once no state root was posted for `FORCE_EXIT_TIMEOUT_SLOTS`.
+pub fn create_force_exit_instruction(owner: &Pubkey, args: ForceExitArgs) -> Instruction {
+    let (pda, _) = find_delegation_pda(owner);
+    let (rollup_state, _) = find_rollup_state_pda();
+    let (exit_record, _) = find_exit_record_pda(owner);
+    args.instruction(vec![
+        AccountMeta::new(*owner, true),              // Owner must be signer
+        AccountMeta::new(pda, false),                // PDA account
+        AccountMeta::new_readonly(rollup_state, false), // Rollup state PDA
+        AccountMeta::new(exit_record, false),        // Exit record PDA
+        AccountMeta::new_readonly(system_program::id(), false), // System program
+    ])
+}
+
+/// Queues a signed L2 transaction for forced inclusion, for when the
+/// sequencer won't take it from `/submit_transaction`. Built by wallets.
+#[allow(dead_code)]
+pub fn create_enqueue_transaction_instruction(sender: &Pubkey, transaction: Vec<u8>) -> Instruction {
+    let (inbox, _) = find_inbox_pda();
+    let (rollup_state, _) = find_rollup_state_pda();
+    EnqueueTransactionArgs { transaction }.instruction(vec![
+        AccountMeta::new(*sender, true),             // Sender must be signer
+        AccountMeta::new(inbox, false),              // Inbox PDA
+        AccountMeta::new_readonly(rollup_state, false), // Rollup state PDA
+        AccountMeta::new_readonly(system_program::id(), false), // System program
+    ])
+}
+
+/// Submits a fraud challenge against a settled batch.
+pub fn create_challenge_state_root_instruction(challenger: &Pubkey, args: ChallengeStateRootArgs) -> Instruction {
+    let (rollup_state, _) = find_rollup_state_pda();
+    args.instruction(vec![
+        AccountMeta::new(*challenger, true),         // Challenger must be signer
+        AccountMeta::new(rollup_state, false),       // Rollup state PDA
+    ])
+}
@@ -1,80 +1,172 @@
 use {
-    crate::delegation::{create_delegation_instruction, create_withdrawal_instruction, find_delegation_pda, DelegatedAccount, create_topup_instruction}, anyhow::{anyhow, Result}, borsh::BorshDeserialize, log, solana_client::rpc_client::RpcClient, solana_sdk::{
-        account::{AccountSharedData, ReadableAccount}, message::Message, pubkey::Pubkey, signature::Keypair, signer::Signer, transaction::Transaction
-    }, std::collections::HashMap
+    crate::delegation::{create_delegation_instruction, create_force_exit_instruction, find_delegation_pda, get_delegation_program_id, has_rollup_instructions, DelegatedAccount, DelegationInstruction, ProgramAccount, create_topup_instruction}, crate::allowance::{outgoing_lamports, Allowance, AllowanceError, AllowanceLedger}, crate::escape_hatch::ExitProof, crate::l1_client::L1Client, anyhow::{anyhow, Result}, solana_sdk::{
+        account::ReadableAccount, message::Message, pubkey::Pubkey, signature::Signature, transaction::Transaction
+    }, std::{collections::HashMap, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, RwLock}, thread, time::Duration}
 };
 
+// Wait before resubscribing after the L1 subscription dropped
+const RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Builds delegation and withdrawal transactions for users to sign, and relays
+/// the signed ones to L1. The node never holds user keys.
+///
+/// Delegation PDAs are cached, and the cache is kept current by a
+/// subscription to the delegation program's accounts (`watch_program_accounts`).
+/// While the subscription is down every read goes to L1, since changes could
+/// be missed.
 pub struct DelegationService {
-    rpc_client: RpcClient,
-    pda_cache: HashMap<Pubkey, AccountSharedData>,
-    signers: HashMap<Pubkey, Keypair>,  // Store multiple signers
+    l1_client: Arc<dyn L1Client>,
+    /// PDA -> its delegation, `None` for PDAs known not to exist.
+    pda_cache: RwLock<HashMap<Pubkey, Option<DelegatedAccount>>>,
+    subscribed: AtomicBool,
+    allowances: Mutex<AllowanceLedger>,
 }
 
 impl DelegationService {
-    pub fn new(rpc_url: &str, initial_signer: Keypair) -> Self {
-        let mut signers = HashMap::new();
-        signers.insert(initial_signer.pubkey(), initial_signer);
-        
+    pub fn new(l1_client: Arc<dyn L1Client>, allowances: AllowanceLedger) -> Self {
         Self {
-            rpc_client: RpcClient::new(rpc_url.to_string()),
-            pda_cache: HashMap::new(),
-            signers,
+            l1_client,
+            pda_cache: RwLock::new(HashMap::new()),
+            subscribed: AtomicBool::new(false),
+            allowances: Mutex::new(allowances),
         }
     }
 
-    pub fn add_signer(&mut self, signer: Keypair) {
-        self.signers.insert(signer.pubkey(), signer);
-    }
-
-    pub fn get_or_fetch_pda(&mut self, user: &Pubkey) -> Result<Option<(Pubkey, DelegatedAccount)>> {
+    pub fn get_or_fetch_pda(&self, user: &Pubkey) -> Result<Option<(Pubkey, DelegatedAccount)>> {
         let (pda, _) = find_delegation_pda(user);
-        
-        // Always try fetching from chain first to be sure
-        match self.rpc_client.get_account(&pda) {
-            Ok(account) => {
+
+        if self.subscribed.load(Ordering::Acquire) {
+            if let Some(cached) = self.pda_cache.read().unwrap().get(&pda) {
+                return Ok(cached.clone().map(|delegation| (pda, delegation)));
+            }
+        }
+
+        match self.l1_client.get_account(&pda) {
+            Ok(Some(account)) => {
                 log::info!(
-                    "Found account for PDA: {}, data length: {}, owner: {}", 
+                    "Found account for PDA: {}, data length: {}, owner: {}",
                     pda,
                     account.data().len(),
                     account.owner()
                 );
-                // Skip the 8-byte discriminator when deserializing
-                if account.data().len() > 8 {
-                    if let Ok(delegation) = DelegatedAccount::try_from_slice(&account.data()[8..]) {
-                        self.pda_cache.insert(pda, account.into());
-                        Ok(Some((pda, delegation)))
-                    } else {
-                        log::warn!(
-                            "Account exists but couldn't deserialize data for PDA: {}. Data: {:?}", 
-                            pda,
-                            account.data()
-                        );
-                        Ok(None)
+                let delegation = DelegatedAccount::try_from_account_data(account.data())
+                    .map_err(|e| anyhow!("Delegation PDA {} doesn't match the program's layout: {}", pda, e))?;
+                self.cache(pda, Some(delegation.clone()));
+                Ok(Some((pda, delegation)))
+            }
+            Ok(None) => {
+                log::info!("No account found for PDA: {}", pda);
+                self.cache(pda, None);
+                Ok(None)
+            }
+            Err(e) => {
+                // Not knowing the delegation is not the same as not having one
+                log::warn!("Failed to fetch PDA: {} (Error: {})", pda, e);
+                self.pda_cache.write().unwrap().remove(&pda);
+                Err(e)
+            }
+        }
+    }
+
+    /// Only caches while subscribed, otherwise the entry could go stale
+    /// without anyone noticing. A change the subscription delivered while
+    /// the fetch was in flight is newer, so it is kept.
+    fn cache(&self, pda: Pubkey, delegation: Option<DelegatedAccount>) {
+        if self.subscribed.load(Ordering::Acquire) {
+            self.pda_cache.write().unwrap().entry(pda).or_insert(delegation);
+        }
+    }
+
+    /// Blocking loop, meant to be run on a dedicated thread. Applies every
+    /// change to a delegation PDA to the cache, and resubscribes with an empty
+    /// cache whenever the subscription drops.
+    pub fn watch_program_accounts(&self) {
+        let program_id = get_delegation_program_id();
+        loop {
+            let updates = match self.l1_client.subscribe_program_accounts(&program_id) {
+                Ok(updates) => updates,
+                Err(e) => {
+                    log::warn!("Failed to subscribe to delegation program accounts: {}", e);
+                    thread::sleep(RESUBSCRIBE_BACKOFF);
+                    continue;
+                }
+            };
+            log::info!("Subscribed to delegation program accounts");
+            self.pda_cache.write().unwrap().clear();
+            self.subscribed.store(true, Ordering::Release);
+
+            for (pubkey, account) in updates.iter() {
+                let mut pda_cache = self.pda_cache.write().unwrap();
+                match DelegatedAccount::try_from_account_data(account.data()) {
+                    Ok(delegation) => {
+                        log::info!("Delegation PDA {} changed, delegated amount: {}", pubkey, delegation.delegated_amount);
+                        pda_cache.insert(pubkey, Some(delegation));
+                    }
+                    // Closed, or one of the program's other accounts
+                    Err(_) if account.lamports() == 0 => {
+                        pda_cache.insert(pubkey, None);
                     }
-                } else {
-                    log::warn!("Account data too short for PDA: {}", pda);
-                    Ok(None)
+                    Err(_) => {}
                 }
             }
-            Err(e) => {
-                log::info!("No account found for PDA: {} (Error: {})", pda, e);
-                self.pda_cache.remove(&pda);
-                Ok(None)
+
+            log::warn!("Delegation program subscription dropped, reading PDAs from L1 until it is back");
+            self.subscribed.store(false, Ordering::Release);
+            self.pda_cache.write().unwrap().clear();
+            thread::sleep(RESUBSCRIBE_BACKOFF);
+        }
+    }
+
+    /// What the owner delegated on L1 and how much of it they spent on the
+    /// rollup so far.
+    pub fn allowance(&self, owner: &Pubkey) -> Result<Allowance> {
+        let delegated = self
+            .get_or_fetch_pda(owner)?
+            .map(|(_, delegation)| delegation.delegated_amount)
+            .unwrap_or(0);
+        let spent = self.allowances.lock().unwrap().spent(owner);
+        Ok(Allowance {
+            owner: *owner,
+            delegated,
+            spent,
+            remaining: delegated.saturating_sub(spent),
+        })
+    }
+
+    /// Checks every account the transaction moves lamports out of against its
+    /// remaining allowance, and returns what each of them spends.
+    pub fn check_allowance(&self, tx: &Transaction, lamports_per_signature: u64) -> Result<HashMap<Pubkey, u64>> {
+        let outgoing = outgoing_lamports(tx, lamports_per_signature);
+        for (owner, requested) in outgoing.iter() {
+            let allowance = self.allowance(owner)?;
+            if allowance.delegated == 0 {
+                return Err(AllowanceError::NoDelegation { owner: *owner }.into());
+            }
+            if *requested > allowance.remaining {
+                return Err(AllowanceError::Exceeded {
+                    owner: *owner,
+                    requested: *requested,
+                    remaining: allowance.remaining,
+                }
+                .into());
             }
         }
+        Ok(outgoing)
     }
 
+    /// Charges an executed transaction's outgoing lamports to the allowances.
+    pub fn record_spend(&self, outgoing: &HashMap<Pubkey, u64>) {
+        self.allowances.lock().unwrap().record(outgoing);
+    }
+
+    /// Unsigned delegation (or top-up, if the PDA already exists) transaction
+    /// with a fresh blockhash, paid and signed by the user.
     pub fn create_delegation_transaction(
-        &mut self,
+        &self,
         user: &Pubkey,
         amount: u64,
     ) -> Result<Transaction> {
-        // First check PDA existence
         let has_existing = self.get_or_fetch_pda(user)?.is_some();
-        log::info!("create:::    signers are here: {:?}", self.signers);
-        // Then get signer after PDA check
-        let signer = self.signers.get(user)
-            .ok_or_else(|| anyhow!("No delegation signer found for {}", user))?;
 
         let instruction = if has_existing {
             create_topup_instruction(user, amount)
@@ -82,39 +174,58 @@ impl DelegationService {
             create_delegation_instruction(user, amount)
         };
 
-        let recent_blockhash = self.rpc_client.get_latest_blockhash()?;
+        let (recent_blockhash, _) = self.l1_client.get_latest_blockhash()?;
         let message = Message::new_with_blockhash(
             &[instruction],
-            Some(&signer.pubkey()),
+            Some(user),
             &recent_blockhash
         );
-        
-        let mut tx = Transaction::new_unsigned(message);
-        tx.try_sign(&[signer], recent_blockhash)?;
-        
-        Ok(tx)
-    }
 
-    pub fn update_pda_state(&mut self, pda: Pubkey, account: AccountSharedData) {
-        self.pda_cache.insert(pda, account);
+        Ok(Transaction::new_unsigned(message))
     }
 
-    pub fn create_withdrawal_transaction(&mut self, pda: &Pubkey, owner: &Pubkey, amount: u64) -> Result<Transaction> {
-        let instruction = create_withdrawal_instruction(pda, owner, amount);
-        log::info!("signers are here: {:?}", self.signers);
-        // Get the signer for the owner
-        let signer = self.signers.get(owner)
-            .ok_or_else(|| anyhow!("No delegation signer found for {}", owner))?;
-        
-        let recent_blockhash = self.rpc_client.get_latest_blockhash()?;
-        let message = Message::new(&[instruction], Some(&signer.pubkey()));
-        
-        let mut tx = Transaction::new_unsigned(message);
-        tx.sign(&[signer], recent_blockhash);
-        
-        Ok(tx)
+    /// Unsigned forced exit transaction for a proof the owner kept, paid and
+    /// signed by the owner. The proof is checked first, so a stale or
+    /// tampered one fails here rather than on L1.
+    pub fn create_force_exit_transaction(&self, proof: &ExitProof) -> Result<Transaction> {
+        if !has_rollup_instructions() {
+            return Err(anyhow!("the delegation program has no force_exit instruction"));
+        }
+        if !proof.verify() {
+            return Err(anyhow!("exit proof does not match its state root"));
+        }
+        let instruction = create_force_exit_instruction(&proof.owner, proof.force_exit_args());
+
+        let (recent_blockhash, _) = self.l1_client.get_latest_blockhash()?;
+        let message = Message::new_with_blockhash(&[instruction], Some(&proof.owner), &recent_blockhash);
+
+        Ok(Transaction::new_unsigned(message))
     }
-    pub fn get_keypair(&self, user: &Pubkey) -> Option<&Keypair> {
-        self.signers.get(user)
+
+    /// Relays a delegation or top-up transaction the user signed themselves.
+    /// Anything else is rejected before it reaches L1, a `withdraw` sent
+    /// straight to L1 would pay out without burning anything on the rollup.
+    pub fn submit_user_transaction(&self, tx: &Transaction) -> Result<Signature> {
+        tx.verify().map_err(|e| anyhow!("invalid signatures: {}", e))?;
+        let account_keys = &tx.message.account_keys;
+        for ix in tx.message.instructions.iter() {
+            if account_keys.get(ix.program_id_index as usize) != Some(&get_delegation_program_id()) {
+                return Err(anyhow!("only delegation program instructions can be relayed"));
+            }
+            match DelegationInstruction::decode(&ix.data) {
+                Ok(DelegationInstruction::InitializeDelegate(_) | DelegationInstruction::TopUp(_)) => {}
+                Ok(_) => return Err(anyhow!("only initialize_delegate and top_up can be relayed, withdraw through /submit_transaction")),
+                Err(e) => return Err(anyhow!("undecodable delegation instruction: {}", e)),
+            }
+            if ix.accounts.first().and_then(|index| account_keys.get(*index as usize)).is_none() {
+                return Err(anyhow!("delegation instruction without an owner"));
+            }
+        }
+
+        // Not confirmed here, the deposit watcher credits it once it is. The
+        // PDA cache follows the program's accounts, or isn't used at all
+        let signature = self.l1_client.send_transaction(tx)?;
+        log::info!("Relayed user delegation transaction: {}", signature);
+        Ok(signature)
     }
-} 
+}
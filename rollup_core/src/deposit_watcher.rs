@@ -0,0 +1,308 @@
+//! Indexes deposits into the delegation program and credits them to the
+//! depositor's rollup account. A deposit is only credited once its L1 slot is
+//! `confirmation_depth` slots behind the tip.
+//!
+//! The rollup's own program reports every deposit in a `Deposited` event,
+//! including deposits made through CPI, so with `delegation.rollup_instructions`
+//! deposits come from the transaction's logs. The deployed program emits no
+//! events, its deposits are read off the top-level instructions.
+
+use std::{fs, path::PathBuf, sync::Arc, thread, time::Duration};
+
+use anyhow::Result;
+use crossbeam::channel::Sender as CBSender;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{clock::Slot, hash::Hash, pubkey::Pubkey, signature::Signature, transaction::VersionedTransaction};
+
+use crate::{
+    delegation::{
+        get_delegation_program_id, has_rollup_instructions, DelegationEvent, DelegationInstruction,
+        InitializeDelegateArgs, TopUpArgs,
+    },
+    l1_client::{L1Client, L1TransactionInfo},
+    rollupdb::RollupDBMessage,
+};
+
+// Largest page the RPC serves for getSignaturesForAddress
+const SIGNATURE_PAGE_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct DepositWatcherConfig {
+    pub poll_interval: Duration,
+    /// Slots a deposit has to be behind the L1 tip before it is credited.
+    pub confirmation_depth: u64,
+    /// File the last credited L1 signature is persisted to.
+    pub cursor_path: PathBuf,
+}
+
+/// Lamports deposited into an owner's delegation PDA on L1.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Deposit {
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub l1_signature: Signature,
+    pub slot: Slot,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct DepositCursor {
+    last_signature: Option<Signature>,
+}
+
+pub struct DepositWatcher {
+    config: DepositWatcherConfig,
+    l1_client: Arc<dyn L1Client>,
+    rollupdb_sender: CBSender<RollupDBMessage>,
+    cursor: DepositCursor,
+}
+
+impl DepositWatcher {
+    pub fn new(
+        config: DepositWatcherConfig,
+        l1_client: Arc<dyn L1Client>,
+        rollupdb_sender: CBSender<RollupDBMessage>,
+    ) -> Self {
+        let cursor = fs::read(&config.cursor_path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Self {
+            config,
+            l1_client,
+            rollupdb_sender,
+            cursor,
+        }
+    }
+
+    /// Blocking loop, meant to be run on a dedicated thread.
+    pub fn run(mut self) {
+        log::info!("Watching {} for deposits", get_delegation_program_id());
+        loop {
+            if let Err(e) = self.poll() {
+                log::warn!("Deposit poll failed: {}", e);
+            }
+            thread::sleep(self.config.poll_interval);
+        }
+    }
+
+    fn poll(&mut self) -> Result<()> {
+        let program_id = get_delegation_program_id();
+        let infos = signatures_since(
+            self.l1_client.as_ref(),
+            &program_id,
+            self.cursor.last_signature,
+            SIGNATURE_PAGE_LIMIT,
+        )?;
+
+        let tip = self.l1_client.get_slot()?;
+        for info in infos {
+            if info.slot + self.config.confirmation_depth > tip {
+                // Not deep enough yet, picked up again on the next poll
+                break;
+            }
+            if info.err.is_none() {
+                let Some(tx) = self.l1_client.get_transaction(&info.signature)? else {
+                    // Not served yet, retry on the next poll
+                    break;
+                };
+                let deposits = if has_rollup_instructions() {
+                    parse_deposit_events(&tx.logs, info.signature, tx.slot)
+                } else {
+                    parse_deposits(&tx.transaction, info.signature, tx.slot)
+                };
+                for deposit in deposits {
+                    log::info!("Crediting deposit of {} lamports to {}", deposit.amount, deposit.owner);
+                    self.rollupdb_sender.send(RollupDBMessage {
+                        credit_deposit: Some(deposit),
+                        ..Default::default()
+                    })?;
+                }
+            }
+            self.cursor.last_signature = Some(info.signature);
+            self.persist_cursor();
+        }
+        Ok(())
+    }
+
+    fn persist_cursor(&self) {
+        // Renamed over the cursor, a crash never leaves it half written
+        let tmp_path = self.config.cursor_path.with_extension("tmp");
+        let result = serde_json::to_vec(&self.cursor)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| fs::write(&tmp_path, bytes).map_err(anyhow::Error::from))
+            .and_then(|_| fs::rename(&tmp_path, &self.config.cursor_path).map_err(anyhow::Error::from));
+        if let Err(e) = result {
+            log::error!("Failed to persist deposit cursor: {}", e);
+        }
+    }
+}
+
+/// Every transaction that touched `address` after `until`, oldest first, so
+/// the cursor only ever moves forward. Pages come newest first and are
+/// walked back until `until`.
+fn signatures_since(
+    l1_client: &dyn L1Client,
+    address: &Pubkey,
+    until: Option<Signature>,
+    limit: usize,
+) -> Result<Vec<L1TransactionInfo>> {
+    let mut infos = Vec::new();
+    let mut before = None;
+    loop {
+        let page = l1_client.get_signatures_for_address(address, before, until, limit)?;
+        let Some(oldest) = page.last() else {
+            break;
+        };
+        before = Some(oldest.signature);
+        infos.extend(page);
+    }
+    infos.reverse();
+    Ok(infos)
+}
+
+/// Every `initialize_delegate` and `top_up` instruction in a transaction.
+pub fn parse_deposits(tx: &VersionedTransaction, signature: Signature, slot: Slot) -> Vec<Deposit> {
+    let program_id = get_delegation_program_id();
+    let account_keys = tx.message.static_account_keys();
+
+    tx.message
+        .instructions()
+        .iter()
+        .filter(|ix| account_keys.get(ix.program_id_index as usize) == Some(&program_id))
+        .filter_map(|ix| {
+            let amount = match DelegationInstruction::decode(&ix.data) {
+                Ok(DelegationInstruction::InitializeDelegate(InitializeDelegateArgs { amount }))
+                | Ok(DelegationInstruction::TopUp(TopUpArgs { amount })) => amount,
+                Ok(_) => return None,
+                Err(e) => {
+                    log::error!("Undecodable delegation instruction in {}: {}", signature, e);
+                    return None;
+                }
+            };
+            let owner = *account_keys.get(*ix.accounts.first()? as usize)?;
+            Some(Deposit {
+                owner,
+                amount,
+                l1_signature: signature,
+                slot,
+            })
+        })
+        .collect()
+}
+
+/// Deposits in the `Deposited` events of a transaction's logs. The program's
+/// other events are only logged, the node reads their effects off its accounts.
+pub fn parse_deposit_events(logs: &[String], signature: Signature, slot: Slot) -> Vec<Deposit> {
+    DelegationEvent::parse_logs(logs)
+        .into_iter()
+        .filter_map(|event| match event {
+            DelegationEvent::Deposited(deposited) => {
+                log::debug!("{} has {} lamports delegated after {}", deposited.owner, deposited.delegated_amount, signature);
+                Some(Deposit {
+                    owner: deposited.owner,
+                    amount: deposited.amount,
+                    l1_signature: signature,
+                    slot,
+                })
+            }
+            DelegationEvent::Withdrawn(withdrawn) => {
+                log::info!(
+                    "{} withdrew {} lamports on L1, {} left delegated",
+                    withdrawn.owner,
+                    withdrawn.amount,
+                    withdrawn.delegated_amount
+                );
+                None
+            }
+            DelegationEvent::StateRootPosted(posted) => {
+                log::debug!(
+                    "Batch {} posted state root {} with inbox at {}",
+                    posted.batch_id,
+                    Hash::new_from_array(posted.state_root),
+                    posted.inbox_index
+                );
+                None
+            }
+            DelegationEvent::TransactionEnqueued(enqueued) => {
+                log::debug!("Inbox entry {} enqueued by {}", enqueued.index, enqueued.sender);
+                None
+            }
+            DelegationEvent::ForcedExit(exit) => {
+                log::warn!("{} force-exited {} lamports against batch {}", exit.owner, exit.amount, exit.batch_id);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{
+        native_token::LAMPORTS_PER_SOL, signature::Keypair, signer::Signer, transaction::Transaction,
+    };
+    use solana_system_interface::instruction as system_instruction;
+
+    use super::*;
+    use crate::{
+        delegation::{create_delegation_instruction, create_topup_instruction, create_withdrawal_instruction, find_delegation_pda},
+        mock_l1::MockL1,
+    };
+
+    #[test]
+    fn parses_deposits_of_delegation_instructions_only() {
+        let owner = Keypair::new();
+        let (pda, _) = find_delegation_pda(&owner.pubkey());
+        let tx = Transaction::new_signed_with_payer(
+            &[
+                create_delegation_instruction(&owner.pubkey(), 100),
+                system_instruction::transfer(&owner.pubkey(), &Pubkey::new_unique(), 5),
+                create_withdrawal_instruction(&pda, &owner.pubkey(), 7),
+                create_topup_instruction(&owner.pubkey(), 20),
+            ],
+            Some(&owner.pubkey()),
+            &[&owner],
+            Hash::default(),
+        );
+        let signature = tx.signatures[0];
+
+        let deposits = parse_deposits(&tx.into(), signature, 42);
+
+        let deposit = |amount| Deposit {
+            owner: owner.pubkey(),
+            amount,
+            l1_signature: signature,
+            slot: 42,
+        };
+        assert_eq!(deposits, vec![deposit(100), deposit(20)]);
+    }
+
+    #[test]
+    fn pages_back_to_the_cursor_oldest_first() {
+        let l1 = MockL1::new();
+        let owner = Keypair::new();
+        l1.airdrop(&owner.pubkey(), LAMPORTS_PER_SOL);
+        let mut sent = Vec::new();
+        for amount in 1..=5 {
+            let ix = if amount == 1 {
+                create_delegation_instruction(&owner.pubkey(), amount)
+            } else {
+                create_topup_instruction(&owner.pubkey(), amount)
+            };
+            let (blockhash, _) = l1.get_latest_blockhash().unwrap();
+            let tx = Transaction::new_signed_with_payer(&[ix], Some(&owner.pubkey()), &[&owner], blockhash);
+            sent.push(l1.send_and_confirm_transaction(&tx).unwrap());
+        }
+        let program_id = get_delegation_program_id();
+        let signatures = |until| -> Vec<Signature> {
+            signatures_since(&l1, &program_id, until, 2)
+                .unwrap()
+                .into_iter()
+                .map(|info| info.signature)
+                .collect()
+        };
+
+        assert_eq!(signatures(None), sent);
+        assert_eq!(signatures(Some(sent[1])), sent[2..]);
+        assert!(signatures(Some(sent[4])).is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+//! Forced exits for when the sequencer stops settling. Every settlement posts
+//! the batch's state root to the delegation program. If no new root is posted
+//! for `FORCE_EXIT_TIMEOUT_SLOTS`, owners can withdraw their rollup balance
+//! straight from their PDA by proving it against the last posted root.
+//!
+//! The node hands out those proofs while it is healthy, users are expected to
+//! keep the latest one around.
+
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    account::{AccountSharedData, ReadableAccount},
+    hash::Hash,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    delegation::ForceExitArgs,
+    merkle::{self, MerkleProof, MerkleTree},
+};
+
+/// The accounts as of the last settled batch, sorted by pubkey the same way
+/// the state root is built.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SettledState {
+    pub batch_id: u64,
+    pub state_root: Hash,
+    pub accounts: Vec<(Pubkey, AccountSharedData)>,
+}
+
+impl SettledState {
+    pub fn new(batch_id: u64, mut accounts: Vec<(Pubkey, AccountSharedData)>) -> Self {
+        accounts.sort_by_key(|(pubkey, _)| *pubkey);
+        let state_root = merkle::state_root(accounts.iter().map(|(pubkey, account)| (pubkey, account)));
+        Self {
+            batch_id,
+            state_root,
+            accounts,
+        }
+    }
+
+    /// `None` if `owner` had no rollup account in this state.
+    pub fn exit_proof(&self, owner: &Pubkey) -> Option<ExitProof> {
+        let index = self
+            .accounts
+            .binary_search_by_key(owner, |(pubkey, _)| *pubkey)
+            .ok()?;
+        let leaves = self
+            .accounts
+            .iter()
+            .map(|(pubkey, account)| merkle::account_leaf(pubkey, account))
+            .collect();
+        let proof = MerkleTree::new(leaves).proof(index)?;
+        Some(ExitProof {
+            batch_id: self.batch_id,
+            state_root: self.state_root,
+            owner: *owner,
+            account: self.accounts[index].1.clone(),
+            proof,
+        })
+    }
+}
+
+/// Everything an owner needs to force their exit on L1.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExitProof {
+    pub batch_id: u64,
+    pub state_root: Hash,
+    pub owner: Pubkey,
+    pub account: AccountSharedData,
+    pub proof: MerkleProof,
+}
+
+impl ExitProof {
+    pub fn verify(&self) -> bool {
+        self.proof
+            .verify(merkle::account_leaf(&self.owner, &self.account), &self.state_root)
+    }
+
+    pub fn force_exit_args(&self) -> ForceExitArgs {
+        ForceExitArgs {
+            batch_id: self.batch_id,
+            lamports: self.account.lamports(),
+            account_owner: *self.account.owner(),
+            executable: self.account.executable(),
+            rent_epoch: self.account.rent_epoch(),
+            data: self.account.data().to_vec(),
+            proof: self
+                .proof
+                .siblings
+                .iter()
+                .map(|(hash, is_left)| (hash.to_bytes(), *is_left))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::account::WritableAccount;
+
+    use super::*;
+
+    fn state() -> SettledState {
+        let accounts = (1..=5)
+            .map(|lamports| (Pubkey::new_unique(), AccountSharedData::new(lamports, 8, &Pubkey::new_unique())))
+            .collect();
+        SettledState::new(3, accounts)
+    }
+
+    #[test]
+    fn exit_proofs_verify_against_the_settled_root() {
+        let state = state();
+        for (owner, account) in state.accounts.iter() {
+            let proof = state.exit_proof(owner).unwrap();
+            assert_eq!((proof.batch_id, proof.state_root), (3, state.state_root));
+            assert_eq!(&proof.account, account);
+            assert!(proof.verify());
+
+            let args = proof.force_exit_args();
+            assert_eq!(args.lamports, account.lamports());
+            assert_eq!(args.proof.len(), proof.proof.siblings.len());
+        }
+        assert!(state.exit_proof(&Pubkey::new_unique()).is_none());
+    }
+
+    #[test]
+    fn tampered_exit_proofs_fail() {
+        let state = state();
+        let owner = state.accounts[1].0;
+
+        let mut proof = state.exit_proof(&owner).unwrap();
+        proof.account.set_lamports(proof.account.lamports() + 1);
+        assert!(!proof.verify());
+
+        let mut proof = state.exit_proof(&owner).unwrap();
+        proof.owner = state.accounts[2].0;
+        assert!(!proof.verify());
+
+        let mut proof = state.exit_proof(&owner).unwrap();
+        proof.state_root = Hash::new_unique();
+        assert!(!proof.verify());
+    }
+}
@@ -0,0 +1,286 @@
+//! Follower mode (`--mode follower`): a node without a sequencer. It takes
+//! the sequencer's blocks, streamed from a node's `/stream_blocks` or rebuilt
+//! from the batches the operator settles on L1, re-executes them with the
+//! same state transitions as `replay` and serves the read-only RPC from the
+//! result.
+//!
+//! A block that doesn't reproduce its state root is a sequencer fault: the
+//! follower logs it and stops at the last good block, still serving it.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{BufRead, BufReader},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{clock::Slot, hash::Hash, pubkey::Pubkey};
+
+use crate::{
+    block::{BlockEntry, BlockJournal, BlockRecord},
+    da::SettlementReader,
+    escape_hatch::SettledState,
+    frontend::StreamBlocks,
+    genesis::Genesis,
+    l1_client::L1Client,
+    pubsub::PubsubEvent,
+    replay::{writes_hash, Replayer},
+    state::RollupState,
+    withdrawal::{parse_withdrawal, WithdrawalStatus},
+};
+
+/// Where a follower takes blocks from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockSource {
+    /// Streamed from a node as they are sealed, the sequencer or another
+    /// follower.
+    #[default]
+    Sequencer,
+    /// From the data of settled batches, only as fresh as the last settlement
+    /// but needs nothing but L1.
+    Da,
+}
+
+#[derive(Debug, Clone)]
+pub struct FollowerConfig {
+    pub source: BlockSource,
+    pub sequencer_url: String,
+    pub operator: Option<Pubkey>,
+    pub poll_interval: Duration,
+}
+
+/// A block the sequencer sealed that doesn't reproduce.
+#[derive(Debug)]
+pub struct SequencerFault(String);
+
+impl fmt::Display for SequencerFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sequencer fault: {}", self.0)
+    }
+}
+
+impl std::error::Error for SequencerFault {}
+
+pub struct Follower {
+    config: FollowerConfig,
+    state: Arc<RollupState>,
+    replayer: Replayer,
+    journal: BlockJournal,
+    next_slot: Slot,
+    parent_hash: Hash,
+    /// Last batch followed from DA.
+    settled_batch: Option<u64>,
+}
+
+impl Follower {
+    /// Starts from the genesis and re-executes the blocks followed before a
+    /// restart from `journal`.
+    pub fn new(
+        config: FollowerConfig,
+        genesis: &Genesis,
+        lamports_per_signature: u64,
+        state: Arc<RollupState>,
+        journal: BlockJournal,
+    ) -> Result<Self> {
+        state.accounts.restore(0, genesis.accounts.clone());
+        let mut follower = Self {
+            config,
+            state,
+            replayer: Replayer::new(genesis.accounts.clone(), genesis.rent_collector(), lamports_per_signature),
+            journal,
+            next_slot: 1,
+            parent_hash: genesis.hash,
+            settled_batch: None,
+        };
+        let records = follower.journal.load()?;
+        for record in records.values() {
+            follower.apply(record)?;
+        }
+        if !records.is_empty() {
+            log::info!("Restored {} followed blocks up to slot {}", records.len(), follower.next_slot - 1);
+        }
+        Ok(follower)
+    }
+
+    /// Verifies and executes a block, then makes it visible to the RPC.
+    fn apply(&mut self, record: &BlockRecord) -> Result<()> {
+        let block = &record.block;
+        if block.slot != self.next_slot {
+            return Err(anyhow!("expected block {}, got {}", self.next_slot, block.slot));
+        }
+        if block.parent_hash != self.parent_hash || !block.verify() {
+            return Err(SequencerFault(format!("block {} doesn't chain to {}", block.slot, self.parent_hash)).into());
+        }
+
+        // Only visible once the whole block reproduced
+        let mut commits = HashMap::new();
+        let mut transactions = Vec::new();
+        let mut withdrawals = Vec::new();
+        for (index, entry) in record.entries.iter().enumerate() {
+            let writes = self
+                .replayer
+                .apply(entry)
+                .map_err(|e| SequencerFault(format!("block {}: entry {} fails to execute: {}", block.slot, index, e)))?;
+            if writes_hash(&writes) != entry.writes_hash() {
+                return Err(SequencerFault(format!(
+                    "block {}: entry {} ({:?}) writes other accounts than the sequencer committed",
+                    block.slot,
+                    index,
+                    entry.signature()
+                ))
+                .into());
+            }
+            match entry {
+                BlockEntry::Transaction { transaction, .. } => transactions.push(transaction.clone()),
+                BlockEntry::Withdrawal { transaction, payable, .. } => {
+                    if let Some(mut withdrawal) = parse_withdrawal(transaction) {
+                        if writes.is_empty() {
+                            let reason = if withdrawal.amount > *payable {
+                                "more than its delegation PDA can pay out"
+                            } else {
+                                "insufficient rollup balance"
+                            };
+                            withdrawal.status = WithdrawalStatus::Rejected { reason: reason.to_string() };
+                        } else {
+                            transactions.push(transaction.clone());
+                        }
+                        withdrawals.push(withdrawal);
+                    }
+                }
+                BlockEntry::Deposit { .. } | BlockEntry::Refund { .. } => {}
+            }
+            commits.extend(writes.into_iter().map(|(pubkey, account)| (pubkey, Some(account))));
+        }
+
+        let state_root = self.replayer.state_root();
+        if state_root != block.state_root {
+            return Err(SequencerFault(format!(
+                "block {} claims state root {}, its entries lead to {}",
+                block.slot, block.state_root, state_root
+            ))
+            .into());
+        }
+        // Logs aren't journaled, subscribers get the transactions without them
+        let pubsub = &self.state.pubsub;
+        pubsub.publish(PubsubEvent::Accounts {
+            slot: block.slot,
+            accounts: commits.iter().filter_map(|(pubkey, account)| Some((*pubkey, account.clone()?))).collect(),
+        });
+        for transaction in transactions.iter() {
+            pubsub.publish(PubsubEvent::Transaction {
+                slot: block.slot,
+                signature: transaction.signatures[0],
+                account_keys: transaction.message.account_keys.clone(),
+                logs: Vec::new(),
+            });
+        }
+        pubsub.publish(PubsubEvent::Slot { slot: block.slot });
+        self.state.accounts.commit(commits.into_iter().collect());
+        for transaction in transactions {
+            self.state.add_transaction(transaction);
+        }
+        for withdrawal in withdrawals {
+            self.state.add_withdrawal(withdrawal);
+        }
+        self.next_slot = block.slot + 1;
+        self.parent_hash = block.hash;
+        self.state.put_block(block.clone());
+        Ok(())
+    }
+
+    /// Applies a block that wasn't followed yet, journals it and passes it on
+    /// to the follower's own followers.
+    fn ingest(&mut self, record: &BlockRecord) -> Result<()> {
+        if record.block.slot < self.next_slot {
+            return Ok(());
+        }
+        self.apply(record)?;
+        self.journal.append(record)?;
+        self.state.publish_block(record);
+        log::info!("Followed block {} ({} entries), state root {}", record.block.slot, record.entries.len(), record.block.state_root);
+        Ok(())
+    }
+
+    /// Follows until a sequencer fault, reconnecting after anything else.
+    pub fn run(mut self, l1_client: Arc<dyn L1Client>) {
+        log::info!("Following {:?} from slot {}", self.config.source, self.next_slot);
+        let mut reader = self.config.operator.map(|operator| SettlementReader::new(l1_client, operator));
+        loop {
+            let result = match (self.config.source, reader.as_mut()) {
+                (BlockSource::Da, Some(reader)) => self.poll_da(reader),
+                (BlockSource::Da, None) => Err(anyhow!("following DA needs an operator")),
+                (BlockSource::Sequencer, _) => self.stream(),
+            };
+            match result {
+                Err(e) if e.is::<SequencerFault>() => {
+                    log::error!("Stopped following at block {}: {}", self.next_slot - 1, e);
+                    return;
+                }
+                Err(e) => log::warn!("Following {:?} failed: {}", self.config.source, e),
+                Ok(()) => {}
+            }
+            thread::sleep(self.config.poll_interval);
+        }
+    }
+
+    /// Reads the stream until the sequencer closes it.
+    fn stream(&mut self) -> Result<()> {
+        // The stream stays open as long as the sequencer runs
+        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let response = client
+            .post(format!("{}/stream_blocks", self.config.sequencer_url))
+            .json(&StreamBlocks { from_slot: Some(self.next_slot) })
+            .send()?
+            .error_for_status()?;
+        for line in BufReader::new(response).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: BlockRecord = serde_json::from_str(&line)?;
+            self.ingest(&record)?;
+        }
+        log::warn!("Block stream from {} ended", self.config.sequencer_url);
+        Ok(())
+    }
+
+    /// Applies the blocks of batches settled since the last poll. Their state
+    /// is what exit proofs are built against.
+    fn poll_da(&mut self, reader: &mut SettlementReader) -> Result<()> {
+        for batch in reader.next_batches()? {
+            // Settled again, its blocks were followed the first time
+            if self.settled_batch.is_some_and(|settled| batch.batch_id <= settled) {
+                continue;
+            }
+            let mut signatures = Vec::new();
+            for block in batch.data.blocks {
+                signatures.extend(block.block.transactions.iter().copied());
+                self.ingest(&BlockRecord::from(block))?;
+            }
+            let accounts = self
+                .replayer
+                .accounts()
+                .iter()
+                .map(|(pubkey, account)| (*pubkey, account.clone()))
+                .collect();
+            let settled = SettledState::new(batch.batch_id, accounts);
+            if settled.state_root != batch.state_root {
+                return Err(SequencerFault(format!(
+                    "batch {} settled root {}, its blocks lead to {}",
+                    batch.batch_id, batch.state_root, settled.state_root
+                ))
+                .into());
+            }
+            log::info!("Batch {} settled at L1 slot {} reproduces state root {}", batch.batch_id, batch.l1_slot, batch.state_root);
+            self.state.set_settled_state(settled);
+            self.state.pubsub.publish(PubsubEvent::Settled { slot: self.next_slot - 1, signatures });
+            self.settled_batch = Some(batch.batch_id);
+        }
+        Ok(())
+    }
+}
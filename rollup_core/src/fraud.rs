@@ -0,0 +1,360 @@
+//! Optimistic settlement: a settled state root stands unless it is
+//! challenged within `settlement.challenge_period_slots` L1 slots of landing.
+//!
+//! `rollup_core verify` follows the batches an operator settles, re-executes
+//! each from its published data with the same state transitions as `replay`
+//! and, at the first entry that doesn't reproduce, writes a challenge: the
+//! disputed entry, the accounts it touches proven against the state before
+//! it, and what it should have written. The challenge file carries the
+//! `challenge_state_root` instruction for the delegation program.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    account::{AccountSharedData, ReadableAccount},
+    clock::Slot,
+    hash::Hash,
+    instruction::Instruction,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    block::BlockEntry,
+    da::{BatchBlock, SettledBatch, SettlementReader},
+    delegation::{create_challenge_state_root_instruction, has_rollup_instructions, ChallengeStateRootArgs, ProvenAccount},
+    escape_hatch::SettledState,
+    genesis::Genesis,
+    l1_client::L1Client,
+    merkle::MerkleProof,
+    replay::{writes_hash, Replayer},
+    withdrawal::parse_withdrawal,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An account the disputed entry touches, as it was in the pre-state.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountProof {
+    pub pubkey: Pubkey,
+    pub account: AccountSharedData,
+    pub proof: MerkleProof,
+}
+
+impl From<&AccountProof> for ProvenAccount {
+    fn from(proof: &AccountProof) -> Self {
+        Self {
+            pubkey: proof.pubkey,
+            lamports: proof.account.lamports(),
+            account_owner: *proof.account.owner(),
+            executable: proof.account.executable(),
+            rent_epoch: proof.account.rent_epoch(),
+            data: proof.account.data().to_vec(),
+            proof: proof
+                .proof
+                .siblings
+                .iter()
+                .map(|(hash, is_left)| (hash.to_bytes(), *is_left))
+                .collect(),
+        }
+    }
+}
+
+/// Evidence that a settled batch's state root is wrong.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Challenge {
+    pub batch_id: u64,
+    /// The root the settlement posted.
+    pub claimed_state_root: Hash,
+    pub block_slot: Slot,
+    /// Position of the disputed entry in its block. The block's entry count
+    /// when every entry reproduced but the root claimed after them didn't.
+    pub entry_index: usize,
+    pub entry: Option<BlockEntry>,
+    pub reason: String,
+    /// Root of the rollup's state right before the entry.
+    pub pre_state_root: Hash,
+    /// The entry's accounts that exist in that state, the others are read
+    /// from L1 or created by the entry.
+    pub pre_state: Vec<AccountProof>,
+    pub claimed_writes_hash: Hash,
+    pub expected_writes: Vec<(Pubkey, AccountSharedData)>,
+    pub expected_writes_hash: Hash,
+    /// Root of the state after the entry, executed correctly.
+    pub expected_state_root: Hash,
+    /// L1 slot the settlement landed in, and the last one it can be
+    /// challenged in.
+    pub settled_slot: Slot,
+    pub challenge_ends_at: Slot,
+}
+
+impl Challenge {
+    pub fn args(&self) -> Result<ChallengeStateRootArgs> {
+        Ok(ChallengeStateRootArgs {
+            batch_id: self.batch_id,
+            block_slot: self.block_slot,
+            entry_index: self.entry_index.try_into()?,
+            entry: match &self.entry {
+                Some(entry) => bincode::serialize(entry)?,
+                None => Vec::new(),
+            },
+            pre_state_root: self.pre_state_root.to_bytes(),
+            pre_state: self.pre_state.iter().map(ProvenAccount::from).collect(),
+            claimed_writes_hash: self.claimed_writes_hash.to_bytes(),
+            expected_writes_hash: self.expected_writes_hash.to_bytes(),
+            expected_state_root: self.expected_state_root.to_bytes(),
+        })
+    }
+
+    pub fn instruction(&self, challenger: &Pubkey) -> Result<Instruction> {
+        Ok(create_challenge_state_root_instruction(challenger, self.args()?))
+    }
+}
+
+/// What `verify` writes to `challenge-<batch id>.json`.
+#[derive(Serialize)]
+struct ChallengeFile<'a> {
+    challenge: &'a Challenge,
+    /// Signed by the challenger, absent without a configured key.
+    instruction: Option<Instruction>,
+}
+
+/// The accounts an entry can read or write in the rollup's state.
+fn touched_accounts(entry: &BlockEntry) -> Vec<Pubkey> {
+    match entry {
+        BlockEntry::Transaction { transaction, .. } => transaction.message.account_keys.clone(),
+        BlockEntry::Withdrawal { transaction, .. } => {
+            parse_withdrawal(transaction).map(|withdrawal| vec![withdrawal.owner]).unwrap_or_default()
+        }
+        BlockEntry::Deposit { owner, .. } | BlockEntry::Refund { owner, .. } => vec![*owner],
+    }
+}
+
+/// The rollup re-executed batch by batch, from the genesis.
+struct Verifier {
+    replayer: Replayer,
+    next_slot: Slot,
+    parent_hash: Hash,
+    state_root: Hash,
+    /// Root each verified batch settled with.
+    verified: HashMap<u64, Hash>,
+    challenge_period_slots: u64,
+}
+
+impl Verifier {
+    fn new(genesis: &Genesis, lamports_per_signature: u64, challenge_period_slots: u64) -> Self {
+        let replayer = Replayer::new(genesis.accounts.clone(), genesis.rent_collector(), lamports_per_signature);
+        let state_root = replayer.state_root();
+        Self {
+            replayer,
+            next_slot: 1,
+            parent_hash: genesis.hash,
+            state_root,
+            verified: HashMap::new(),
+            challenge_period_slots,
+        }
+    }
+
+    /// `Some` if the batch doesn't reproduce, an error if its data can't be
+    /// verified at all.
+    fn verify(&mut self, batch: &SettledBatch) -> Result<Option<Challenge>> {
+        if let Some(state_root) = self.verified.get(&batch.batch_id) {
+            if *state_root != batch.state_root {
+                log::warn!("Batch {} settled again with root {}, verified {}", batch.batch_id, batch.state_root, state_root);
+            }
+            return Ok(None);
+        }
+        if batch.data.pre_state_root != self.state_root {
+            // A batch whose settlement failed moves the operator's pre-state
+            // root, its blocks are carried over into this one
+            log::warn!("Batch {} starts from root {}, verified {}", batch.batch_id, batch.data.pre_state_root, self.state_root);
+        }
+
+        let mut last_slot = self.next_slot.saturating_sub(1);
+        for BatchBlock { block, entries } in &batch.data.blocks {
+            if block.slot < self.next_slot {
+                continue;
+            }
+            if block.slot != self.next_slot {
+                return Err(anyhow!("batch {}: block {} is missing from its data", batch.batch_id, self.next_slot));
+            }
+            if block.parent_hash != self.parent_hash || !block.verify() {
+                return Err(anyhow!("batch {}: block {} doesn't chain to {}", batch.batch_id, block.slot, self.parent_hash));
+            }
+
+            for (index, entry) in entries.iter().enumerate() {
+                let touched = touched_accounts(entry);
+                let before: Vec<(Pubkey, Option<AccountSharedData>)> = touched
+                    .iter()
+                    .map(|pubkey| (*pubkey, self.replayer.accounts().get(pubkey).cloned()))
+                    .collect();
+                let (writes, reason) = match self.replayer.apply(entry) {
+                    Ok(writes) if writes_hash(&writes) != entry.writes_hash() => {
+                        (writes, Some("writes other accounts than the sequencer committed".to_string()))
+                    }
+                    Ok(writes) => (writes, None),
+                    // Failed transactions are never sequenced
+                    Err(err) => (Vec::new(), Some(format!("fails to execute: {}", err))),
+                };
+                let Some(reason) = reason else {
+                    continue;
+                };
+
+                let expected_state_root = self.replayer.state_root();
+                let mut accounts = self.replayer.accounts().clone();
+                for (pubkey, account) in before {
+                    match account {
+                        Some(account) => accounts.insert(pubkey, account),
+                        None => accounts.remove(&pubkey),
+                    };
+                }
+                let pre_state = SettledState::new(0, accounts.into_iter().collect());
+                let proofs = touched
+                    .iter()
+                    .filter_map(|pubkey| pre_state.exit_proof(pubkey))
+                    .map(|proof| AccountProof {
+                        pubkey: proof.owner,
+                        account: proof.account,
+                        proof: proof.proof,
+                    })
+                    .collect();
+                return Ok(Some(Challenge {
+                    batch_id: batch.batch_id,
+                    claimed_state_root: batch.state_root,
+                    block_slot: block.slot,
+                    entry_index: index,
+                    entry: Some(entry.clone()),
+                    reason: format!("entry {} ({:?}) {}", index, entry.signature(), reason),
+                    pre_state_root: pre_state.state_root,
+                    pre_state: proofs,
+                    claimed_writes_hash: entry.writes_hash(),
+                    expected_writes_hash: writes_hash(&writes),
+                    expected_writes: writes,
+                    expected_state_root,
+                    settled_slot: batch.l1_slot,
+                    challenge_ends_at: batch.l1_slot + self.challenge_period_slots,
+                }));
+            }
+
+            let state_root = self.replayer.state_root();
+            if state_root != block.state_root {
+                let reason = format!("block {} claims state root {}, its entries lead to {}", block.slot, block.state_root, state_root);
+                return Ok(Some(self.root_challenge(batch, block.slot, entries.len(), state_root, reason)));
+            }
+            self.next_slot = block.slot + 1;
+            self.parent_hash = block.hash;
+            self.state_root = state_root;
+            last_slot = block.slot;
+        }
+
+        if batch.state_root != self.state_root {
+            let entries = batch.data.blocks.last().map_or(0, |block| block.entries.len());
+            let reason = format!("batch settled root {}, its blocks lead to {}", batch.state_root, self.state_root);
+            return Ok(Some(self.root_challenge(batch, last_slot, entries, self.state_root, reason)));
+        }
+        self.verified.insert(batch.batch_id, batch.state_root);
+        Ok(None)
+    }
+
+    /// Every entry reproduced, the root claimed after them is wrong.
+    fn root_challenge(&self, batch: &SettledBatch, block_slot: Slot, entry_index: usize, state_root: Hash, reason: String) -> Challenge {
+        Challenge {
+            batch_id: batch.batch_id,
+            claimed_state_root: batch.state_root,
+            block_slot,
+            entry_index,
+            entry: None,
+            reason,
+            pre_state_root: state_root,
+            pre_state: Vec::new(),
+            claimed_writes_hash: Hash::default(),
+            expected_writes: Vec::new(),
+            expected_writes_hash: Hash::default(),
+            expected_state_root: state_root,
+            settled_slot: batch.l1_slot,
+            challenge_ends_at: batch.l1_slot + self.challenge_period_slots,
+        }
+    }
+}
+
+fn write_challenge(out_dir: &Path, challenge: &Challenge, challenger: Option<&Pubkey>) -> Result<PathBuf> {
+    fs::create_dir_all(out_dir)?;
+    let path = out_dir.join(format!("challenge-{:020}.json", challenge.batch_id));
+    let file = ChallengeFile {
+        challenge,
+        // Left out when the program couldn't execute it
+        instruction: challenger
+            .filter(|_| has_rollup_instructions())
+            .map(|challenger| challenge.instruction(challenger))
+            .transpose()?,
+    };
+    fs::write(&path, serde_json::to_vec_pretty(&file)?)?;
+    Ok(path)
+}
+
+/// `rollup_core verify`: checks every batch `operator` settled, and with
+/// `follow` every one it settles from now on. Returns an error once a
+/// challenge was written.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    genesis: &Genesis,
+    lamports_per_signature: u64,
+    challenge_period_slots: u64,
+    l1_client: Arc<dyn L1Client>,
+    operator: Pubkey,
+    challenger: Option<Pubkey>,
+    out_dir: &Path,
+    follow: bool,
+) -> Result<()> {
+    let mut verifier = Verifier::new(genesis, lamports_per_signature, challenge_period_slots);
+    let mut reader = SettlementReader::new(l1_client.clone(), operator);
+    loop {
+        for batch in reader.next_batches()? {
+            let Some(challenge) = verifier.verify(&batch)? else {
+                log::info!(
+                    "Batch {} ({} blocks, {}) reproduces state root {}",
+                    batch.batch_id,
+                    batch.data.blocks.len(),
+                    batch.commitment,
+                    batch.state_root
+                );
+                continue;
+            };
+            let path = write_challenge(out_dir, &challenge, challenger.as_ref())?;
+            let slot = l1_client.get_slot()?;
+            if slot > challenge.challenge_ends_at {
+                log::error!(
+                    "Batch {}'s challenge window closed at slot {}, the L1 is at {}",
+                    challenge.batch_id,
+                    challenge.challenge_ends_at,
+                    slot
+                );
+            }
+            return Err(anyhow!(
+                "batch {} doesn't reproduce: {}, challenge written to {}",
+                challenge.batch_id,
+                challenge.reason,
+                path.display()
+            ));
+        }
+        if !follow {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    log::info!(
+        "Verified {} batches up to slot {}, state root {}",
+        verifier.verified.len(),
+        verifier.next_slot.saturating_sub(1),
+        verifier.state_root
+    );
+    Ok(())
+}
@@ -1,23 +1,21 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
-    cell::RefCell,
+    sync::Arc,
 };
 
 use actix_web::{error, web, HttpResponse};
-use async_channel::{Receiver, Send, Sender};
-use crossbeam::channel::{Sender as CBSender, Receiver as CBReceiver};
+use crossbeam::channel::Sender as CBSender;
 use serde::{Deserialize, Serialize};
 use solana_sdk::hash::Hash; // keccak::Hash
+use solana_sdk::pubkey::Pubkey;
 use solana_sdk::transaction::Transaction;
-use crate::rollupdb::RollupDBMessage;
-
-// message format to send found transaction from db to frontend
-#[derive(Serialize, Deserialize)]
-pub struct FrontendMessage {
-    pub get_tx: Option<Hash>,
-    pub transaction: Option<Transaction>,
-}
+use crate::batch::BatchQuery;
+use crate::block::{BlockJournal, BlockQuery, BlockRecord};
+use crate::config::FeeConfig;
+use crate::delegation_service::DelegationService;
+use crate::withdrawal::{parse_withdrawal, WithdrawalQuery};
+use crate::escape_hatch::ExitProof;
+use crate::state::RollupState;
 
 // message format used to get transaction client
 #[derive(Serialize, Deserialize, Debug)]
@@ -32,6 +30,66 @@ pub struct RollupTransaction {
     pub sol_transaction: Transaction,
 }
 
+// message format used to look up batches, by id, by a contained transaction
+// signature, or the latest `limit` batches when neither is given
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetBatch {
+    pub batch_id: Option<u64>,
+    pub signature: Option<String>,
+    pub limit: Option<usize>,
+}
+
+// message format used to look up blocks, by slot, by a transaction
+// signature they contain, or the latest ones
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetBlock {
+    pub slot: Option<u64>,
+    pub signature: Option<String>,
+    pub limit: Option<usize>,
+}
+
+// message format used by followers to stream sealed blocks, with their
+// entries, as JSON lines from a slot on
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamBlocks {
+    pub from_slot: Option<u64>,
+}
+
+// message format used to look up withdrawals, by the signature of the L2
+// transaction that requested them or by owner
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetWithdrawal {
+    pub signature: Option<String>,
+    pub owner: Option<String>,
+}
+
+// message format used to request an owner's exit proof against the last
+// settled state root
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetExitProof {
+    pub owner: String,
+}
+
+// message format used to request an owner's remaining allowance
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetAllowance {
+    pub owner: String,
+}
+
+// message format used to request an unsigned delegation or withdrawal
+// transaction, the owner signs it locally and posts it back
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PrepareDelegationTransaction {
+    pub owner: String,
+    pub amount: u64,
+}
+
+// message format used to submit a delegation or withdrawal transaction signed by its owner
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignedDelegationTransaction {
+    pub transaction: Transaction,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "status", content = "data")]
 pub enum TransactionResponse {
@@ -43,11 +101,36 @@ pub enum TransactionResponse {
 pub async fn submit_transaction(
     body: web::Json<RollupTransaction>,
     sequencer_sender: web::Data<CBSender<Transaction>>,
+    delegation_service: web::Data<Arc<DelegationService>>,
+    fee_config: web::Data<FeeConfig>,
 ) -> actix_web::Result<HttpResponse> {
      // Validate transaction structure with serialization in function signature
      log::info!("Submitted transaction");
      log::info!("{body:?}");
 
+    // The sequencer verifies again, inbox transactions don't come through here
+    if let Err(e) = body.sol_transaction.verify() {
+        return Ok(HttpResponse::BadRequest().json(TransactionResponse::Error {
+            message: format!("invalid signatures: {}", e),
+        }));
+    }
+
+    // Overspends are rejected here already, the sequencer checks again
+    // against the allowance left when the transaction executes
+    if parse_withdrawal(&body.sol_transaction).is_none() {
+        let tx = body.sol_transaction.clone();
+        let lamports_per_signature = fee_config.lamports_per_signature;
+        let delegation_service = delegation_service.into_inner();
+        let allowed = web::block(move || delegation_service.check_allowance(&tx, lamports_per_signature))
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        if let Err(e) = allowed {
+            return Ok(HttpResponse::BadRequest().json(TransactionResponse::Error {
+                message: e.to_string(),
+            }));
+        }
+    }
+
        // Send transaction to sequencer
     sequencer_sender.send(body.sol_transaction.clone())
         .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
@@ -59,36 +142,226 @@ pub async fn submit_transaction(
 
 pub async fn get_transaction(
     body: web::Json<GetTransaction>,
-    sequencer_sender: web::Data<Sender<Transaction>>,
-    rollupdb_sender: web::Data<Sender<RollupDBMessage>>,
-    frontend_receiver: web::Data<Receiver<FrontendMessage>>,
+    rollup_state: web::Data<Arc<RollupState>>,
 ) -> actix_web::Result<HttpResponse> {
-    // Validate transaction structure with serialization in function signature
     log::info!("Requested transaction");
     log::info!("{body:?}");
 
-    rollupdb_sender
-        .send(RollupDBMessage {
-            lock_accounts: None,
-            add_new_data: None,
-            add_processed_transaction: None,
-            frontend_get_tx: Some(Hash::new(body.get_tx.as_bytes())),
-            add_settle_proof: None,
-            get_account: None,
-            bundle_tx: false
-        })
+    // Transactions are keyed by message hash
+    let hash: Hash = body
+        .get_tx
+        .parse()
+        .map_err(|_| error::ErrorBadRequest("invalid transaction hash"))?;
+    match rollup_state.transaction(&hash) {
+        Some(sol_transaction) => Ok(HttpResponse::Ok().json(RollupTransaction {
+            sender: "Rollup RPC".into(),
+            sol_transaction,
+        })),
+        None => Err(error::ErrorNotFound("no pending transaction with that hash")),
+    }
+}
+
+pub async fn get_batch(
+    body: web::Json<GetBatch>,
+    rollup_state: web::Data<Arc<RollupState>>,
+) -> actix_web::Result<HttpResponse> {
+    log::info!("Requested batch");
+    log::info!("{body:?}");
+
+    let query = if let Some(batch_id) = body.batch_id {
+        BatchQuery::ById(batch_id)
+    } else if let Some(signature) = &body.signature {
+        let signature = signature
+            .parse()
+            .map_err(|_| error::ErrorBadRequest("invalid transaction signature"))?;
+        BatchQuery::ByTransaction(signature)
+    } else {
+        BatchQuery::Latest(body.limit.unwrap_or(10))
+    };
+
+    Ok(HttpResponse::Ok().json(rollup_state.batches(&query)))
+}
+
+pub async fn get_block(
+    body: web::Json<GetBlock>,
+    rollup_state: web::Data<Arc<RollupState>>,
+) -> actix_web::Result<HttpResponse> {
+    log::info!("Requested block");
+    log::info!("{body:?}");
+
+    let query = if let Some(slot) = body.slot {
+        BlockQuery::BySlot(slot)
+    } else if let Some(signature) = &body.signature {
+        let signature = signature
+            .parse()
+            .map_err(|_| error::ErrorBadRequest("invalid transaction signature"))?;
+        BlockQuery::ByTransaction(signature)
+    } else {
+        BlockQuery::Latest(body.limit.unwrap_or(10))
+    };
+
+    Ok(HttpResponse::Ok().json(rollup_state.blocks(&query)))
+}
+
+pub async fn stream_blocks(
+    body: web::Json<StreamBlocks>,
+    rollup_state: web::Data<Arc<RollupState>>,
+    block_journal: web::Data<BlockJournal>,
+) -> actix_web::Result<HttpResponse> {
+    log::info!("Requested block stream");
+    log::info!("{body:?}");
+
+    // Subscribed before the journal is read, so no block falls in between
+    let live = rollup_state.subscribe_blocks();
+    let block_journal = block_journal.into_inner();
+    let journaled = web::block(move || block_journal.load())
+        .await?
+        .map_err(|e| error::ErrorServiceUnavailable(format!("failed to read the block journal: {}", e)))?;
+
+    let (sender, receiver) = async_channel::bounded::<Result<web::Bytes, std::io::Error>>(16);
+    let mut next_slot = body.from_slot.unwrap_or(1);
+    actix_web::rt::spawn(async move {
+        let line = |record: &BlockRecord| {
+            let mut line = serde_json::to_vec(record).unwrap();
+            line.push(b'\n');
+            web::Bytes::from(line)
+        };
+        let from_slot = next_slot;
+        for record in journaled.into_values().filter(|record| record.block.slot >= from_slot) {
+            next_slot = record.block.slot + 1;
+            if sender.send(Ok(line(&record))).await.is_err() {
+                return;
+            }
+        }
+        // Ends when the follower disconnects or falls too far behind
+        while let Ok(record) = live.recv().await {
+            if record.block.slot < next_slot {
+                continue;
+            }
+            next_slot = record.block.slot + 1;
+            if sender.send(Ok(line(&record))).await.is_err() {
+                return;
+            }
+        }
+    });
+    Ok(HttpResponse::Ok().content_type("application/x-ndjson").streaming(receiver))
+}
+
+pub async fn get_withdrawal(
+    body: web::Json<GetWithdrawal>,
+    rollup_state: web::Data<Arc<RollupState>>,
+) -> actix_web::Result<HttpResponse> {
+    log::info!("Requested withdrawal");
+    log::info!("{body:?}");
+
+    let query = if let Some(signature) = &body.signature {
+        let signature = signature
+            .parse()
+            .map_err(|_| error::ErrorBadRequest("invalid transaction signature"))?;
+        WithdrawalQuery::BySignature(signature)
+    } else if let Some(owner) = &body.owner {
+        let owner = owner.parse().map_err(|_| error::ErrorBadRequest("invalid owner"))?;
+        WithdrawalQuery::ByOwner(owner)
+    } else {
+        return Err(error::ErrorBadRequest("either signature or owner is required"));
+    };
+
+    Ok(HttpResponse::Ok().json(rollup_state.withdrawals(&query)))
+}
+
+pub async fn get_exit_proof(
+    body: web::Json<GetExitProof>,
+    rollup_state: web::Data<Arc<RollupState>>,
+) -> actix_web::Result<HttpResponse> {
+    log::info!("Requested exit proof");
+    log::info!("{body:?}");
+
+    let owner: Pubkey = body.owner.parse().map_err(|_| error::ErrorBadRequest("invalid owner"))?;
+    match rollup_state.exit_proof(&owner) {
+        Some(exit_proof) => Ok(HttpResponse::Ok().json(exit_proof)),
+        None => Err(error::ErrorNotFound("no settled rollup account for owner")),
+    }
+}
+
+/// Builds the unsigned forced exit transaction for a proof from `/get_exit_proof`.
+pub async fn prepare_force_exit(
+    body: web::Json<ExitProof>,
+    delegation_service: web::Data<Arc<DelegationService>>,
+) -> actix_web::Result<HttpResponse> {
+    log::info!("Requested forced exit transaction");
+
+    let proof = body.into_inner();
+    let delegation_service = delegation_service.into_inner();
+    let tx = web::block(move || delegation_service.create_force_exit_transaction(&proof))
         .await
-        .unwrap();
+        .map_err(error::ErrorInternalServerError)?
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
 
-    if let Ok(frontend_message) = frontend_receiver.recv().await {
-        return Ok(HttpResponse::Ok().json(RollupTransaction {
-            sender: "Rollup RPC".into(),
-            sol_transaction: frontend_message.transaction.unwrap(),
-        }));
-        // Ok(HttpResponse::Ok().json(HashMap::from([("Transaction status", "requested")])))
+    Ok(HttpResponse::Ok().json(tx))
+}
+
+pub async fn get_allowance(
+    body: web::Json<GetAllowance>,
+    delegation_service: web::Data<Arc<DelegationService>>,
+) -> actix_web::Result<HttpResponse> {
+    log::info!("Requested allowance");
+    log::info!("{body:?}");
+
+    let owner: Pubkey = body.owner.parse().map_err(|_| error::ErrorBadRequest("invalid owner"))?;
+    let delegation_service = delegation_service.into_inner();
+    let allowance = web::block(move || delegation_service.allowance(&owner))
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(allowance))
+}
+
+/// Unsigned delegation transaction for the amount the owner chose, a top-up
+/// if they delegated before.
+pub async fn prepare_delegation(
+    body: web::Json<PrepareDelegationTransaction>,
+    delegation_service: web::Data<Arc<DelegationService>>,
+) -> actix_web::Result<HttpResponse> {
+    log::info!("Requested delegation transaction");
+    log::info!("{body:?}");
+
+    let owner: Pubkey = body.owner.parse().map_err(|_| error::ErrorBadRequest("invalid owner"))?;
+    let amount = body.amount;
+    if amount == 0 {
+        return Err(error::ErrorBadRequest("amount must be greater than 0"));
     }
+    let delegation_service = delegation_service.into_inner();
+    let tx = web::block(move || delegation_service.create_delegation_transaction(&owner, amount))
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(tx))
+}
+
+/// Relays a delegation or top-up transaction signed by its owner. Withdrawals
+/// are L2 transactions, they go through `/submit_transaction`.
+pub async fn submit_delegation(
+    body: web::Json<SignedDelegationTransaction>,
+    delegation_service: web::Data<Arc<DelegationService>>,
+) -> actix_web::Result<HttpResponse> {
+    log::info!("Submitted delegation transaction");
+
+    let tx = body.into_inner().transaction;
+    let delegation_service = delegation_service.into_inner();
+    let result = web::block(move || delegation_service.submit_user_transaction(&tx))
+        .await
+        .map_err(error::ErrorInternalServerError)?;
 
-    Ok(HttpResponse::Ok().json(HashMap::from([("Transaction status", "requested")])))
+    Ok(match result {
+        Ok(signature) => HttpResponse::Ok().json(TransactionResponse::Success {
+            message: signature.to_string(),
+        }),
+        Err(e) => HttpResponse::BadRequest().json(TransactionResponse::Error {
+            message: e.to_string(),
+        }),
+    })
 }
 
 pub async fn test() -> HttpResponse {
@@ -0,0 +1,183 @@
+//! Genesis of a rollup instance: the accounts and programs it starts with,
+//! its sysvar parameters, fees and the operator keys allowed to run it. Two
+//! nodes booted from the same genesis file start from the same state.
+//!
+//! The file is JSON, with base58 pubkeys and base64 account data. Program
+//! ELF paths are relative to the genesis file.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    account::{AccountSharedData, ReadableAccount, WritableAccount},
+    bpf_loader,
+    epoch_schedule::EpochSchedule,
+    hash::{hashv, Hash},
+    pubkey::Pubkey,
+    rent::Rent,
+};
+use solana_sdk_ids::system_program;
+
+use crate::config::FeeConfig;
+use crate::processor::RentCollector;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisAccount {
+    pub pubkey: String,
+    pub lamports: u64,
+    /// System program if unset
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Base64
+    #[serde(default)]
+    pub data: Option<String>,
+    #[serde(default)]
+    pub executable: bool,
+}
+
+/// A BPF program, deployed with the non-upgradeable loader.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisProgram {
+    pub program_id: String,
+    pub elf: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenesisSysvars {
+    pub rent: Rent,
+    pub epoch_schedule: EpochSchedule,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenesisFile {
+    pub accounts: Vec<GenesisAccount>,
+    pub programs: Vec<GenesisProgram>,
+    pub sysvars: GenesisSysvars,
+    /// Overrides the node's `[fees]` when set
+    pub fees: Option<FeeConfig>,
+    /// Operator pubkeys allowed to run the instance, any if empty
+    pub operators: Vec<String>,
+}
+
+/// A loaded genesis file, with its programs read and every key parsed.
+#[derive(Debug, Clone, Default)]
+pub struct Genesis {
+    /// Hash of the accounts the rollup starts with, equal on every node
+    /// booted from the same genesis.
+    pub hash: Hash,
+    pub accounts: Vec<(Pubkey, AccountSharedData)>,
+    pub sysvars: GenesisSysvars,
+    pub fees: Option<FeeConfig>,
+    pub operators: Vec<Pubkey>,
+}
+
+impl Genesis {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read genesis file {}", path.display()))?;
+        let file: GenesisFile = serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse genesis file {}", path.display()))?;
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+
+        let mut accounts = Vec::new();
+        for account in file.accounts.iter() {
+            let pubkey = parse_pubkey(&account.pubkey, "account")?;
+            let owner = match &account.owner {
+                Some(owner) => parse_pubkey(owner, "account owner")?,
+                None => system_program::id(),
+            };
+            let data = match &account.data {
+                Some(data) => base64::engine::general_purpose::STANDARD
+                    .decode(data)
+                    .with_context(|| format!("genesis account {} has invalid base64 data", pubkey))?,
+                None => Vec::new(),
+            };
+            let mut shared = AccountSharedData::new(account.lamports, data.len(), &owner);
+            shared.set_data_from_slice(&data);
+            shared.set_executable(account.executable);
+            accounts.push((pubkey, shared));
+        }
+
+        for program in file.programs.iter() {
+            let program_id = parse_pubkey(&program.program_id, "program")?;
+            let elf_path = base_dir.join(&program.elf);
+            let elf = fs::read(&elf_path)
+                .with_context(|| format!("failed to read program {} from {}", program_id, elf_path.display()))?;
+            let mut shared = AccountSharedData::new(
+                file.sysvars.rent.minimum_balance(elf.len()),
+                elf.len(),
+                &bpf_loader::id(),
+            );
+            shared.set_data_from_slice(&elf);
+            shared.set_executable(true);
+            accounts.push((program_id, shared));
+        }
+
+        let mut seen = HashSet::new();
+        if let Some((duplicate, _)) = accounts.iter().find(|(pubkey, _)| !seen.insert(*pubkey)) {
+            return Err(anyhow!("{} is listed more than once in the genesis file", duplicate));
+        }
+
+        let operators = file
+            .operators
+            .iter()
+            .map(|operator| parse_pubkey(operator, "operator"))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            hash: hash_accounts(&accounts),
+            accounts,
+            sysvars: file.sysvars,
+            fees: file.fees,
+            operators,
+        })
+    }
+
+    #[allow(deprecated)]
+    pub fn rent_collector(&self) -> RentCollector {
+        RentCollector {
+            rent: self.sysvars.rent.clone(),
+            epoch_schedule: self.sysvars.epoch_schedule.clone(),
+            ..RentCollector::default()
+        }
+    }
+
+    pub fn check_operator(&self, operator: &Pubkey) -> Result<()> {
+        if self.operators.is_empty() || self.operators.contains(operator) {
+            Ok(())
+        } else {
+            Err(anyhow!("operator {} is not one of the genesis operators", operator))
+        }
+    }
+}
+
+// Sorted by pubkey, the file's order doesn't matter
+fn hash_accounts(accounts: &[(Pubkey, AccountSharedData)]) -> Hash {
+    let mut sorted: Vec<&(Pubkey, AccountSharedData)> = accounts.iter().collect();
+    sorted.sort_by_key(|(pubkey, _)| *pubkey);
+    sorted.into_iter().fold(Hash::default(), |hash, (pubkey, account)| {
+        hashv(&[
+            hash.as_ref(),
+            pubkey.as_ref(),
+            &account.lamports().to_le_bytes(),
+            account.owner().as_ref(),
+            &[account.executable() as u8],
+            account.data(),
+        ])
+    })
+}
+
+fn parse_pubkey(pubkey: &str, what: &str) -> Result<Pubkey> {
+    Pubkey::from_str(pubkey).map_err(|_| anyhow!("invalid genesis {} pubkey {:?}", what, pubkey))
+}
@@ -0,0 +1,316 @@
+//! Checks the typed client in `delegation.rs` against the delegation program's
+//! Anchor IDL, as a test: discriminators, account lists and flags, and the
+//! borsh layouts of instruction args, accounts and events. The program's source
+//! isn't part of this repository, so `idl/delegation.json` can't be generated
+//! here. It is maintained by hand in Anchor's IDL format, and has to change
+//! together with the program and the client.
+//!
+//! The rollup's own instructions, accounts and events are only checked if the
+//! IDL has them, the deployed program doesn't (see `delegation.rollup_instructions`).
+//! Everything the IDL has, the client has to know.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use borsh::{
+    schema::{Declaration, Definition, Fields},
+    BorshSchema,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use solana_sdk::{instruction::Instruction, pubkey::Pubkey};
+
+use crate::config::DelegationConfig;
+use crate::delegation::{
+    create_challenge_state_root_instruction, create_delegation_instruction, create_enqueue_transaction_instruction,
+    create_force_exit_instruction, create_post_state_root_instruction, create_settle_withdrawal_instruction,
+    create_topup_instruction, create_withdrawal_instruction, find_delegation_pda,
+    ChallengeStateRootArgs, DelegatedAccount, DepositedEvent, EnqueueTransactionArgs, ExitRecord, ForceExitArgs,
+    ForcedExitEvent, Inbox, InitializeDelegateArgs, PostStateRootArgs, ProgramAccount, ProgramEvent,
+    ProgramInstruction, RollupState, SettleWithdrawalArgs, StateRootPostedEvent, TopUpArgs,
+    TransactionEnqueuedEvent, WithdrawArgs, WithdrawnEvent,
+};
+
+const DELEGATION_IDL: &str = include_str!("../idl/delegation.json");
+
+#[derive(Deserialize, Debug)]
+struct Idl {
+    address: String,
+    instructions: Vec<IdlInstruction>,
+    accounts: Vec<IdlItem>,
+    events: Vec<IdlItem>,
+    types: Vec<IdlTypeDef>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IdlInstruction {
+    name: String,
+    discriminator: [u8; 8],
+    accounts: Vec<IdlAccountMeta>,
+    args: Vec<IdlField>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IdlField {
+    name: String,
+    #[serde(rename = "type")]
+    ty: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct IdlTypeDef {
+    name: String,
+    #[serde(rename = "type")]
+    ty: IdlStruct,
+}
+
+#[derive(Deserialize, Debug)]
+struct IdlStruct {
+    fields: Vec<IdlField>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IdlAccountMeta {
+    name: String,
+    #[serde(default)]
+    writable: bool,
+    #[serde(default)]
+    signer: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct IdlItem {
+    name: String,
+    discriminator: [u8; 8],
+}
+
+/// A borsh layout, read from either side. Fields are named in the IDL and in
+/// the client's structs, the client's tuples leave the names out.
+#[derive(Debug)]
+enum Layout {
+    Primitive(String),
+    Array(Box<Layout>, u32),
+    Vec(Box<Layout>),
+    Fields(Vec<(Option<String>, Layout)>),
+}
+
+impl Layout {
+    /// Same encoding, and same field names where both sides name them.
+    fn matches(&self, other: &Layout) -> bool {
+        match (self, other) {
+            (Self::Primitive(a), Self::Primitive(b)) => a == b,
+            (Self::Array(a, a_len), Self::Array(b, b_len)) => a_len == b_len && a.matches(b),
+            (Self::Vec(a), Self::Vec(b)) => a.matches(b),
+            (Self::Fields(a), Self::Fields(b)) => {
+                a.len() == b.len()
+                    && a.iter().zip(b).all(|((a_name, a), (b_name, b))| {
+                        (a_name.is_none() || b_name.is_none() || a_name == b_name) && a.matches(b)
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    fn idl_fields(fields: &[IdlField], types: &[IdlTypeDef]) -> Result<Self> {
+        let fields = fields
+            .iter()
+            .map(|field| Ok((Some(field.name.clone()), Self::idl(&field.ty, types)?)))
+            .collect::<Result<_>>()?;
+        Ok(Self::Fields(fields))
+    }
+
+    fn idl(ty: &Value, types: &[IdlTypeDef]) -> Result<Self> {
+        if let Some(name) = ty.as_str() {
+            return Ok(match name {
+                "bytes" => Self::Vec(Box::new(Self::Primitive("u8".to_string()))),
+                name => Self::Primitive(name.to_string()),
+            });
+        }
+        if let Some([element, length]) = ty.get("array").and_then(Value::as_array).map(Vec::as_slice) {
+            let length = length.as_u64().ok_or_else(|| anyhow!("Array length {} is not a number", length))?;
+            return Ok(Self::Array(Box::new(Self::idl(element, types)?), length as u32));
+        }
+        if let Some(element) = ty.get("vec") {
+            return Ok(Self::Vec(Box::new(Self::idl(element, types)?)));
+        }
+        if let Some(name) = ty.pointer("/defined/name").and_then(Value::as_str) {
+            let def = types
+                .iter()
+                .find(|def| def.name == name)
+                .ok_or_else(|| anyhow!("Type {} is missing from the delegation IDL", name))?;
+            return Self::idl_fields(&def.ty.fields, types);
+        }
+        Err(anyhow!("Unsupported IDL type {}", ty))
+    }
+
+    fn client<T: BorshSchema>() -> Self {
+        let container = T::schema_container();
+        Self::declared(&container.declaration, &container.definitions)
+    }
+
+    fn declared(declaration: &Declaration, definitions: &HashMap<Declaration, Definition>) -> Self {
+        if declaration == "Pubkey" {
+            return Self::Primitive("pubkey".to_string());
+        }
+        match definitions.get(declaration) {
+            None => Self::Primitive(declaration.clone()),
+            Some(Definition::Array { length, elements }) => {
+                Self::Array(Box::new(Self::declared(elements, definitions)), *length)
+            }
+            Some(Definition::Sequence { elements }) => Self::Vec(Box::new(Self::declared(elements, definitions))),
+            Some(Definition::Tuple { elements }) | Some(Definition::Struct { fields: Fields::UnnamedFields(elements) }) => {
+                Self::Fields(elements.iter().map(|element| (None, Self::declared(element, definitions))).collect())
+            }
+            Some(Definition::Struct { fields: Fields::NamedFields(fields) }) => Self::Fields(
+                fields
+                    .iter()
+                    .map(|(name, element)| (Some(name.clone()), Self::declared(element, definitions)))
+                    .collect(),
+            ),
+            Some(Definition::Struct { fields: Fields::Empty }) => Self::Fields(Vec::new()),
+            // The program has no enums, so nothing in the IDL matches one
+            Some(Definition::Enum { .. }) => Self::Primitive(declaration.clone()),
+        }
+    }
+}
+
+// What the deployed program has, everything else is the rollup's own
+const BASE_INSTRUCTIONS: [&str; 3] = [InitializeDelegateArgs::NAME, TopUpArgs::NAME, WithdrawArgs::NAME];
+const BASE_ACCOUNTS: [&str; 1] = [DelegatedAccount::NAME];
+
+#[test]
+fn delegation_client_matches_idl() {
+    check(DELEGATION_IDL).unwrap();
+}
+
+/// Fails on the first difference between the IDL and the typed client.
+fn check(idl: &str) -> Result<()> {
+    let idl: Idl = serde_json::from_str(idl)?;
+    let program_id = DelegationConfig::default().program_id;
+    if idl.address != program_id {
+        return Err(anyhow!("Delegation IDL is for program {}, the default is {}", idl.address, program_id));
+    }
+
+    // Every builder with placeholder arguments, the metas are what the IDL
+    // account flags are checked against.
+    let owner = Pubkey::new_unique();
+    let force_exit_args = ForceExitArgs {
+        batch_id: 0,
+        lamports: 0,
+        account_owner: Pubkey::default(),
+        executable: false,
+        rent_epoch: 0,
+        data: vec![],
+        proof: vec![],
+    };
+    let challenge_args = ChallengeStateRootArgs {
+        batch_id: 0,
+        block_slot: 0,
+        entry_index: 0,
+        entry: vec![],
+        pre_state_root: [0; 32],
+        pre_state: vec![],
+        claimed_writes_hash: [0; 32],
+        expected_writes_hash: [0; 32],
+        expected_state_root: [0; 32],
+    };
+    let instructions: Vec<ClientInstruction> = vec![
+        instruction::<InitializeDelegateArgs>(create_delegation_instruction(&owner, 0)),
+        instruction::<TopUpArgs>(create_topup_instruction(&owner, 0)),
+        instruction::<WithdrawArgs>(create_withdrawal_instruction(&find_delegation_pda(&owner).0, &owner, 0)),
+        instruction::<SettleWithdrawalArgs>(create_settle_withdrawal_instruction(&Pubkey::new_unique(), &owner, 0)),
+        instruction::<PostStateRootArgs>(create_post_state_root_instruction(&owner, 0, [0; 32], 0)),
+        instruction::<ForceExitArgs>(create_force_exit_instruction(&owner, force_exit_args)),
+        instruction::<EnqueueTransactionArgs>(create_enqueue_transaction_instruction(&owner, vec![])),
+        instruction::<ChallengeStateRootArgs>(create_challenge_state_root_instruction(&owner, challenge_args)),
+    ];
+    for idl_ix in idl.instructions.iter() {
+        if !instructions.iter().any(|(name, ..)| *name == idl_ix.name) {
+            return Err(anyhow!("Instruction {} of the delegation IDL is unknown to the client", idl_ix.name));
+        }
+    }
+    for (name, accounts, discriminator, layout, ix) in instructions {
+        let Some(idl_ix) = idl.instructions.iter().find(|ix| ix.name == name) else {
+            if BASE_INSTRUCTIONS.contains(&name) {
+                return Err(anyhow!("Instruction {} is missing from the delegation IDL", name));
+            }
+            continue;
+        };
+        if idl_ix.discriminator != discriminator || ix.data[..8] != discriminator {
+            return Err(anyhow!("Instruction {} has discriminator {:?} in the IDL", name, idl_ix.discriminator));
+        }
+        let idl_names: Vec<&str> = idl_ix.accounts.iter().map(|meta| meta.name.as_str()).collect();
+        if idl_names != accounts {
+            return Err(anyhow!("Instruction {} takes {:?} in the IDL, the client passes {:?}", name, idl_names, accounts));
+        }
+        for (idl_meta, meta) in idl_ix.accounts.iter().zip(&ix.accounts) {
+            if idl_meta.writable != meta.is_writable || idl_meta.signer != meta.is_signer {
+                return Err(anyhow!(
+                    "Account {} of {} is writable: {}, signer: {} in the IDL",
+                    idl_meta.name,
+                    name,
+                    idl_meta.writable,
+                    idl_meta.signer
+                ));
+            }
+        }
+        let idl_layout = Layout::idl_fields(&idl_ix.args, &idl.types)?;
+        if !idl_layout.matches(&layout) {
+            return Err(anyhow!("Instruction {} takes {:?} in the IDL, the client encodes {:?}", name, idl_layout, layout));
+        }
+    }
+
+    let accounts = [
+        (DelegatedAccount::NAME, DelegatedAccount::discriminator(), Layout::client::<DelegatedAccount>()),
+        (RollupState::NAME, RollupState::discriminator(), Layout::client::<RollupState>()),
+        (Inbox::NAME, Inbox::discriminator(), Layout::client::<Inbox>()),
+        (ExitRecord::NAME, ExitRecord::discriminator(), Layout::client::<ExitRecord>()),
+    ];
+    check_items("account", &idl, &idl.accounts, &accounts, &BASE_ACCOUNTS)?;
+
+    let events = [
+        (DepositedEvent::NAME, DepositedEvent::discriminator(), Layout::client::<DepositedEvent>()),
+        (WithdrawnEvent::NAME, WithdrawnEvent::discriminator(), Layout::client::<WithdrawnEvent>()),
+        (StateRootPostedEvent::NAME, StateRootPostedEvent::discriminator(), Layout::client::<StateRootPostedEvent>()),
+        (
+            TransactionEnqueuedEvent::NAME,
+            TransactionEnqueuedEvent::discriminator(),
+            Layout::client::<TransactionEnqueuedEvent>(),
+        ),
+        (ForcedExitEvent::NAME, ForcedExitEvent::discriminator(), Layout::client::<ForcedExitEvent>()),
+    ];
+    check_items("event", &idl, &idl.events, &events, &[])?;
+    Ok(())
+}
+
+/// Name, account names, discriminator and args layout of a built instruction.
+type ClientInstruction = (&'static str, &'static [&'static str], [u8; 8], Layout, Instruction);
+
+fn instruction<T: ProgramInstruction + BorshSchema>(ix: Instruction) -> ClientInstruction {
+    (T::NAME, T::ACCOUNTS, T::discriminator(), Layout::client::<T>(), ix)
+}
+
+/// Accounts and events, their layouts are in the IDL's `types` under the same name.
+fn check_items(kind: &str, idl: &Idl, idl_items: &[IdlItem], items: &[(&str, [u8; 8], Layout)], required: &[&str]) -> Result<()> {
+    for idl_item in idl_items {
+        if !items.iter().any(|(name, ..)| *name == idl_item.name) {
+            return Err(anyhow!("The {} {} of the delegation IDL is unknown to the client", kind, idl_item.name));
+        }
+    }
+    for (name, discriminator, layout) in items {
+        let Some(idl_item) = idl_items.iter().find(|item| item.name == *name) else {
+            if required.contains(name) {
+                return Err(anyhow!("The {} {} is missing from the delegation IDL", kind, name));
+            }
+            continue;
+        };
+        if idl_item.discriminator != *discriminator {
+            return Err(anyhow!("The {} {} has discriminator {:?} in the IDL", kind, name, idl_item.discriminator));
+        }
+        let idl_layout = Layout::idl(&serde_json::json!({ "defined": { "name": name } }), &idl.types)?;
+        if !idl_layout.matches(layout) {
+            return Err(anyhow!("The {} {} is {:?} in the IDL, the client has {:?}", kind, name, idl_layout, layout));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! Forced inclusion. Users who get censored can queue a signed L2 transaction
+//! in the delegation program's inbox on L1. The delegation program refuses
+//! state roots that leave an entry out for more than
+//! `FORCED_INCLUSION_BATCHES` batches, so the watcher hands every new entry to
+//! the sequencer, which takes them ahead of `/submit_transaction` traffic.
+
+use std::{sync::Arc, thread, time::Duration};
+
+use anyhow::Result;
+use crossbeam::channel::Sender as CBSender;
+use solana_sdk::{account::Account, transaction::Transaction};
+
+use crate::{
+    delegation::{find_inbox_pda, find_rollup_state_pda, Inbox, InboxEntry, ProgramAccount, RollupState, FORCED_INCLUSION_BATCHES},
+    l1_client::L1Client,
+};
+
+#[derive(Debug, Clone)]
+pub struct InboxWatcherConfig {
+    pub poll_interval: Duration,
+}
+
+pub struct InboxWatcher {
+    config: InboxWatcherConfig,
+    l1_client: Arc<dyn L1Client>,
+    sequencer_sender: CBSender<InboxEntry>,
+    /// First entry not handed to the sequencer yet.
+    next_index: u64,
+}
+
+impl InboxWatcher {
+    /// `next_index` is the inbox index of the last recorded batch, entries
+    /// below it are already part of a batch.
+    pub fn new(
+        config: InboxWatcherConfig,
+        l1_client: Arc<dyn L1Client>,
+        sequencer_sender: CBSender<InboxEntry>,
+        next_index: u64,
+    ) -> Self {
+        Self {
+            config,
+            l1_client,
+            sequencer_sender,
+            next_index,
+        }
+    }
+
+    /// Blocking loop, meant to be run on a dedicated thread.
+    pub fn run(mut self) {
+        log::info!("Watching inbox {} from entry {}", find_inbox_pda().0, self.next_index);
+        loop {
+            if let Err(e) = self.poll() {
+                log::warn!("Inbox poll failed: {}", e);
+            }
+            thread::sleep(self.config.poll_interval);
+        }
+    }
+
+    fn poll(&mut self) -> Result<()> {
+        let Some(inbox) = self.l1_client.get_account(&find_inbox_pda().0)?.and_then(|account| decode_inbox(&account)) else {
+            return Ok(());
+        };
+        let last_batch_id = self
+            .l1_client
+            .get_account(&find_rollup_state_pda().0)?
+            .map(|account| RollupState::try_from_account_data(&account.data))
+            .transpose()?
+            .map(|state| state.batch_id);
+
+        let from_index = self.next_index;
+        for entry in inbox.entries.into_iter().filter(|entry| entry.index >= from_index) {
+            if last_batch_id.is_some_and(|batch_id| entry.batch_id + FORCED_INCLUSION_BATCHES <= batch_id + 1) {
+                log::warn!("Inbox entry {} has to be in the next batch", entry.index);
+            }
+            log::info!("Forcing inclusion of inbox entry {} from {}", entry.index, entry.sender);
+            self.next_index = entry.index + 1;
+            self.sequencer_sender.send(entry)?;
+        }
+        Ok(())
+    }
+}
+
+fn decode_inbox(account: &Account) -> Option<Inbox> {
+    match Inbox::try_from_account_data(&account.data) {
+        Ok(inbox) => Some(inbox),
+        Err(e) => {
+            log::error!("Failed to decode the inbox: {}", e);
+            None
+        }
+    }
+}
+
+/// The signed L2 transaction of an entry, `None` if it doesn't decode or its
+/// signatures don't verify. Such entries still count as included.
+pub fn decode_transaction(entry: &InboxEntry) -> Option<Transaction> {
+    let tx: Transaction = bincode::deserialize(&entry.transaction).ok()?;
+    tx.verify().ok()?;
+    Some(tx)
+}
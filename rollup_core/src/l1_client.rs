@@ -0,0 +1,284 @@
+//! The rollup's only view of the base chain. Everything that talks to L1 goes
+//! through the `L1Client` trait, so the node can run against a real cluster
+//! over RPC or against the in-process `MockL1` chain.
+
+use std::thread;
+
+use anyhow::Result;
+use crossbeam::channel::Receiver as CBReceiver;
+use serde::{Deserialize, Serialize};
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{
+    pubsub_client::PubsubClient,
+    rpc_client::{GetConfirmedSignaturesForAddress2Config, RpcClient},
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcSendTransactionConfig, RpcTransactionConfig},
+};
+use solana_sdk::{
+    account::Account,
+    clock::Slot,
+    commitment_config::{CommitmentConfig, CommitmentLevel},
+    hash::Hash,
+    pubkey::Pubkey,
+    signature::Signature,
+    transaction::{Transaction, TransactionError, VersionedTransaction},
+};
+use solana_transaction_status::UiTransactionEncoding;
+
+use crate::metrics::metrics;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct L1SignatureStatus {
+    pub slot: Slot,
+    pub err: Option<TransactionError>,
+    /// Highest commitment level the transaction has reached.
+    pub commitment: CommitmentLevel,
+}
+
+impl L1SignatureStatus {
+    pub fn satisfies(&self, commitment: CommitmentConfig) -> bool {
+        commitment_rank(self.commitment) >= commitment_rank(commitment.commitment)
+    }
+}
+
+fn commitment_rank(level: CommitmentLevel) -> u8 {
+    match level {
+        CommitmentLevel::Processed => 0,
+        CommitmentLevel::Confirmed => 1,
+        CommitmentLevel::Finalized => 2,
+    }
+}
+
+/// A transaction that touched an address, as returned by `get_signatures_for_address`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct L1TransactionInfo {
+    pub signature: Signature,
+    pub slot: Slot,
+    pub err: Option<TransactionError>,
+}
+
+/// An executed transaction, with the log messages its programs emitted.
+#[derive(Debug, Clone)]
+pub struct L1Transaction {
+    pub slot: Slot,
+    pub transaction: VersionedTransaction,
+    pub logs: Vec<String>,
+}
+
+pub trait L1Client: Send + Sync {
+    /// `None` if the account doesn't exist on L1.
+    fn get_account(&self, pubkey: &Pubkey) -> Result<Option<Account>>;
+
+    /// Like `get_account`, together with the slot the account was read at.
+    fn get_account_with_slot(&self, pubkey: &Pubkey) -> Result<(Slot, Option<Account>)>;
+
+    /// Latest blockhash and the last block height it is valid for.
+    fn get_latest_blockhash(&self) -> Result<(Hash, u64)>;
+
+    fn get_block_height(&self) -> Result<u64>;
+
+    fn get_minimum_balance_for_rent_exemption(&self, data_len: usize) -> Result<u64>;
+
+    /// Sends without waiting for confirmation.
+    fn send_transaction(&self, tx: &Transaction) -> Result<Signature>;
+
+    fn send_and_confirm_transaction(&self, tx: &Transaction) -> Result<Signature>;
+
+    /// `None` if the cluster hasn't seen the signature (yet).
+    fn get_signature_status(&self, signature: &Signature) -> Result<Option<L1SignatureStatus>>;
+
+    fn get_slot(&self) -> Result<Slot>;
+
+    /// Transactions that touched `address`, newest first, starting below
+    /// `before` and stopping at (and excluding) `until`.
+    fn get_signatures_for_address(
+        &self,
+        address: &Pubkey,
+        before: Option<Signature>,
+        until: Option<Signature>,
+        limit: usize,
+    ) -> Result<Vec<L1TransactionInfo>>;
+
+    /// `None` if the transaction isn't known (yet).
+    fn get_transaction(&self, signature: &Signature) -> Result<Option<L1Transaction>>;
+
+    /// Every change to an account owned by `program_id`, closed accounts come
+    /// with zero lamports. The receiver disconnects when the subscription
+    /// drops, changes after that are missed.
+    fn subscribe_program_accounts(&self, program_id: &Pubkey) -> Result<CBReceiver<(Pubkey, Account)>>;
+}
+
+pub struct RpcL1Client {
+    rpc_client: RpcClient,
+    ws_url: String,
+    commitment: CommitmentConfig,
+}
+
+impl RpcL1Client {
+    pub fn new(rpc_url: &str, ws_url: &str, commitment: CommitmentConfig) -> Self {
+        Self {
+            rpc_client: RpcClient::new_with_commitment(rpc_url.to_string(), commitment),
+            ws_url: ws_url.to_string(),
+            commitment,
+        }
+    }
+}
+
+/// Counts the call's failure on `/metrics`.
+fn counted<T, E: Into<anyhow::Error>>(method: &'static str, result: Result<T, E>) -> Result<T> {
+    result.map_err(|e| {
+        metrics().l1_rpc_error(method);
+        e.into()
+    })
+}
+
+impl L1Client for RpcL1Client {
+    fn get_account(&self, pubkey: &Pubkey) -> Result<Option<Account>> {
+        Ok(counted("getAccountInfo", self.rpc_client.get_account_with_commitment(pubkey, self.commitment))?.value)
+    }
+
+    fn get_account_with_slot(&self, pubkey: &Pubkey) -> Result<(Slot, Option<Account>)> {
+        let response = counted("getAccountInfo", self.rpc_client.get_account_with_commitment(pubkey, self.commitment))?;
+        Ok((response.context.slot, response.value))
+    }
+
+    fn get_latest_blockhash(&self) -> Result<(Hash, u64)> {
+        counted("getLatestBlockhash", self.rpc_client.get_latest_blockhash_with_commitment(self.commitment))
+    }
+
+    fn get_block_height(&self) -> Result<u64> {
+        counted("getBlockHeight", self.rpc_client.get_block_height_with_commitment(self.commitment))
+    }
+
+    fn get_minimum_balance_for_rent_exemption(&self, data_len: usize) -> Result<u64> {
+        counted("getMinimumBalanceForRentExemption", self.rpc_client.get_minimum_balance_for_rent_exemption(data_len))
+    }
+
+    fn send_transaction(&self, tx: &Transaction) -> Result<Signature> {
+        counted(
+            "sendTransaction",
+            self.rpc_client.send_transaction_with_config(
+                tx,
+                RpcSendTransactionConfig {
+                    preflight_commitment: Some(self.commitment.commitment),
+                    max_retries: Some(0),
+                    ..Default::default()
+                },
+            ),
+        )
+    }
+
+    fn send_and_confirm_transaction(&self, tx: &Transaction) -> Result<Signature> {
+        counted("sendTransaction", self.rpc_client.send_and_confirm_transaction(tx))
+    }
+
+    fn get_signature_status(&self, signature: &Signature) -> Result<Option<L1SignatureStatus>> {
+        let statuses = counted("getSignatureStatuses", self.rpc_client.get_signature_statuses(&[*signature]))?.value;
+        Ok(statuses.into_iter().next().flatten().map(|status| {
+            let commitment = if status.satisfies_commitment(CommitmentConfig::finalized()) {
+                CommitmentLevel::Finalized
+            } else if status.satisfies_commitment(CommitmentConfig::confirmed()) {
+                CommitmentLevel::Confirmed
+            } else {
+                CommitmentLevel::Processed
+            };
+            L1SignatureStatus {
+                slot: status.slot,
+                err: status.err,
+                commitment,
+            }
+        }))
+    }
+
+    fn get_slot(&self) -> Result<Slot> {
+        counted("getSlot", self.rpc_client.get_slot_with_commitment(self.commitment))
+    }
+
+    fn get_signatures_for_address(
+        &self,
+        address: &Pubkey,
+        before: Option<Signature>,
+        until: Option<Signature>,
+        limit: usize,
+    ) -> Result<Vec<L1TransactionInfo>> {
+        let statuses = counted(
+            "getSignaturesForAddress",
+            self.rpc_client.get_signatures_for_address_with_config(
+                address,
+                GetConfirmedSignaturesForAddress2Config {
+                    before,
+                    until,
+                    limit: Some(limit),
+                    commitment: Some(self.commitment),
+                },
+            ),
+        )?;
+        statuses
+            .into_iter()
+            .map(|status| {
+                Ok(L1TransactionInfo {
+                    signature: status.signature.parse()?,
+                    slot: status.slot,
+                    err: status.err,
+                })
+            })
+            .collect()
+    }
+
+    fn get_transaction(&self, signature: &Signature) -> Result<Option<L1Transaction>> {
+        let confirmed = match self.rpc_client.get_transaction_with_config(
+            signature,
+            RpcTransactionConfig {
+                encoding: Some(UiTransactionEncoding::Base64),
+                commitment: Some(self.commitment),
+                max_supported_transaction_version: Some(0),
+            },
+        ) {
+            Ok(confirmed) => confirmed,
+            Err(e) => {
+                log::debug!("Transaction {} not available: {}", signature, e);
+                return Ok(None);
+            }
+        };
+        let logs = confirmed
+            .transaction
+            .meta
+            .and_then(|meta| Option::<Vec<String>>::from(meta.log_messages))
+            .unwrap_or_default();
+        Ok(confirmed.transaction.transaction.decode().map(|transaction| L1Transaction {
+            slot: confirmed.slot,
+            transaction,
+            logs,
+        }))
+    }
+
+    fn subscribe_program_accounts(&self, program_id: &Pubkey) -> Result<CBReceiver<(Pubkey, Account)>> {
+        let config = RpcProgramAccountsConfig {
+            account_config: RpcAccountInfoConfig {
+                encoding: Some(UiAccountEncoding::Base64),
+                commitment: Some(self.commitment),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (subscription, updates) =
+            counted("programSubscribe", PubsubClient::program_subscribe(&self.ws_url, program_id, Some(config)))?;
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        thread::spawn(move || {
+            // Unsubscribes once dropped, when the socket closes or nobody listens anymore
+            let _subscription = subscription;
+            for update in updates.iter() {
+                let Ok(pubkey) = update.value.pubkey.parse::<Pubkey>() else {
+                    continue;
+                };
+                let Some(account) = update.value.account.decode::<Account>() else {
+                    log::warn!("Undecodable account update for {}", pubkey);
+                    continue;
+                };
+                if sender.send((pubkey, account)).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(receiver)
+    }
+}
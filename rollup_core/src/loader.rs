@@ -1,97 +1,102 @@
-//! PayTube's "account loader" component, which provides the SVM API with the
-//! ability to load accounts for PayTube channels.
-//!
-//! The account loader is a simple example of an RPC client that can first load
-//! an account from the base chain, then cache it locally within the protocol
-//! for the duration of the channel.
+//! Where the rollup's accounts come from. An account is looked up in the
+//! rollup's own state first, then, depending on the `ClonePolicy`, on L1, and
+//! is not found otherwise. The sequencer reads L1 before handing a transaction
+//! to RollupDB, which resolves the accounts, and `RollupAccountLoader` hands
+//! them to the SVM.
 
 use {
-    log::info, solana_client::rpc_client::RpcClient, solana_sdk::{
+    crate::{
+        delegation::{find_delegation_pda, DelegatedAccount, ProgramAccount},
+        l1_client::L1Client,
+    },
+    anyhow::Result,
+    serde::{Deserialize, Serialize},
+    solana_sdk::{
         account::{AccountSharedData, ReadableAccount},
+        clock::Slot,
+        native_loader,
         pubkey::Pubkey,
-    }, solana_svm::transaction_processing_callback::TransactionProcessingCallback, std::{collections::HashMap, sync::RwLock},
-    std::ops::IndexMut,
+    },
+    solana_svm::transaction_processing_callback::TransactionProcessingCallback,
+    std::{collections::HashMap, sync::{Arc, RwLock}},
 };
 
-// impl IndexMut for HashMap<K, V> {
-//     fn index_mut(&mut self, index: Idx) -> &mut Self::Output {
-        
-//     }
-// }
-/// An account loading mechanism to hoist accounts from the base chain up to
-/// an active PayTube channel.
-///
-/// Employs a simple cache mechanism to ensure accounts are only loaded once.
-pub struct RollupAccountLoader<'a> {
-    pub cache: RwLock<HashMap<Pubkey, AccountSharedData>>,
-    pub rpc_client: &'a RpcClient,
+/// What happens to accounts the rollup doesn't hold yet.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClonePolicy {
+    /// Only rollup-local accounts exist, everything else starts out empty.
+    #[default]
+    Never,
+    /// The L1 account is cloned into the rollup the first time a transaction
+    /// reads it, and is rollup-local from then on.
+    OnFirstRead,
+    /// L1 accounts are read fresh from L1 every time and only become
+    /// rollup-local once a transaction changes them.
+    ReadOnlyMirror,
 }
 
-impl<'a> RollupAccountLoader<'a> {
-    pub fn new(rpc_client: &'a RpcClient) -> Self {
-        Self {
-            cache: RwLock::new(HashMap::new()),
-            rpc_client,
-        }
+/// Which level of the hierarchy an account came from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountOrigin {
+    Local,
+    /// Cloned from L1, as of `slot`.
+    Cloned { slot: Slot },
+    /// Read from L1 as of `slot`, not part of the rollup's state.
+    Mirrored { slot: Slot },
+    NotFound,
+}
+
+/// The L1 end of the hierarchy, the rollup-local end is RollupDB's state.
+pub struct AccountSource {
+    l1_client: Arc<dyn L1Client>,
+    policy: ClonePolicy,
+}
+
+impl AccountSource {
+    pub fn new(l1_client: Arc<dyn L1Client>, policy: ClonePolicy) -> Self {
+        Self { l1_client, policy }
     }
 
-    pub fn add_account(&mut self, pubkey: Pubkey, modified_or_new_account: AccountSharedData) {
-        let mut map = self.cache.write().unwrap();
-        let res = map.contains_key(&pubkey);
-        if res == false {
-            map.insert(pubkey, modified_or_new_account);
-            log::info!("newone: {:?}", map);
-        } else { // PROBLEM HERE!!! SOMEHOW DON'T FIND THIS ELSE
-        
-            map.insert(pubkey, modified_or_new_account);
-            // map.entry(pubkey)
-            // .and_modify(|account| {
-            //     log::info!("Updating existing account.");
-            //     *account = modified_or_new_account.clone(); // Replace existing account
-            // });
-            log::info!("oldone: {:?}", map);
-            
+    /// The L1 account and where it would come from under the clone policy,
+    /// `None` if L1 doesn't have it or the policy never clones.
+    pub fn fetch(&self, pubkey: &Pubkey) -> Result<Option<(AccountSharedData, AccountOrigin)>> {
+        if self.policy == ClonePolicy::Never {
+            return Ok(None);
         }
-    //     let mut cache = self.cache.write().unwrap(); // Get a write lock once
-
-    // if let Some(account) = cache.get_mut(&pubkey) {
-    //     log::info!("Updating existing account");
-    //     *account = modified_or_new_account; // Overwrite existing entry
-    // } else {
-    //     log::info!("Adding new account");
-    //     cache.insert(pubkey, modified_or_new_account); // Insert new entry
-    // }
+        let (slot, account) = self.l1_client.get_account_with_slot(pubkey)?;
+        let origin = match self.policy {
+            ClonePolicy::ReadOnlyMirror => AccountOrigin::Mirrored { slot },
+            _ => AccountOrigin::Cloned { slot },
+        };
+        Ok(account.map(|account| (account.into(), origin)))
+    }
 
+    /// What `owner` delegated, the most their delegation PDA can pay out.
+    pub fn delegated_amount(&self, owner: &Pubkey) -> Result<u64> {
+        let (pda, _) = find_delegation_pda(owner);
+        match self.l1_client.get_account(&pda)? {
+            Some(account) => Ok(DelegatedAccount::try_from_account_data(&account.data)?.delegated_amount),
+            None => Ok(0),
+        }
+    }
+}
 
-        // let mut cache = self.cache.write().unwrap();
+/// The accounts of the transaction being executed, as resolved by RollupDB.
+/// Accounts it doesn't have are not found, the SVM treats them as new.
+pub struct RollupAccountLoader {
+    pub cache: RwLock<HashMap<Pubkey, AccountSharedData>>,
+}
 
+impl RollupAccountLoader {
+    pub fn new() -> Self {
+        Self {
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
 
-        // cache.entry(pubkey)
-        // .and_modify(|account| {
-        //     log::info!("Updating existing account.");
-        //     *account = modified_or_new_account.clone(); // Replace existing account
-        // })
-        // .or_insert_with(|| {
-        //     log::info!("Inserting new account.");
-        //     modified_or_new_account
-        // });
-        // if let Some(account) = self.cache.read().unwrap().get(&pubkey) {
-        //     log::info!("it is alright");
-        //     cache.entry(pubkey).and_modify(
-        //         //     |account| {
-        //         //     let data = account.data();
-        //         //     account.set_data_from_slice(&data)
-        //         // }
-        //         |account| *account = modified_or_new_account.clone()
-        //         );
-        // }
-        // else {
-        //     self.cache.write().unwrap().insert(pubkey, modified_or_new_account);
-        //     log::info!("fucking problem");
-        // } 
-        // log::info!("cache: {:?}", self.cache.read().unwrap());
-        // log::info!("entryyy: {:?}", self.cache.read().unwrap().get(&pubkey));
-        
+    pub fn add_account(&mut self, pubkey: Pubkey, modified_or_new_account: AccountSharedData) {
+        self.cache.write().unwrap().insert(pubkey, modified_or_new_account);
     }
 }
 
@@ -101,24 +106,22 @@ impl<'a> RollupAccountLoader<'a> {
 /// ability to load accounts.
 ///
 /// In the Agave validator, this implementation is Bank, powered by AccountsDB.
-impl TransactionProcessingCallback for RollupAccountLoader<'_> {
+impl TransactionProcessingCallback for RollupAccountLoader {
     fn get_account_shared_data(&self, pubkey: &Pubkey) -> Option<AccountSharedData> {
-        if let Some(account) = self.cache.read().unwrap().get(pubkey) {
-            log::info!("the account was loaded from the database");
-            return Some(account.clone());
-        }
-        else {
-            None
-        }
-
-        // let account: AccountSharedData = self.rpc_client.get_account(pubkey).ok()?.into();
-        // self.cache.write().unwrap().insert(*pubkey, account.clone());
-        // log::info!("the account was loaded from the rpcclient");
-        // Some(account)
+        self.cache.read().unwrap().get(pubkey).cloned()
     }
 
     fn account_matches_owners(&self, account: &Pubkey, owners: &[Pubkey]) -> Option<usize> {
         self.get_account_shared_data(account)
             .and_then(|account| owners.iter().position(|key| account.owner().eq(key)))
     }
+
+    // Builtins exist whatever the clone policy, the rollup runs them natively
+    fn add_builtin_account(&self, name: &str, program_id: &Pubkey) {
+        self.cache
+            .write()
+            .unwrap()
+            .entry(*program_id)
+            .or_insert_with(|| native_loader::create_loadable_account_with_fields(name, (1, 0)));
+    }
 }
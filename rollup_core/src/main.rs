@@ -1,17 +1,33 @@
 use std::thread;
-use std::sync::{Arc, RwLock};
+use std::str::FromStr;
+use std::sync::Arc;
 use crate::delegation_service::DelegationService;
+use crate::allowance::AllowanceLedger;
+use crate::config::{Cli, Command, NodeConfig, NodeMode};
+use crate::batch::{BatchJournal, BatchRecord};
+use crate::block::BlockJournal;
+use crate::withdrawal::WithdrawalJournal;
 
-use actix_web::{web, App, HttpResponse, HttpServer};
-use async_channel;
-use frontend::{FrontendMessage, RollupTransaction, TransactionResponse};
+use actix_web::{web, App, HttpServer};
+use clap::Parser;
 use rollupdb::{RollupDB, RollupDBMessage};
+use settle::{PendingSettlement, SettlementStatus, SettlementWorker};
+use l1_client::{L1Client, RpcL1Client};
+use mock_l1::MockL1;
+use deposit_watcher::DepositWatcher;
+use inbox::InboxWatcher;
+use loader::AccountSource;
+use state::RollupState;
+use snapshot::{SnapshotWriter, StateSnapshot};
+use genesis::Genesis;
+use follower::Follower;
+use plugin::Plugins;
+use metrics::{metrics, Worker};
+use da::{DaBackend, DataAvailability, FileDa, HttpDa, L1BufferDa, L1MemoDa};
 use solana_sdk::pubkey::Pubkey;
-use solana_sdk::signature::Keypair;
+use solana_sdk::signer::Signer;
 use solana_sdk::{account::AccountSharedData, transaction::Transaction};
 use tokio::runtime::Builder;
-use tokio::sync::oneshot;
-use crossbeam;
 mod frontend;
 mod rollupdb;
 mod sequencer;
@@ -19,46 +35,208 @@ mod settle;
 mod processor;
 mod loader;
 mod bundler;
-mod errors;
 mod delegation;
 mod delegation_service;
+mod batch;
+mod merkle;
+mod l1_client;
+mod mock_l1;
+mod config;
+mod signer;
+mod deposit_watcher;
+mod withdrawal;
+mod escape_hatch;
+mod inbox;
+#[cfg(test)]
+mod idl;
+mod allowance;
+mod state;
+mod snapshot;
+mod genesis;
+mod block;
+mod replay;
+mod da;
+mod fraud;
+mod follower;
+mod pubsub;
+mod plugin;
+mod metrics;
 
 // #[actix_web::main]
 // #[tokio::main]
-fn main() { // async
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
+fn main() -> anyhow::Result<()> { // async
+    let mut cli = Cli::parse();
+    let command = cli.command.take();
+    if let Some(Command::Keystore { keypair, out }) = &command {
+        return signer::create_keystore(keypair, out);
+    }
+    let mut config = NodeConfig::load(cli)?;
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or(config.log_level.as_str()));
+    std::fs::create_dir_all(&config.storage.path)?;
+    delegation::set_delegation_program_id(config.delegation_program_id());
+    delegation::set_rollup_instructions(config.rollup_instructions());
 
-    log::info!("starting HTTP server at http://localhost:8080");
+    let genesis = match &config.genesis {
+        Some(path) => {
+            let genesis = Genesis::load(path)?;
+            log::info!("Genesis {} with {} accounts from {}", genesis.hash, genesis.accounts.len(), path.display());
+            genesis
+        }
+        None => Genesis::default(),
+    };
+    if let Some(fees) = &genesis.fees {
+        config.fees = fees.clone();
+    }
+
+    if let Some(Command::Replay { blocks, snapshot }) = &command {
+        let blocks = blocks.clone().unwrap_or_else(|| config.storage_file("blocks.jsonl"));
+        return replay::run(&genesis, config.fees.lamports_per_signature, &blocks, snapshot.as_deref());
+    }
+
+    log::info!("starting HTTP server at http://{}", config.server.bind_address);
 
     let (sequencer_sender, sequencer_receiver) = 
         crossbeam::channel::unbounded::<Transaction>();
     let (rollupdb_sender, rollupdb_receiver) = crossbeam::channel::unbounded::<RollupDBMessage>();
+    let (settlement_sender, settlement_receiver) = crossbeam::channel::unbounded::<PendingSettlement>();
+    let (inbox_sender, inbox_receiver) = crossbeam::channel::unbounded::<delegation::InboxEntry>();
 
     
-    let (frontend_sender, frontend_receiver) = async_channel::unbounded::<FrontendMessage>(); // Channel for communication between data availability layer and frontend
     pub type PubkeyAccountSharedData = Option<Vec<(Pubkey, AccountSharedData)>>;
     let (account_sender, account_receiver) = async_channel::unbounded::<PubkeyAccountSharedData>();
     let (sender_locked_account, receiver_locked_account) = async_channel::unbounded::<bool>();
 
     let db_sender2 = rollupdb_sender.clone();
-    let fe_2 = frontend_sender.clone();
+    // Written by RollupDB, read directly by the RPC handlers
+    let rollup_state = Arc::new(RollupState::default());
+    let rollup_state_db = rollup_state.clone();
+    let rollup_state_sequencer = rollup_state.clone();
 
 
-    let signer = Keypair::new(); // Temporary keypair, will be replaced when client connects
-    
-    let (delegation_keypair_sender, delegation_keypair_receiver) = async_channel::unbounded::<Vec<u8>>();
-    
-    let delegation_service = Arc::new(RwLock::new(
-        DelegationService::new("https://api.devnet.solana.com", signer)
-    ));
+    let l1_client: Arc<dyn L1Client> = if config.cluster.mock {
+        log::info!("Using in-process mock L1");
+        Arc::new(MockL1::new())
+    } else {
+        Arc::new(RpcL1Client::new(&config.cluster.rpc_url, &config.cluster_ws_url(), config.cluster_commitment()))
+    };
+
+    if let Some(Command::FetchDa { operator, out }) = &command {
+        let operator = Pubkey::from_str(operator).map_err(|_| anyhow::anyhow!("invalid operator pubkey {:?}", operator))?;
+        return da::fetch(&l1_client, &operator, out);
+    }
+    if let Some(Command::Verify { operator, out, follow }) = &command {
+        let operator = Pubkey::from_str(operator).map_err(|_| anyhow::anyhow!("invalid operator pubkey {:?}", operator))?;
+        // Challenges are built for the configured key, if there is one
+        let challenger = config.operator_signer().ok().and_then(|signer| signer.try_pubkey().ok());
+        let out = out.clone().unwrap_or_else(|| config.storage_file("challenges"));
+        return fraud::run(
+            &genesis,
+            config.fees.lamports_per_signature,
+            config.settlement.challenge_period_slots,
+            l1_client,
+            operator,
+            challenger,
+            &out,
+            *follow,
+        );
+    }
+    if config.mode == NodeMode::Follower {
+        return run_follower(config, genesis, l1_client);
+    }
+
+    for worker in [Worker::Sequencer, Worker::RollupDb, Worker::Settlement] {
+        metrics().expect(worker);
+    }
+
+    let operator = config.operator_signer()?;
+    log::info!("Operator: {}", operator.try_pubkey()?);
+    genesis.check_operator(&operator.try_pubkey()?)?;
+
+    let data_availability: Arc<dyn DataAvailability> = match config.da.backend {
+        DaBackend::L1Memo => Arc::new(L1MemoDa::new(l1_client.clone(), Some(operator.clone()))),
+        DaBackend::L1Buffer => Arc::new(L1BufferDa::new(l1_client.clone(), Some(operator.clone()))),
+        DaBackend::File => Arc::new(FileDa::new(config.da_dir())),
+        DaBackend::Http => Arc::new(HttpDa::new(config.da.url.as_deref().unwrap())),
+    };
+    log::info!("Publishing batch data to {:?}", config.da.backend);
+
+    let delegation_service = Arc::new(
+        DelegationService::new(l1_client.clone(), AllowanceLedger::load(config.storage_file("allowances.json"))?)
+    );
+
+    // Keeps the delegation PDA cache current
+    let delegation_service_watch = delegation_service.clone();
+    thread::spawn(move || delegation_service_watch.watch_program_accounts());
 
     let delegation_service_clone = delegation_service.clone();
-    let delegation_service_clone_1 = delegation_service.clone();
-    
 
-    let asdserver_thread = thread::spawn(|| {
+    // Settlement worker, keeps retrying batches until they are confirmed on L1
+    let db_sender3 = rollupdb_sender.clone();
+    let settlement_config = config.settlement_config();
+    let l1_client_settle = l1_client.clone();
+    let settlement_thread = thread::spawn(move || {
+        SettlementWorker::run(
+            settlement_receiver,
+            db_sender3,
+            l1_client_settle,
+            operator,
+            data_availability,
+            settlement_config,
+        );
+    });
+
+    // Deposit watcher, credits delegation program deposits to rollup accounts
+    if config.deposits.enabled {
+        let watcher = DepositWatcher::new(config.deposit_watcher_config(), l1_client.clone(), rollupdb_sender.clone());
+        thread::spawn(move || watcher.run());
+    }
+
+    let account_source = AccountSource::new(l1_client.clone(), config.accounts.l1_clone);
+    let batch_journal = BatchJournal::new(config.storage_file("batch_journal.jsonl"));
+    let block_journal = BlockJournal::new(config.storage_file("blocks.jsonl"));
+    let withdrawal_journal = WithdrawalJournal::new(config.storage_file("withdrawals.jsonl"));
+    // Read by /stream_blocks for followers catching up
+    let stream_journal = web::Data::new(BlockJournal::new(config.storage_file("blocks.jsonl")));
+    let snapshot_writer = SnapshotWriter::new(config.snapshot_config());
+    let mut restored = match &config.snapshots.restore_from {
+        Some(path) => {
+            log::info!("Booting from snapshot {}", path.display());
+            Some(StateSnapshot::read(path)?)
+        }
+        None => None,
+    };
+
+    // Inbox watcher, feeds forced inclusions to the sequencer ahead of the mempool
+    if config.inbox.enabled && !config.rollup_instructions() {
+        log::warn!("The delegation program has no inbox without delegation.rollup_instructions, forced inclusion is off");
+    } else if config.inbox.enabled {
+        let next_index = batch_journal
+            .load()
+            .ok()
+            .and_then(|batches| batches.values().last().map(|record| record.inbox_index))
+            .unwrap_or(0)
+            .max(restored.as_ref().map_or(0, |snapshot| snapshot.inbox_index));
+        let watcher = InboxWatcher::new(config.inbox_watcher_config(), l1_client.clone(), inbox_sender, next_index);
+        thread::spawn(move || watcher.run());
+    }
+    // The state the batches the settlement worker resumes were cut at, and
+    // the last settled one's, which exit proofs are built against
+    let batches = batch_journal.load()?;
+    let last_settled = batches.values().rev().find(|record| matches!(record.status, SettlementStatus::Confirmed { .. }));
+    let rebuilt: Vec<BatchRecord> = batches
+        .values()
+        .filter(|record| record.status.is_settling())
+        .chain(last_settled)
+        .cloned()
+        .collect();
+    let boot = replay::boot(&genesis, config.fees.lamports_per_signature, restored.as_mut(), &block_journal, &rebuilt)?;
+    let plugins = Arc::new(Plugins::load(&config.plugins)?);
+    let sequencer_config = config.clone();
+    let rent_collector = genesis.rent_collector();
+    let runtime_threads = config.runtime.worker_threads;
+    thread::spawn(move || {
         let rt = Builder::new_multi_thread()
-            .worker_threads(4)
+            .worker_threads(runtime_threads)
             .enable_time()
             .build()
             .unwrap();
@@ -66,23 +244,30 @@ fn main() { // async
         rt.spawn(async {
             sequencer::run(
                 sequencer_receiver,
+                inbox_receiver,
                 db_sender2,
                 account_receiver,
                 receiver_locked_account,
                 delegation_service_clone,
+                account_source,
+                rollup_state_sequencer,
+                rent_collector,
+                sequencer_config,
             ).await.unwrap()
         });
 
         
-        rt.block_on(RollupDB::run(rollupdb_receiver, fe_2, account_sender, sender_locked_account, delegation_service_clone_1));
+        rt.block_on(RollupDB::run(rollupdb_receiver, rollup_state_db, account_sender, sender_locked_account, settlement_sender, batch_journal, block_journal, withdrawal_journal, snapshot_writer, restored, boot, genesis, plugins));
     });
    
 
      // Spawn the Actix Web server in a separate thread
-    let server_thread = thread::spawn( || {
+    let server_config = config.server.clone();
+    let fee_config = config.fees.clone();
+    let server_thread = thread::spawn(move || {
             // Create a separate Tokio runtime for Actix Web
         let rt2 = Builder::new_multi_thread()
-            .worker_threads(4)
+            .worker_threads(server_config.worker_threads)
             .enable_io()
             .enable_time()
             .build()
@@ -93,47 +278,35 @@ fn main() { // async
             App::new()
                 .app_data(web::Data::new(sequencer_sender.clone()))
                 .app_data(web::Data::new(rollupdb_sender.clone()))
-                .app_data(web::Data::new(frontend_sender.clone()))
-                .app_data(web::Data::new(frontend_receiver.clone()))
-                .route("/", web::get().to(frontend::test))
-                .route(
-                    "/get_transaction",
-                    web::post().to(frontend::get_transaction),
-                )
+                .app_data(web::Data::new(rollup_state.clone()))
+                .app_data(web::Data::new(delegation_service.clone()))
+                .app_data(web::Data::new(fee_config.clone()))
+                .app_data(stream_journal.clone())
+                .configure(read_routes)
                 .route(
                     "/submit_transaction",
                     web::post().to(frontend::submit_transaction),
                 )
                 .route(
-                    "/init_delegation_service",
-                    {
-                        let delegation_service = delegation_service.clone();
-                        web::post().to(move |body: web::Bytes| {
-                            let keypair = Keypair::from_bytes(&body).unwrap();
-                            *delegation_service.write().unwrap() = 
-                                DelegationService::new("https://api.devnet.solana.com", keypair);
-                            log::info!("Delegation service initialized___");
-                            // log::info!("{:?}", )
-                            HttpResponse::Ok()
-                        })
-                    },
+                    "/prepare_force_exit",
+                    web::post().to(frontend::prepare_force_exit),
+                )
+                .route(
+                    "/get_allowance",
+                    web::post().to(frontend::get_allowance),
                 )
                 .route(
-                    "/add_delegation_signer",
-                    {
-                        let delegation_service = delegation_service.clone();
-                        web::post().to(move |body: web::Bytes| {
-                            let keypair = Keypair::from_bytes(&body).unwrap();
-                            delegation_service.write().unwrap().add_signer(keypair);
-                            log::info!("Added signer to delegation service");
-                            HttpResponse::Ok()
-                        })
-                    },
+                    "/prepare_delegation",
+                    web::post().to(frontend::prepare_delegation),
+                )
+                .route(
+                    "/submit_delegation",
+                    web::post().to(frontend::submit_delegation),
                 )
         
         })
-        .worker_max_blocking_threads(2)
-        .bind("127.0.0.1:8080")
+        .worker_max_blocking_threads(server_config.max_blocking_threads)
+        .bind(server_config.bind_address.as_str())
         .unwrap()
         .run()
         .await
@@ -141,5 +314,75 @@ fn main() { // async
         });
         });
         server_thread.join().unwrap();
+        settlement_thread.join().unwrap();
+        Ok(())
+
+}
 
+/// Routes that only read the rollup's state, a follower serves these.
+fn read_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/", web::get().to(frontend::test))
+        .route(
+            "/get_transaction",
+            web::post().to(frontend::get_transaction),
+        )
+        .route(
+            "/get_batch",
+            web::post().to(frontend::get_batch),
+        )
+        .route(
+            "/get_block",
+            web::post().to(frontend::get_block),
+        )
+        .route(
+            "/stream_blocks",
+            web::post().to(frontend::stream_blocks),
+        )
+        .route(
+            "/get_withdrawal",
+            web::post().to(frontend::get_withdrawal),
+        )
+        .route(
+            "/get_exit_proof",
+            web::post().to(frontend::get_exit_proof),
+        )
+        .route("/ws", web::get().to(pubsub::subscribe))
+        .route("/metrics", web::get().to(metrics::serve_metrics))
+        .route("/health", web::get().to(metrics::health))
+        .route("/ready", web::get().to(metrics::ready));
+}
+
+/// `--mode follower`: no sequencer, RollupDB or settlement worker, the
+/// follower writes the state the read-only RPC serves.
+fn run_follower(config: NodeConfig, genesis: Genesis, l1_client: Arc<dyn L1Client>) -> anyhow::Result<()> {
+    let rollup_state = Arc::new(RollupState::default());
+    let follower = Follower::new(
+        config.follower_config(),
+        &genesis,
+        config.fees.lamports_per_signature,
+        rollup_state.clone(),
+        BlockJournal::new(config.storage_file("blocks.jsonl")),
+    )?;
+    thread::spawn(move || follower.run(l1_client));
+
+    let stream_journal = web::Data::new(BlockJournal::new(config.storage_file("blocks.jsonl")));
+    let server_config = config.server.clone();
+    let rt = Builder::new_multi_thread()
+        .worker_threads(server_config.worker_threads)
+        .enable_io()
+        .enable_time()
+        .build()?;
+    rt.block_on(async {
+        HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(rollup_state.clone()))
+                .app_data(stream_journal.clone())
+                .configure(read_routes)
+        })
+        .worker_max_blocking_threads(server_config.max_blocking_threads)
+        .bind(server_config.bind_address.as_str())?
+        .run()
+        .await
+    })?;
+    Ok(())
 }
@@ -0,0 +1,169 @@
+//! Binary merkle tree over the rollup accounts. Its root is the state root
+//! recorded for every batch, proofs against it back forced exits on L1.
+
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    account::{AccountSharedData, ReadableAccount},
+    hash::{hashv, Hash},
+    pubkey::Pubkey,
+};
+
+// Domain separation, so a leaf can never be passed off as an inner node
+const LEAF_PREFIX: &[u8] = &[0];
+const NODE_PREFIX: &[u8] = &[1];
+
+/// Hash of a single account, as it is committed to in the state root.
+pub fn account_leaf(pubkey: &Pubkey, account: &AccountSharedData) -> Hash {
+    hashv(&[
+        LEAF_PREFIX,
+        pubkey.as_ref(),
+        &account.lamports().to_le_bytes(),
+        account.owner().as_ref(),
+        &[account.executable() as u8],
+        &account.rent_epoch().to_le_bytes(),
+        account.data(),
+    ])
+}
+
+pub fn hash_nodes(left: &Hash, right: &Hash) -> Hash {
+    hashv(&[NODE_PREFIX, left.as_ref(), right.as_ref()])
+}
+
+/// Path from a leaf to the root. Levels where the node was promoted without
+/// a sibling have no entry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    /// Sibling hashes from the leaf up, and whether each one is the left node.
+    pub siblings: Vec<(Hash, bool)>,
+}
+
+impl MerkleProof {
+    pub fn root(&self, leaf: Hash) -> Hash {
+        self.siblings.iter().fold(leaf, |node, (sibling, is_left)| {
+            if *is_left {
+                hash_nodes(sibling, &node)
+            } else {
+                hash_nodes(&node, sibling)
+            }
+        })
+    }
+
+    pub fn verify(&self, leaf: Hash, root: &Hash) -> bool {
+        self.root(leaf) == *root
+    }
+}
+
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    /// Builds the tree bottom up. An odd node at the end of a level is
+    /// promoted to the next level unchanged.
+    pub fn new(leaves: Vec<Hash>) -> Self {
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash_nodes(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    /// The root of an empty tree is the default hash.
+    pub fn root(&self) -> Hash {
+        self.levels
+            .last()
+            .and_then(|level| level.first())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// `None` if there is no leaf at `index`.
+    pub fn proof(&self, mut index: usize) -> Option<MerkleProof> {
+        if index >= self.levels[0].len() {
+            return None;
+        }
+        let mut siblings = Vec::new();
+        for level in self.levels.iter().take(self.levels.len() - 1) {
+            let sibling = index ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                siblings.push((*hash, sibling < index));
+            }
+            index /= 2;
+        }
+        Some(MerkleProof { siblings })
+    }
+}
+
+/// State root over a set of accounts, leaves are ordered by pubkey.
+pub fn state_root<'a>(accounts: impl IntoIterator<Item = (&'a Pubkey, &'a AccountSharedData)>) -> Hash {
+    let mut accounts: Vec<_> = accounts.into_iter().collect();
+    accounts.sort_by_key(|(pubkey, _)| **pubkey);
+    let leaves = accounts
+        .into_iter()
+        .map(|(pubkey, account)| account_leaf(pubkey, account))
+        .collect();
+    MerkleTree::new(leaves).root()
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{account::WritableAccount, hash::hash};
+
+    use super::*;
+
+    fn leaves(count: u8) -> Vec<Hash> {
+        (0..count).map(|byte| hash(&[byte])).collect()
+    }
+
+    #[test]
+    fn every_leaf_proves_against_the_root() {
+        assert_eq!(MerkleTree::new(Vec::new()).root(), Hash::default());
+        // Odd counts promote the last node at some level
+        for count in 1..=9 {
+            let leaves = leaves(count);
+            let tree = MerkleTree::new(leaves.clone());
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(index).unwrap();
+                assert!(proof.verify(*leaf, &tree.root()), "leaf {} of {}", index, count);
+                assert!(!proof.verify(hash(b"other"), &tree.root()));
+            }
+            assert!(tree.proof(leaves.len()).is_none());
+        }
+    }
+
+    #[test]
+    fn proofs_skip_promoted_levels() {
+        let leaves = leaves(3);
+        let tree = MerkleTree::new(leaves.clone());
+        let left = hash_nodes(&leaves[0], &leaves[1]);
+        assert_eq!(tree.root(), hash_nodes(&left, &leaves[2]));
+        assert_eq!(tree.proof(2).unwrap().siblings, vec![(left, true)]);
+        assert_eq!(tree.proof(1).unwrap().siblings, vec![(leaves[0], true), (leaves[2], false)]);
+        // A single leaf is its own root
+        assert_eq!(MerkleTree::new(vec![leaves[0]]).root(), leaves[0]);
+    }
+
+    #[test]
+    fn state_root_is_independent_of_account_order() {
+        let accounts: Vec<(Pubkey, AccountSharedData)> = (1..=5)
+            .map(|lamports| (Pubkey::new_unique(), AccountSharedData::new(lamports, 0, &Pubkey::default())))
+            .collect();
+        let root = state_root(accounts.iter().map(|(pubkey, account)| (pubkey, account)));
+        assert_eq!(root, state_root(accounts.iter().rev().map(|(pubkey, account)| (pubkey, account))));
+
+        // Every committed field changes the root
+        let mut changed = accounts.clone();
+        changed[2].1.set_rent_epoch(1);
+        assert_ne!(root, state_root(changed.iter().map(|(pubkey, account)| (pubkey, account))));
+    }
+}
@@ -0,0 +1,281 @@
+//! Prometheus metrics on `/metrics`, and whether the node's workers are alive
+//! on `/health` and `/ready`.
+//!
+//! The metrics live in a process-wide registry, every part of the node
+//! records into it directly. Workers beat a heartbeat from their loops and
+//! hold a `Running` guard, so a worker that returned or panicked is down at
+//! once and one that hangs is down once its heartbeat is stale.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::HttpResponse;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use serde::Serialize;
+
+/// A worker that beat longer ago than this is hung. RPC timeouts of the
+/// settlement worker stay well below it.
+const STALE_AFTER: Duration = Duration::from_secs(60);
+
+/// How often a worker that waits for work beats anyway.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+pub struct Metrics {
+    registry: Registry,
+    /// Transactions submitted and not picked up by the sequencer yet.
+    pub mempool_depth: IntGauge,
+    /// By `result`: executed, failed (an instruction failed, fees were
+    /// charged) or rejected (never executed).
+    pub transactions: IntCounterVec,
+    pub execution_seconds: Histogram,
+    pub compute_units: Histogram,
+    /// Until RollupDB handed over the transaction's accounts.
+    pub lock_wait_seconds: Histogram,
+    pub block_slot: IntGauge,
+    pub batch_transactions: Histogram,
+    /// From queued for settlement until confirmed on L1.
+    pub settlement_seconds: Histogram,
+    /// By `result`: confirmed or failed.
+    pub settlements: IntCounterVec,
+    /// By the L1 RPC `method` that failed.
+    pub l1_rpc_errors: IntCounterVec,
+    workers: BTreeMap<Worker, WorkerHealth>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Self {
+            mempool_depth: IntGauge::new("rollup_mempool_depth", "Transactions waiting for the sequencer").unwrap(),
+            transactions: IntCounterVec::new(
+                Opts::new("rollup_transactions_total", "Transactions the sequencer took from the mempool or inbox"),
+                &["result"],
+            )
+            .unwrap(),
+            execution_seconds: histogram(
+                "rollup_execution_seconds",
+                "Time to execute a transaction in the SVM",
+                prometheus::exponential_buckets(0.000_1, 2.0, 16).unwrap(),
+            ),
+            compute_units: histogram(
+                "rollup_compute_units",
+                "Compute units consumed per transaction",
+                prometheus::exponential_buckets(500.0, 2.0, 12).unwrap(),
+            ),
+            lock_wait_seconds: histogram(
+                "rollup_lock_wait_seconds",
+                "Time a transaction waited for its accounts to be locked",
+                prometheus::exponential_buckets(0.000_1, 4.0, 10).unwrap(),
+            ),
+            block_slot: IntGauge::new("rollup_block_slot", "Slot of the last sealed block").unwrap(),
+            batch_transactions: histogram(
+                "rollup_batch_transactions",
+                "Transactions per bundled batch",
+                prometheus::exponential_buckets(1.0, 2.0, 12).unwrap(),
+            ),
+            settlement_seconds: histogram(
+                "rollup_settlement_seconds",
+                "Time from queueing a batch for settlement until it is confirmed on L1",
+                prometheus::exponential_buckets(0.5, 2.0, 12).unwrap(),
+            ),
+            settlements: IntCounterVec::new(
+                Opts::new("rollup_settlements_total", "Batches whose settlement finished"),
+                &["result"],
+            )
+            .unwrap(),
+            l1_rpc_errors: IntCounterVec::new(
+                Opts::new("rollup_l1_rpc_errors_total", "Failed L1 RPC calls"),
+                &["method"],
+            )
+            .unwrap(),
+            workers: Worker::ALL.iter().map(|worker| (*worker, WorkerHealth::default())).collect(),
+            registry,
+        };
+        let collectors: [Box<dyn prometheus::core::Collector>; 10] = [
+            Box::new(metrics.mempool_depth.clone()),
+            Box::new(metrics.transactions.clone()),
+            Box::new(metrics.execution_seconds.clone()),
+            Box::new(metrics.compute_units.clone()),
+            Box::new(metrics.lock_wait_seconds.clone()),
+            Box::new(metrics.block_slot.clone()),
+            Box::new(metrics.batch_transactions.clone()),
+            Box::new(metrics.settlement_seconds.clone()),
+            Box::new(metrics.settlements.clone()),
+            Box::new(metrics.l1_rpc_errors.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    pub fn transaction(&self, result: &str) -> IntCounter {
+        self.transactions.with_label_values(&[result])
+    }
+
+    pub fn l1_rpc_error(&self, method: &str) {
+        self.l1_rpc_errors.with_label_values(&[method]).inc();
+    }
+
+    /// Marks `worker` as one this node runs, its health counts from now on.
+    pub fn expect(&self, worker: Worker) {
+        self.workers[&worker].expected.store(true, Ordering::Relaxed);
+    }
+
+    /// Called from the worker's loop.
+    pub fn beat(&self, worker: Worker) {
+        self.workers[&worker].beat();
+    }
+
+    /// Beats once and keeps the worker up until the guard is dropped.
+    pub fn running(&self, worker: Worker) -> Running {
+        self.workers[&worker].stopped.store(false, Ordering::Relaxed);
+        self.beat(worker);
+        Running(worker)
+    }
+
+    fn report(&self) -> HealthReport {
+        let workers: BTreeMap<&'static str, WorkerStatus> = self
+            .workers
+            .iter()
+            .filter(|(_, health)| health.expected.load(Ordering::Relaxed))
+            .map(|(worker, health)| (worker.name(), health.status()))
+            .collect();
+        HealthReport {
+            healthy: workers.values().all(|status| *status != WorkerStatus::Down),
+            ready: workers.values().all(|status| *status == WorkerStatus::Up),
+            workers,
+        }
+    }
+}
+
+fn histogram(name: &str, help: &str, buckets: Vec<f64>) -> Histogram {
+    Histogram::with_opts(HistogramOpts::new(name, help).buckets(buckets)).unwrap()
+}
+
+/// The long-running parts of a node whose health `/health` and `/ready`
+/// report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Worker {
+    Sequencer,
+    RollupDb,
+    Settlement,
+}
+
+impl Worker {
+    const ALL: [Worker; 3] = [Worker::Sequencer, Worker::RollupDb, Worker::Settlement];
+
+    fn name(self) -> &'static str {
+        match self {
+            Worker::Sequencer => "sequencer",
+            Worker::RollupDb => "rollupdb",
+            Worker::Settlement => "settlement",
+        }
+    }
+}
+
+/// Marks its worker as stopped when dropped, on return or on panic.
+pub struct Running(Worker);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        log::warn!("{} stopped", self.0.name());
+        metrics().workers[&self.0].stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct WorkerHealth {
+    expected: AtomicBool,
+    /// Milliseconds since `started()` of the last heartbeat, 0 before the
+    /// first one.
+    last_beat: AtomicU64,
+    stopped: AtomicBool,
+}
+
+impl WorkerHealth {
+    fn beat(&self) {
+        let now = started().elapsed().as_millis() as u64;
+        self.last_beat.store(now.max(1), Ordering::Relaxed);
+    }
+
+    fn status(&self) -> WorkerStatus {
+        let last_beat = self.last_beat.load(Ordering::Relaxed);
+        if self.stopped.load(Ordering::Relaxed) {
+            WorkerStatus::Down
+        } else if last_beat == 0 {
+            WorkerStatus::Starting
+        } else if started().elapsed().saturating_sub(Duration::from_millis(last_beat)) > STALE_AFTER {
+            WorkerStatus::Down
+        } else {
+            WorkerStatus::Up
+        }
+    }
+}
+
+fn started() -> Instant {
+    static STARTED: OnceLock<Instant> = OnceLock::new();
+    *STARTED.get_or_init(Instant::now)
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum WorkerStatus {
+    /// Restoring its state, before the first heartbeat.
+    Starting,
+    Up,
+    /// Stopped, or hung.
+    Down,
+}
+
+#[derive(Serialize, Debug)]
+struct HealthReport {
+    healthy: bool,
+    ready: bool,
+    workers: BTreeMap<&'static str, WorkerStatus>,
+}
+
+pub async fn serve_metrics() -> HttpResponse {
+    let mut body = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&metrics().registry.gather(), &mut body) {
+        log::error!("Failed to encode metrics: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok().content_type(encoder.format_type()).body(body)
+}
+
+/// 503 once a worker stopped or hung, a node that is still starting is
+/// healthy.
+pub async fn health() -> HttpResponse {
+    let report = metrics().report();
+    if report.healthy {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+/// 503 until every worker restored its state and is running.
+pub async fn ready() -> HttpResponse {
+    let report = metrics().report();
+    if report.ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
@@ -0,0 +1,1037 @@
+//! An in-process stand-in for the base chain, so the whole rollup flow can run
+//! without a cluster. Transactions are signature and blockhash checked, then
+//! executed by small native handlers for the programs the rollup talks to:
+//! the System, Memo, delegation and BPF loader buffer programs. More programs can be
+//! plugged in with `MockL1::with_program`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, RwLock},
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use borsh::BorshSerialize;
+use crossbeam::channel::{Receiver as CBReceiver, Sender as CBSender};
+use solana_sdk::{
+    account::{Account, AccountSharedData, WritableAccount},
+    clock::{Clock, Slot},
+    commitment_config::CommitmentLevel,
+    hash::{hashv, Hash},
+    instruction::InstructionError,
+    loader_upgradeable_instruction::UpgradeableLoaderInstruction,
+    pubkey::Pubkey,
+    rent::Rent,
+    signature::Signature,
+    sysvar,
+    transaction::{Transaction, TransactionError},
+};
+use solana_loader_v3_interface::state::UpgradeableLoaderState;
+use solana_sdk_ids::{bpf_loader_upgradeable, system_program};
+use solana_system_interface::instruction::SystemInstruction;
+
+use crate::{
+    delegation::{
+        find_delegation_pda, find_exit_record_pda, find_inbox_pda, find_rollup_state_pda, get_delegation_program_id,
+        ChallengeStateRootArgs, DelegatedAccount, DelegationInstruction, DepositedEvent, EnqueueTransactionArgs,
+        ExitRecord, ForceExitArgs, ForcedExitEvent, Inbox, InboxEntry, InitializeDelegateArgs, PostStateRootArgs,
+        ProgramAccount, ProgramEvent, RollupState, SettleWithdrawalArgs, StateRootPostedEvent, TopUpArgs,
+        TransactionEnqueuedEvent, WithdrawArgs, WithdrawnEvent, FORCED_INCLUSION_BATCHES, FORCE_EXIT_TIMEOUT_SLOTS,
+    },
+    l1_client::{L1Client, L1SignatureStatus, L1Transaction, L1TransactionInfo},
+    merkle::{account_leaf, MerkleProof},
+    settle::MEMO_PROGRAM_ID,
+};
+
+// Same as a real cluster, a blockhash is valid for 150 blocks
+const MAX_RECENT_BLOCKHASHES: usize = 150;
+
+/// Native implementation of an on-chain program for the mock chain. Log
+/// messages it pushes to `logs` are served by `get_transaction`.
+pub trait MockProgram: Send + Sync {
+    fn process(
+        &self,
+        accounts: &mut HashMap<Pubkey, Account>,
+        instruction_accounts: &[Pubkey],
+        data: &[u8],
+        logs: &mut Vec<String>,
+    ) -> Result<()>;
+}
+
+#[derive(Default)]
+struct ChainState {
+    slot: Slot,
+    accounts: HashMap<Pubkey, Account>,
+    recent_blockhashes: VecDeque<Hash>,
+    signatures: HashMap<Signature, L1SignatureStatus>,
+    /// Every executed transaction and its logs, oldest first
+    history: Vec<(Slot, Transaction, Vec<String>)>,
+}
+
+impl ChainState {
+    fn advance_slot(&mut self) {
+        self.slot += 1;
+        let clock = Clock {
+            slot: self.slot,
+            ..Clock::default()
+        };
+        self.accounts
+            .insert(sysvar::clock::id(), Account::new_data(1, &clock, &sysvar::id()).unwrap());
+        let blockhash = hashv(&[b"mock_l1", &self.slot.to_le_bytes()]);
+        self.recent_blockhashes.push_back(blockhash);
+        if self.recent_blockhashes.len() > MAX_RECENT_BLOCKHASHES {
+            self.recent_blockhashes.pop_front();
+        }
+    }
+}
+
+pub struct MockL1 {
+    state: RwLock<ChainState>,
+    programs: HashMap<Pubkey, Box<dyn MockProgram>>,
+    /// Program id -> subscriber, from `subscribe_program_accounts`
+    subscribers: Mutex<Vec<(Pubkey, AccountSender)>>,
+}
+
+type AccountSender = CBSender<(Pubkey, Account)>;
+
+impl MockL1 {
+    /// A chain with the System, Memo, delegation and BPF loader buffer programs loaded.
+    pub fn new() -> Self {
+        let mut state = ChainState::default();
+        state.advance_slot();
+        Self {
+            state: RwLock::new(state),
+            programs: HashMap::new(),
+            subscribers: Mutex::new(Vec::new()),
+        }
+        .with_program(system_program::id(), Box::new(MockSystemProgram))
+        .with_program(MEMO_PROGRAM_ID, Box::new(MockMemoProgram))
+        .with_program(bpf_loader_upgradeable::id(), Box::new(MockBufferProgram))
+        .with_program(get_delegation_program_id(), Box::new(MockDelegationProgram))
+    }
+
+    pub fn with_program(mut self, program_id: Pubkey, program: Box<dyn MockProgram>) -> Self {
+        self.programs.insert(program_id, program);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn airdrop(&self, pubkey: &Pubkey, lamports: u64) {
+        let mut state = self.state.write().unwrap();
+        let account = state
+            .accounts
+            .entry(*pubkey)
+            .or_insert_with(|| Account::new(0, 0, &system_program::id()));
+        account.lamports += lamports;
+    }
+
+    pub fn slot(&self) -> Slot {
+        self.state.read().unwrap().slot
+    }
+
+    /// Sends every account of a transaction that is (or was, if it got
+    /// closed) owned by a subscribed program to its subscribers.
+    fn notify_subscribers(&self, before: &HashMap<Pubkey, Account>, after: &HashMap<Pubkey, Account>, keys: &[Pubkey]) {
+        self.subscribers.lock().unwrap().retain(|(program_id, sender)| {
+            let owned = |accounts: &HashMap<Pubkey, Account>, key: &Pubkey| {
+                accounts.get(key).is_some_and(|account| account.owner == *program_id)
+            };
+            keys.iter()
+                .filter(|key| owned(before, key) || owned(after, key))
+                .all(|key| sender.send((*key, after.get(key).cloned().unwrap_or_default())).is_ok())
+        });
+    }
+
+    /// Executes every instruction against a copy of the accounts, the copy
+    /// only replaces the chain state if all of them succeed.
+    fn execute(
+        &self,
+        accounts: &HashMap<Pubkey, Account>,
+        tx: &Transaction,
+        logs: &mut Vec<String>,
+    ) -> std::result::Result<HashMap<Pubkey, Account>, TransactionError> {
+        let mut working = accounts.clone();
+        let account_keys = &tx.message.account_keys;
+        for (index, ix) in tx.message.instructions.iter().enumerate() {
+            let program_id = account_keys[ix.program_id_index as usize];
+            let program = self
+                .programs
+                .get(&program_id)
+                .ok_or(TransactionError::InvalidProgramForExecution)?;
+            let instruction_accounts: Vec<Pubkey> = ix
+                .accounts
+                .iter()
+                .map(|account_index| account_keys[*account_index as usize])
+                .collect();
+            program
+                .process(&mut working, &instruction_accounts, &ix.data, logs)
+                .map_err(|e| {
+                    log::info!("Mock L1 instruction {} failed: {}", index, e);
+                    TransactionError::InstructionError(index as u8, InstructionError::Custom(0))
+                })?;
+        }
+        Ok(working)
+    }
+}
+
+impl L1Client for MockL1 {
+    fn get_account(&self, pubkey: &Pubkey) -> Result<Option<Account>> {
+        Ok(self.state.read().unwrap().accounts.get(pubkey).cloned())
+    }
+
+    fn get_account_with_slot(&self, pubkey: &Pubkey) -> Result<(Slot, Option<Account>)> {
+        let state = self.state.read().unwrap();
+        Ok((state.slot, state.accounts.get(pubkey).cloned()))
+    }
+
+    fn get_latest_blockhash(&self) -> Result<(Hash, u64)> {
+        let state = self.state.read().unwrap();
+        let blockhash = *state.recent_blockhashes.back().unwrap();
+        Ok((blockhash, state.slot + MAX_RECENT_BLOCKHASHES as u64))
+    }
+
+    fn get_block_height(&self) -> Result<u64> {
+        Ok(self.slot())
+    }
+
+    fn get_minimum_balance_for_rent_exemption(&self, data_len: usize) -> Result<u64> {
+        Ok(Rent::default().minimum_balance(data_len))
+    }
+
+    fn send_transaction(&self, tx: &Transaction) -> Result<Signature> {
+        tx.verify()?;
+        let signature = tx.signatures[0];
+
+        let mut state = self.state.write().unwrap();
+        if !state.recent_blockhashes.contains(&tx.message.recent_blockhash) {
+            return Err(anyhow!(TransactionError::BlockhashNotFound));
+        }
+        if state.signatures.contains_key(&signature) {
+            return Err(anyhow!(TransactionError::AlreadyProcessed));
+        }
+
+        let mut logs = Vec::new();
+        let err = match self.execute(&state.accounts, tx, &mut logs) {
+            Ok(accounts) => {
+                self.notify_subscribers(&state.accounts, &accounts, &tx.message.account_keys);
+                state.accounts = accounts;
+                None
+            }
+            Err(err) => Some(err),
+        };
+        let slot = state.slot;
+        state.history.push((slot, tx.clone(), logs));
+        state.signatures.insert(
+            signature,
+            L1SignatureStatus {
+                slot,
+                err,
+                // There are no forks, so everything is final right away
+                commitment: CommitmentLevel::Finalized,
+            },
+        );
+        state.advance_slot();
+        Ok(signature)
+    }
+
+    fn send_and_confirm_transaction(&self, tx: &Transaction) -> Result<Signature> {
+        let signature = self.send_transaction(tx)?;
+        match self.get_signature_status(&signature)?.and_then(|status| status.err) {
+            Some(err) => Err(anyhow!(err)),
+            None => Ok(signature),
+        }
+    }
+
+    fn get_signature_status(&self, signature: &Signature) -> Result<Option<L1SignatureStatus>> {
+        Ok(self.state.read().unwrap().signatures.get(signature).cloned())
+    }
+
+    fn get_slot(&self) -> Result<Slot> {
+        Ok(self.slot())
+    }
+
+    fn get_signatures_for_address(
+        &self,
+        address: &Pubkey,
+        before: Option<Signature>,
+        until: Option<Signature>,
+        limit: usize,
+    ) -> Result<Vec<L1TransactionInfo>> {
+        let state = self.state.read().unwrap();
+        let mut infos = Vec::new();
+        let mut started = before.is_none();
+        for (slot, tx, _) in state.history.iter().rev() {
+            let signature = tx.signatures[0];
+            if !started {
+                started = Some(signature) == before;
+                continue;
+            }
+            if Some(signature) == until || infos.len() >= limit {
+                break;
+            }
+            if tx.message.account_keys.contains(address) {
+                infos.push(L1TransactionInfo {
+                    signature,
+                    slot: *slot,
+                    err: state.signatures.get(&signature).and_then(|status| status.err.clone()),
+                });
+            }
+        }
+        Ok(infos)
+    }
+
+    fn get_transaction(&self, signature: &Signature) -> Result<Option<L1Transaction>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .history
+            .iter()
+            .find(|(_, tx, _)| tx.signatures[0] == *signature)
+            .map(|(slot, tx, logs)| L1Transaction {
+                slot: *slot,
+                transaction: tx.clone().into(),
+                logs: logs.clone(),
+            }))
+    }
+
+    fn subscribe_program_accounts(&self, program_id: &Pubkey) -> Result<CBReceiver<(Pubkey, Account)>> {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        self.subscribers.lock().unwrap().push((*program_id, sender));
+        Ok(receiver)
+    }
+}
+
+fn account_mut<'a>(accounts: &'a mut HashMap<Pubkey, Account>, pubkey: &Pubkey) -> &'a mut Account {
+    accounts
+        .entry(*pubkey)
+        .or_insert_with(|| Account::new(0, 0, &system_program::id()))
+}
+
+fn move_lamports(accounts: &mut HashMap<Pubkey, Account>, from: &Pubkey, to: &Pubkey, lamports: u64) -> Result<()> {
+    let from_account = account_mut(accounts, from);
+    from_account.lamports = from_account
+        .lamports
+        .checked_sub(lamports)
+        .ok_or_else(|| anyhow!("insufficient lamports in {}", from))?;
+    account_mut(accounts, to).lamports += lamports;
+    Ok(())
+}
+
+/// Plain transfers, and account creation for DA buffers.
+struct MockSystemProgram;
+
+impl MockProgram for MockSystemProgram {
+    fn process(&self, accounts: &mut HashMap<Pubkey, Account>, instruction_accounts: &[Pubkey], data: &[u8], _logs: &mut Vec<String>) -> Result<()> {
+        match bincode::deserialize::<SystemInstruction>(data)? {
+            SystemInstruction::Transfer { lamports } => {
+                let [from, to, ..] = instruction_accounts else {
+                    return Err(anyhow!("transfer needs 2 accounts"));
+                };
+                move_lamports(accounts, from, to, lamports)
+            }
+            SystemInstruction::CreateAccount { lamports, space, owner } => {
+                let [from, to, ..] = instruction_accounts else {
+                    return Err(anyhow!("create_account needs 2 accounts"));
+                };
+                if accounts.get(to).is_some_and(|account| account.lamports > 0 || !account.data.is_empty()) {
+                    return Err(anyhow!("account {} already exists", to));
+                }
+                move_lamports(accounts, from, to, lamports)?;
+                let account = account_mut(accounts, to);
+                account.data = vec![0; space as usize];
+                account.owner = owner;
+                Ok(())
+            }
+            other => Err(anyhow!("unsupported system instruction {:?}", other)),
+        }
+    }
+}
+
+/// Buffers of the upgradeable BPF loader, which DA batches can be written to.
+/// Only creating and writing them is supported.
+struct MockBufferProgram;
+
+impl MockProgram for MockBufferProgram {
+    fn process(&self, accounts: &mut HashMap<Pubkey, Account>, instruction_accounts: &[Pubkey], data: &[u8], _logs: &mut Vec<String>) -> Result<()> {
+        let metadata_len = UpgradeableLoaderState::size_of_buffer_metadata();
+        match bincode::deserialize::<UpgradeableLoaderInstruction>(data)? {
+            UpgradeableLoaderInstruction::InitializeBuffer => {
+                let [buffer, authority, ..] = instruction_accounts else {
+                    return Err(anyhow!("initialize_buffer needs 2 accounts"));
+                };
+                let state = bincode::serialize(&UpgradeableLoaderState::Buffer {
+                    authority_address: Some(*authority),
+                })?;
+                let account = account_mut(accounts, buffer);
+                if account.owner != bpf_loader_upgradeable::id() || account.data.len() < metadata_len {
+                    return Err(anyhow!("{} is not an uninitialized buffer", buffer));
+                }
+                account.data[..state.len()].copy_from_slice(&state);
+                Ok(())
+            }
+            UpgradeableLoaderInstruction::Write { offset, bytes } => {
+                let [buffer, authority, ..] = instruction_accounts else {
+                    return Err(anyhow!("write needs 2 accounts"));
+                };
+                let account = account_mut(accounts, buffer);
+                match bincode::deserialize::<UpgradeableLoaderState>(&account.data)? {
+                    UpgradeableLoaderState::Buffer { authority_address } if authority_address == Some(*authority) => {}
+                    _ => return Err(anyhow!("{} is not a buffer of {}", buffer, authority)),
+                }
+                let start = metadata_len + offset as usize;
+                let target = account
+                    .data
+                    .get_mut(start..start + bytes.len())
+                    .ok_or_else(|| anyhow!("write past the end of {}", buffer))?;
+                target.copy_from_slice(&bytes);
+                Ok(())
+            }
+            other => Err(anyhow!("unsupported loader instruction {:?}", other)),
+        }
+    }
+}
+
+/// Settlement commitments are memos, they only have to land.
+struct MockMemoProgram;
+
+impl MockProgram for MockMemoProgram {
+    fn process(&self, _accounts: &mut HashMap<Pubkey, Account>, _instruction_accounts: &[Pubkey], data: &[u8], _logs: &mut Vec<String>) -> Result<()> {
+        std::str::from_utf8(data)?;
+        Ok(())
+    }
+}
+
+/// Mirrors the on-chain delegation program: `initialize_delegate` and
+/// `top_up` move lamports into the owner's PDA, `withdraw` and the
+/// operator's `settle_withdrawal` move them back. `post_state_root` and
+/// `force_exit` implement the escape hatch, `enqueue_transaction` the forced
+/// inclusion inbox and `challenge_state_root` checks the shape of a fraud
+/// challenge.
+struct MockDelegationProgram;
+
+impl MockProgram for MockDelegationProgram {
+    fn process(&self, accounts: &mut HashMap<Pubkey, Account>, instruction_accounts: &[Pubkey], data: &[u8], logs: &mut Vec<String>) -> Result<()> {
+        let (amount, deposit) = match DelegationInstruction::decode(data)? {
+            DelegationInstruction::PostStateRoot(args) => return Self::post_state_root(accounts, instruction_accounts, args, logs),
+            DelegationInstruction::ForceExit(args) => return Self::force_exit(accounts, instruction_accounts, args, logs),
+            DelegationInstruction::EnqueueTransaction(args) => {
+                return Self::enqueue_transaction(accounts, instruction_accounts, args, logs)
+            }
+            DelegationInstruction::ChallengeStateRoot(args) => {
+                return Self::challenge_state_root(accounts, instruction_accounts, args)
+            }
+            DelegationInstruction::InitializeDelegate(InitializeDelegateArgs { amount })
+            | DelegationInstruction::TopUp(TopUpArgs { amount }) => (amount, true),
+            DelegationInstruction::Withdraw(WithdrawArgs { amount })
+            | DelegationInstruction::SettleWithdrawal(SettleWithdrawalArgs { amount }) => (amount, false),
+        };
+
+        let [owner, pda, ..] = instruction_accounts else {
+            return Err(anyhow!("delegation instructions need an owner and a pda"));
+        };
+        let mut delegation = Self::delegation(accounts, owner, pda)?;
+        if deposit {
+            move_lamports(accounts, owner, pda, amount)?;
+            delegation.delegated_amount += amount;
+            emit(logs, &DepositedEvent {
+                owner: *owner,
+                amount,
+                delegated_amount: delegation.delegated_amount,
+            });
+        } else {
+            delegation.delegated_amount = delegation
+                .delegated_amount
+                .checked_sub(amount)
+                .ok_or_else(|| anyhow!("withdrawing more than delegated"))?;
+            move_lamports(accounts, pda, owner, amount)?;
+            emit(logs, &WithdrawnEvent {
+                owner: *owner,
+                amount,
+                delegated_amount: delegation.delegated_amount,
+            });
+        }
+
+        write_program_account(accounts, pda, &delegation)
+    }
+}
+
+impl MockDelegationProgram {
+    fn delegation(accounts: &HashMap<Pubkey, Account>, owner: &Pubkey, pda: &Pubkey) -> Result<DelegatedAccount> {
+        let (expected_pda, bump) = find_delegation_pda(owner);
+        if *pda != expected_pda {
+            return Err(anyhow!("{} is not the delegation pda of {}", pda, owner));
+        }
+        Ok(match accounts.get(pda).filter(|account| !account.data.is_empty()) {
+            Some(account) => DelegatedAccount::try_from_account_data(&account.data)?,
+            None => DelegatedAccount {
+                owner: *owner,
+                delegated_amount: 0,
+                last_deposit_time: 0,
+                bump,
+            },
+        })
+    }
+
+    fn rollup_state(accounts: &HashMap<Pubkey, Account>) -> Result<Option<RollupState>> {
+        let (rollup_state, _) = find_rollup_state_pda();
+        match accounts.get(&rollup_state).filter(|account| !account.data.is_empty()) {
+            Some(account) => Ok(Some(RollupState::try_from_account_data(&account.data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn inbox(accounts: &HashMap<Pubkey, Account>) -> Result<Inbox> {
+        let (inbox, _) = find_inbox_pda();
+        match accounts.get(&inbox).filter(|account| !account.data.is_empty()) {
+            Some(account) => Ok(Inbox::try_from_account_data(&account.data)?),
+            None => Ok(Inbox::default()),
+        }
+    }
+
+    /// The first operator to post a root becomes the rollup's operator. A root
+    /// is refused while it leaves out an inbox entry that is overdue.
+    fn post_state_root(
+        accounts: &mut HashMap<Pubkey, Account>,
+        instruction_accounts: &[Pubkey],
+        args: PostStateRootArgs,
+        logs: &mut Vec<String>,
+    ) -> Result<()> {
+        let [operator, rollup_state, inbox, ..] = instruction_accounts else {
+            return Err(anyhow!("post_state_root needs an operator, the rollup state and the inbox"));
+        };
+        if *rollup_state != find_rollup_state_pda().0 || *inbox != find_inbox_pda().0 {
+            return Err(anyhow!("wrong rollup state or inbox pda"));
+        }
+        if let Some(state) = Self::rollup_state(accounts)? {
+            if state.operator != *operator {
+                return Err(anyhow!("{} is not the rollup operator", operator));
+            }
+            if args.batch_id <= state.batch_id {
+                return Err(anyhow!("batch {} is not newer than {}", args.batch_id, state.batch_id));
+            }
+        }
+
+        let mut queue = Self::inbox(accounts)?;
+        queue.entries.retain(|entry| entry.index >= args.inbox_index);
+        if let Some(entry) = queue
+            .entries
+            .iter()
+            .find(|entry| entry.batch_id + FORCED_INCLUSION_BATCHES <= args.batch_id)
+        {
+            return Err(anyhow!("inbox entry {} is overdue", entry.index));
+        }
+        write_program_account(accounts, inbox, &queue)?;
+
+        let state = RollupState {
+            operator: *operator,
+            batch_id: args.batch_id,
+            state_root: args.state_root,
+            slot: current_slot(accounts)?,
+        };
+        write_program_account(accounts, rollup_state, &state)?;
+        emit(logs, &StateRootPostedEvent {
+            batch_id: args.batch_id,
+            state_root: args.state_root,
+            inbox_index: args.inbox_index,
+        });
+        Ok(())
+    }
+
+    fn enqueue_transaction(
+        accounts: &mut HashMap<Pubkey, Account>,
+        instruction_accounts: &[Pubkey],
+        args: EnqueueTransactionArgs,
+        logs: &mut Vec<String>,
+    ) -> Result<()> {
+        let [sender, inbox, ..] = instruction_accounts else {
+            return Err(anyhow!("enqueue_transaction needs a sender and the inbox"));
+        };
+        if *inbox != find_inbox_pda().0 {
+            return Err(anyhow!("{} is not the inbox pda", inbox));
+        }
+        let batch_id = Self::rollup_state(accounts)?.map_or(0, |state| state.batch_id);
+        let slot = current_slot(accounts)?;
+
+        let mut queue = Self::inbox(accounts)?;
+        queue.entries.push(InboxEntry {
+            index: queue.next_index,
+            sender: *sender,
+            batch_id,
+            slot,
+            transaction: args.transaction,
+        });
+        emit(logs, &TransactionEnqueuedEvent {
+            index: queue.next_index,
+            sender: *sender,
+        });
+        queue.next_index += 1;
+        write_program_account(accounts, inbox, &queue)
+    }
+
+    /// Pays out the proven balance, capped at what the PDA holds.
+    fn force_exit(
+        accounts: &mut HashMap<Pubkey, Account>,
+        instruction_accounts: &[Pubkey],
+        args: ForceExitArgs,
+        logs: &mut Vec<String>,
+    ) -> Result<()> {
+        let [owner, pda, rollup_state, exit_record, ..] = instruction_accounts else {
+            return Err(anyhow!("force_exit needs an owner, pda, rollup state and exit record"));
+        };
+        if *rollup_state != find_rollup_state_pda().0 || *exit_record != find_exit_record_pda(owner).0 {
+            return Err(anyhow!("wrong rollup state or exit record pda"));
+        }
+        let state = Self::rollup_state(accounts)?.ok_or_else(|| anyhow!("no state root was posted"))?;
+        if current_slot(accounts)? <= state.slot + FORCE_EXIT_TIMEOUT_SLOTS {
+            return Err(anyhow!("the sequencer posted a root in the last {} slots", FORCE_EXIT_TIMEOUT_SLOTS));
+        }
+        if args.batch_id != state.batch_id {
+            return Err(anyhow!("proof is for batch {}, the last posted root is {}", args.batch_id, state.batch_id));
+        }
+        if accounts.get(exit_record).is_some_and(|account| !account.data.is_empty()) {
+            return Err(anyhow!("{} already exited", owner));
+        }
+
+        let mut account = AccountSharedData::new(args.lamports, args.data.len(), &args.account_owner);
+        account.set_data_from_slice(&args.data);
+        account.set_executable(args.executable);
+        account.set_rent_epoch(args.rent_epoch);
+        let proof = MerkleProof {
+            siblings: args.proof.iter().map(|(hash, is_left)| (Hash::new_from_array(*hash), *is_left)).collect(),
+        };
+        if !proof.verify(account_leaf(owner, &account), &Hash::new_from_array(state.state_root)) {
+            return Err(anyhow!("invalid proof for {}", owner));
+        }
+
+        let mut delegation = Self::delegation(accounts, owner, pda)?;
+        let amount = args.lamports.min(delegation.delegated_amount);
+        delegation.delegated_amount -= amount;
+        move_lamports(accounts, pda, owner, amount)?;
+        write_program_account(accounts, pda, &delegation)?;
+        write_program_account(accounts, exit_record, &ExitRecord { batch_id: args.batch_id })?;
+        emit(logs, &ForcedExitEvent {
+            owner: *owner,
+            amount,
+            batch_id: args.batch_id,
+        });
+        Ok(())
+    }
+
+    /// Can't re-execute the disputed entry, so it only checks the challenge
+    /// is against the last posted root, its accounts are proven and it
+    /// disputes something.
+    fn challenge_state_root(accounts: &mut HashMap<Pubkey, Account>, instruction_accounts: &[Pubkey], args: ChallengeStateRootArgs) -> Result<()> {
+        let [_challenger, rollup_state, ..] = instruction_accounts else {
+            return Err(anyhow!("challenge_state_root needs a challenger and the rollup state"));
+        };
+        if *rollup_state != find_rollup_state_pda().0 {
+            return Err(anyhow!("{} is not the rollup state pda", rollup_state));
+        }
+        let state = Self::rollup_state(accounts)?.ok_or_else(|| anyhow!("no state root was posted"))?;
+        if args.batch_id != state.batch_id {
+            return Err(anyhow!("challenge is for batch {}, the last posted root is {}", args.batch_id, state.batch_id));
+        }
+        for proven in &args.pre_state {
+            let mut account = AccountSharedData::new(proven.lamports, proven.data.len(), &proven.account_owner);
+            account.set_data_from_slice(&proven.data);
+            account.set_executable(proven.executable);
+            account.set_rent_epoch(proven.rent_epoch);
+            let proof = MerkleProof {
+                siblings: proven.proof.iter().map(|(hash, is_left)| (Hash::new_from_array(*hash), *is_left)).collect(),
+            };
+            if !proof.verify(account_leaf(&proven.pubkey, &account), &Hash::new_from_array(args.pre_state_root)) {
+                return Err(anyhow!("invalid proof for {}", proven.pubkey));
+            }
+        }
+        if args.claimed_writes_hash == args.expected_writes_hash && args.expected_state_root == state.state_root {
+            return Err(anyhow!("challenge agrees with batch {}", args.batch_id));
+        }
+        Ok(())
+    }
+}
+
+fn current_slot(accounts: &HashMap<Pubkey, Account>) -> Result<Slot> {
+    let clock = accounts
+        .get(&sysvar::clock::id())
+        .ok_or_else(|| anyhow!("clock sysvar missing"))?;
+    Ok(bincode::deserialize::<Clock>(&clock.data)?.slot)
+}
+
+/// Logs an event the way Anchor's `emit!` does.
+fn emit<T: ProgramEvent + BorshSerialize>(logs: &mut Vec<String>, event: &T) {
+    let mut data = T::discriminator().to_vec();
+    data.extend(event.try_to_vec().unwrap());
+    logs.push(format!("Program data: {}", BASE64.encode(data)));
+}
+
+fn write_program_account<T: ProgramAccount>(accounts: &mut HashMap<Pubkey, Account>, pubkey: &Pubkey, value: &T) -> Result<()> {
+    let account = account_mut(accounts, pubkey);
+    account.owner = get_delegation_program_id();
+    account.data = value.to_account_data();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc, thread, time::{Duration, Instant}};
+
+    use solana_sdk::{
+        account::ReadableAccount, instruction::Instruction, native_token::LAMPORTS_PER_SOL, signature::Keypair,
+        signer::Signer,
+    };
+    use solana_system_interface::instruction as system_instruction;
+    use tokio::runtime::Builder;
+
+    use super::*;
+    use crate::{
+        allowance::AllowanceLedger,
+        batch::{BatchJournal, BatchRecord},
+        block::{BlockEntry, BlockJournal, BlockQuery},
+        config::NodeConfig,
+        da::{DaBackend, FileDa},
+        delegation::{create_delegation_instruction, create_withdrawal_instruction, set_rollup_instructions},
+        delegation_service::DelegationService,
+        deposit_watcher::DepositWatcher,
+        escape_hatch::SettledState,
+        genesis::Genesis,
+        loader::AccountSource,
+        plugin::Plugins,
+        replay,
+        rollupdb::{RollupDB, RollupDBMessage},
+        sequencer,
+        settle::{SettlementStatus, SettlementWorker},
+        signer::OperatorSigner,
+        snapshot::SnapshotWriter,
+        state::RollupState as L2State,
+        withdrawal::{WithdrawalJournal, WithdrawalQuery, WithdrawalStatus},
+    };
+
+    /// A sequencer node wired up like `main`, against its own mock L1. Two
+    /// transactions make a batch.
+    struct TestNode {
+        config: NodeConfig,
+        l1: Arc<MockL1>,
+        state: Arc<L2State>,
+        sequencer: CBSender<Transaction>,
+    }
+
+    impl TestNode {
+        fn start(name: &str, configure: impl FnOnce(&mut NodeConfig)) -> Self {
+            // The mock L1 has the rollup's instructions
+            set_rollup_instructions(true);
+            let mut config = NodeConfig::default();
+            config.storage.path = std::env::temp_dir().join(format!("rollup-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&config.storage.path);
+            fs::create_dir_all(&config.storage.path).unwrap();
+            config.batch.max_transactions = 2;
+            config.blocks.interval_ms = 50;
+            config.deposits.poll_interval_ms = 20;
+            config.deposits.confirmation_depth = 0;
+            config.settlement.poll_interval_ms = 20;
+            config.settlement.initial_backoff_ms = 20;
+            config.settlement.max_backoff_ms = 100;
+            config.inbox.enabled = false;
+            config.da.backend = DaBackend::File;
+            configure(&mut config);
+            Self::boot(config, Arc::new(MockL1::new()))
+        }
+
+        /// Boots a node on `config`'s storage, from whatever its journals hold.
+        fn boot(config: NodeConfig, l1: Arc<MockL1>) -> Self {
+            let node_config = config.clone();
+            let l1_client: Arc<dyn L1Client> = l1.clone();
+            let state = Arc::new(L2State::default());
+            let (sequencer_sender, sequencer_receiver) = crossbeam::channel::unbounded();
+            let (rollupdb_sender, rollupdb_receiver) = crossbeam::channel::unbounded::<RollupDBMessage>();
+            let (settlement_sender, settlement_receiver) = crossbeam::channel::unbounded();
+            // Disconnected, like with the inbox watcher disabled
+            let (_, inbox_receiver) = crossbeam::channel::unbounded();
+            let (account_sender, account_receiver) = async_channel::unbounded();
+            let (locked_sender, locked_receiver) = async_channel::unbounded();
+
+            let operator: OperatorSigner = Arc::new(Keypair::new());
+            let data_availability = Arc::new(FileDa::new(config.da_dir()));
+            let settlement_config = config.settlement_config();
+            let (db_sender, client) = (rollupdb_sender.clone(), l1_client.clone());
+            thread::spawn(move || {
+                SettlementWorker::run(settlement_receiver, db_sender, client, operator, data_availability, settlement_config)
+            });
+            let watcher = DepositWatcher::new(config.deposit_watcher_config(), l1_client.clone(), rollupdb_sender.clone());
+            thread::spawn(move || watcher.run());
+
+            let delegation_service = Arc::new(DelegationService::new(
+                l1_client.clone(),
+                AllowanceLedger::load(config.storage_file("allowances.json")).unwrap(),
+            ));
+            let account_source = AccountSource::new(l1_client, config.accounts.l1_clone);
+            let batch_journal = BatchJournal::new(config.storage_file("batch_journal.jsonl"));
+            let block_journal = BlockJournal::new(config.storage_file("blocks.jsonl"));
+            let withdrawal_journal = WithdrawalJournal::new(config.storage_file("withdrawals.jsonl"));
+            let snapshot_writer = SnapshotWriter::new(config.snapshot_config());
+            let genesis = Genesis::default();
+            let batches = BatchJournal::new(config.storage_file("batch_journal.jsonl")).load().unwrap();
+            let last_settled = batches.values().rev().find(|record| matches!(record.status, SettlementStatus::Confirmed { .. }));
+            let rebuilt: Vec<BatchRecord> = batches
+                .values()
+                .filter(|record| record.status.is_settling())
+                .chain(last_settled)
+                .cloned()
+                .collect();
+            let boot = replay::boot(&genesis, config.fees.lamports_per_signature, None, &block_journal, &rebuilt).unwrap();
+            let rollup_state = state.clone();
+            thread::spawn(move || {
+                let rt = Builder::new_multi_thread().worker_threads(2).enable_time().build().unwrap();
+                rt.spawn(sequencer::run(
+                    sequencer_receiver,
+                    inbox_receiver,
+                    rollupdb_sender,
+                    account_receiver,
+                    locked_receiver,
+                    delegation_service,
+                    account_source,
+                    rollup_state.clone(),
+                    genesis.rent_collector(),
+                    config,
+                ));
+                rt.block_on(RollupDB::run(
+                    rollupdb_receiver,
+                    rollup_state,
+                    account_sender,
+                    locked_sender,
+                    settlement_sender,
+                    batch_journal,
+                    block_journal,
+                    withdrawal_journal,
+                    snapshot_writer,
+                    None,
+                    boot,
+                    genesis,
+                    Arc::new(Plugins::load(&[]).unwrap()),
+                ));
+            });
+
+            Self {
+                config: node_config,
+                l1,
+                state,
+                sequencer: sequencer_sender,
+            }
+        }
+
+        /// Funds `owner` on L1 and delegates `lamports` of it, waits until the
+        /// deposit is credited on the rollup.
+        fn deposit(&self, owner: &Keypair, lamports: u64) {
+            self.l1.airdrop(&owner.pubkey(), 10 * LAMPORTS_PER_SOL);
+            let before = self.balance(&owner.pubkey());
+            self.send_l1(owner, &[create_delegation_instruction(&owner.pubkey(), lamports)]);
+            wait_for("the deposit to be credited", || self.balance(&owner.pubkey()) == before + lamports);
+        }
+
+        fn send_l1(&self, payer: &Keypair, ixs: &[Instruction]) {
+            let (blockhash, _) = self.l1.get_latest_blockhash().unwrap();
+            let tx = Transaction::new_signed_with_payer(ixs, Some(&payer.pubkey()), &[payer], blockhash);
+            self.l1.send_and_confirm_transaction(&tx).unwrap();
+        }
+
+        /// Submits an L2 transaction signed by `payer`.
+        fn submit(&self, payer: &Keypair, ixs: &[Instruction]) -> Signature {
+            let tx = Transaction::new_signed_with_payer(ixs, Some(&payer.pubkey()), &[payer], Hash::default());
+            self.sequencer.send(tx.clone()).unwrap();
+            tx.signatures[0]
+        }
+
+        fn transfer(&self, from: &Keypair, to: &Pubkey, lamports: u64) -> Signature {
+            self.submit(from, &[system_instruction::transfer(&from.pubkey(), to, lamports)])
+        }
+
+        fn withdraw(&self, owner: &Keypair, lamports: u64) -> Signature {
+            let (pda, _) = find_delegation_pda(&owner.pubkey());
+            self.submit(owner, &[create_withdrawal_instruction(&pda, &owner.pubkey(), lamports)])
+        }
+
+        /// Rollup balance
+        fn balance(&self, pubkey: &Pubkey) -> u64 {
+            self.state.accounts.get(pubkey).map_or(0, |account| account.lamports())
+        }
+
+        fn l1_balance(&self, pubkey: &Pubkey) -> u64 {
+            self.l1.get_account(pubkey).unwrap().map_or(0, |account| account.lamports)
+        }
+
+        fn delegated(&self, owner: &Pubkey) -> u64 {
+            let (pda, _) = find_delegation_pda(owner);
+            let account = self.l1.get_account(&pda).unwrap().unwrap();
+            DelegatedAccount::try_from_account_data(&account.data).unwrap().delegated_amount
+        }
+
+        /// Waits until the withdrawal requested by `signature` is recorded
+        /// and `done` with its status.
+        fn wait_for_withdrawal(&self, signature: &Signature, done: impl Fn(&WithdrawalStatus) -> bool) -> WithdrawalStatus {
+            let status = || {
+                self.state
+                    .withdrawals(&WithdrawalQuery::BySignature(*signature))
+                    .pop()
+                    .map(|withdrawal| withdrawal.status)
+            };
+            wait_for("the withdrawal", || status().is_some_and(|status| done(&status)));
+            status().unwrap()
+        }
+    }
+
+    fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+        let started = Instant::now();
+        while !done() {
+            assert!(started.elapsed() < Duration::from_secs(20), "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn deposit_transfer_and_withdrawal_settle_on_l1() {
+        let node = TestNode::start("settle", |_| {});
+        let (alice, bob) = (Keypair::new(), Keypair::new());
+        node.deposit(&alice, 5 * LAMPORTS_PER_SOL);
+        assert_eq!(node.l1_balance(&alice.pubkey()), 5 * LAMPORTS_PER_SOL);
+
+        node.transfer(&alice, &bob.pubkey(), LAMPORTS_PER_SOL);
+        let withdrawal = node.withdraw(&alice, 2 * LAMPORTS_PER_SOL);
+        let status = node.wait_for_withdrawal(&withdrawal, |status| !matches!(status, WithdrawalStatus::Pending | WithdrawalStatus::Settling { .. }));
+        assert!(matches!(status, WithdrawalStatus::Paid { batch_id: 0, .. }), "{:?}", status);
+
+        assert_eq!(node.balance(&alice.pubkey()), 2 * LAMPORTS_PER_SOL);
+        assert_eq!(node.balance(&bob.pubkey()), LAMPORTS_PER_SOL);
+        // Paid out of alice's PDA, what bob received stays on the rollup
+        assert_eq!(node.l1_balance(&alice.pubkey()), 7 * LAMPORTS_PER_SOL);
+        assert_eq!(node.delegated(&alice.pubkey()), 3 * LAMPORTS_PER_SOL);
+
+        let batch = node.state.batch(0).unwrap();
+        assert!(matches!(batch.status, SettlementStatus::Confirmed { .. }), "{:?}", batch.status);
+        assert_eq!(batch.transactions.len(), 2);
+        let rollup_state = node.l1.get_account(&find_rollup_state_pda().0).unwrap().unwrap();
+        let rollup_state = RollupState::try_from_account_data(&rollup_state.data).unwrap();
+        assert_eq!(rollup_state.batch_id, 0);
+        assert_eq!(rollup_state.state_root, batch.post_state_root.to_bytes());
+    }
+
+    #[test]
+    fn failed_settlement_refunds_withdrawals() {
+        let node = TestNode::start("refund", |config| {
+            // Publishing the batch data fails on every attempt
+            let da_dir = config.storage.path.join("da");
+            fs::write(&da_dir, b"not a directory").unwrap();
+            config.da.dir = Some(da_dir);
+            config.settlement.max_attempts = 3;
+        });
+        let (alice, bob) = (Keypair::new(), Keypair::new());
+        node.deposit(&alice, 5 * LAMPORTS_PER_SOL);
+
+        node.transfer(&alice, &bob.pubkey(), LAMPORTS_PER_SOL);
+        let withdrawal = node.withdraw(&alice, 2 * LAMPORTS_PER_SOL);
+        let status = node.wait_for_withdrawal(&withdrawal, |status| matches!(status, WithdrawalStatus::Refunded { .. }));
+        assert_eq!(status, WithdrawalStatus::Refunded { batch_id: 0 });
+
+        let batch = node.state.batch(0).unwrap();
+        assert_eq!(
+            batch.status,
+            SettlementStatus::Failed {
+                reason: "gave up after 3 attempts".to_string()
+            }
+        );
+        assert!(batch.settlement_signatures.is_empty());
+        // The burn is undone on the rollup, nothing moved on L1
+        wait_for("the refund", || node.balance(&alice.pubkey()) == 4 * LAMPORTS_PER_SOL);
+        assert_eq!(node.delegated(&alice.pubkey()), 5 * LAMPORTS_PER_SOL);
+        assert!(node.l1.get_account(&find_rollup_state_pda().0).unwrap().is_none());
+    }
+
+    #[test]
+    fn withdrawal_over_the_delegation_is_rejected() {
+        let node = TestNode::start("overdrawn", |_| {});
+        let (alice, bob) = (Keypair::new(), Keypair::new());
+        node.deposit(&alice, 2 * LAMPORTS_PER_SOL);
+        node.deposit(&bob, 3 * LAMPORTS_PER_SOL);
+
+        // Received lamports are on alice's rollup balance, not in her PDA
+        node.transfer(&bob, &alice.pubkey(), LAMPORTS_PER_SOL);
+        wait_for("the transfer", || node.balance(&alice.pubkey()) == 3 * LAMPORTS_PER_SOL);
+        let withdrawal = node.withdraw(&alice, 3 * LAMPORTS_PER_SOL);
+        let status = node.wait_for_withdrawal(&withdrawal, |_| true);
+        assert_eq!(
+            status,
+            WithdrawalStatus::Rejected {
+                reason: format!("its delegation PDA can only pay out {} more lamports", 2 * LAMPORTS_PER_SOL)
+            }
+        );
+        assert_eq!(node.balance(&alice.pubkey()), 3 * LAMPORTS_PER_SOL);
+
+        // What is already on its way out counts against the PDA too
+        let first = node.withdraw(&alice, 2 * LAMPORTS_PER_SOL);
+        let status = node.wait_for_withdrawal(&first, |_| true);
+        assert!(!matches!(status, WithdrawalStatus::Rejected { .. }), "{:?}", status);
+        let second = node.withdraw(&alice, 1);
+        let status = node.wait_for_withdrawal(&second, |_| true);
+        assert!(matches!(status, WithdrawalStatus::Rejected { .. }), "{:?}", status);
+        assert_eq!(node.balance(&alice.pubkey()), LAMPORTS_PER_SOL);
+    }
+
+    #[test]
+    fn boot_replays_the_block_journal() {
+        let node = TestNode::start("boot", |_| {});
+        let (alice, bob) = (Keypair::new(), Keypair::new());
+        node.deposit(&alice, 5 * LAMPORTS_PER_SOL);
+        node.transfer(&alice, &bob.pubkey(), LAMPORTS_PER_SOL);
+        node.transfer(&alice, &bob.pubkey(), LAMPORTS_PER_SOL);
+        wait_for("the transfers", || node.balance(&bob.pubkey()) == 2 * LAMPORTS_PER_SOL);
+        wait_for("the batch", || node.state.batch(0).is_some());
+        let batch = node.state.batch(0).unwrap();
+        let journal = BlockJournal::new(node.config.storage_file("blocks.jsonl"));
+        wait_for("the batch's blocks", || {
+            let blocks = journal.load().unwrap();
+            let journaled: Vec<_> = blocks.values().flat_map(|record| record.block.transactions.iter()).collect();
+            batch.transactions.iter().all(|signature| journaled.contains(&signature))
+        });
+
+        let genesis = Genesis::default();
+        let fees = node.config.fees.lamports_per_signature;
+        let blocks = journal.load().unwrap();
+        let last = blocks.values().last().unwrap().block.clone();
+        // As if the batch was still settling when the node stopped
+        let boot = replay::boot(&genesis, fees, None, &journal, std::slice::from_ref(&batch)).unwrap();
+        assert_eq!(SettledState::new(0, boot.accounts).state_root, last.state_root);
+        assert_eq!(boot.batch_states[&0].state_root, batch.post_state_root);
+
+        // A journal the accounts can't be rebuilt from stops the boot
+        let tampered = BlockJournal::new(node.config.storage_file("tampered.jsonl"));
+        for mut record in blocks.into_values() {
+            for entry in record.entries.iter_mut() {
+                if let BlockEntry::Deposit { lamports, .. } = entry {
+                    *lamports += 1;
+                }
+            }
+            tampered.append(&record).unwrap();
+        }
+        assert!(replay::boot(&genesis, fees, None, &tampered, &[]).is_err());
+    }
+
+    #[test]
+    fn restart_restores_withdrawals_transactions_and_exit_proofs() {
+        let node = TestNode::start("restart", |_| {});
+        let (alice, bob) = (Keypair::new(), Keypair::new());
+        node.deposit(&alice, 5 * LAMPORTS_PER_SOL);
+        node.transfer(&alice, &bob.pubkey(), LAMPORTS_PER_SOL);
+        let withdrawal = node.withdraw(&alice, 2 * LAMPORTS_PER_SOL);
+        let paid = node.wait_for_withdrawal(&withdrawal, |status| matches!(status, WithdrawalStatus::Paid { .. }));
+        // Left for the next batch
+        let unsettled = node.transfer(&alice, &bob.pubkey(), LAMPORTS_PER_SOL / 2);
+        wait_for("the unsettled transfer's block", || !node.state.blocks(&BlockQuery::ByTransaction(unsettled)).is_empty());
+
+        let restarted = TestNode::boot(node.config.clone(), node.l1.clone());
+        // The accounts are restored last
+        wait_for("the restart", || restarted.balance(&alice.pubkey()) == node.balance(&alice.pubkey()));
+        assert_eq!(restarted.wait_for_withdrawal(&withdrawal, |_| true), paid);
+        let pending: Vec<Signature> = restarted.state.transactions().into_iter().map(|(_, tx)| tx.signatures[0]).collect();
+        assert_eq!(pending, vec![unsettled]);
+        let (proof, restored) = (node.state.exit_proof(&bob.pubkey()).unwrap(), restarted.state.exit_proof(&bob.pubkey()).unwrap());
+        assert_eq!((restored.batch_id, restored.state_root), (proof.batch_id, proof.state_root));
+        assert!(restored.verify());
+    }
+}
@@ -1,20 +1,35 @@
 //! A helper to initialize Solana SVM API's `TransactionBatchProcessor`.
 
 use {
+    agave_feature_set::FeatureSet,
+    anyhow::{anyhow, Result},
+    serde::{Deserialize, Serialize},
     solana_bpf_loader_program::syscalls::create_program_runtime_environment_v1,
     solana_compute_budget::compute_budget::ComputeBudget,
     solana_program_runtime::loaded_programs::{BlockRelation, ForkGraph, ProgramCacheEntry},
-    solana_sdk::{clock::Slot, feature_set::FeatureSet, pubkey::Pubkey, transaction},
+    solana_sdk::{
+        account::AccountSharedData, bpf_loader, clock::Slot, hash::Hash, pubkey::Pubkey,
+        transaction::{self, SanitizedTransaction, Transaction},
+    },
     solana_svm::{
         account_loader::CheckedTransactionDetails,
         transaction_processing_callback::TransactionProcessingCallback,
-        transaction_processor::TransactionBatchProcessor,
+        transaction_processing_result::ProcessedTransaction,
+        transaction_processor::{
+            ExecutionRecordingConfig, TransactionBatchProcessor, TransactionProcessingConfig,
+            TransactionProcessingEnvironment,
+        },
     },
     solana_system_program::system_processor,
     std::sync::{Arc, RwLock},
     std::collections::HashSet,
 };
 
+/// The SVM still takes solana-sdk's `RentCollector`, deprecated in favour of
+/// the runtime's own, which the rollup doesn't link.
+#[allow(deprecated)]
+pub type RentCollector = solana_sdk::rent_collector::RentCollector;
+
 /// In order to use the `TransactionBatchProcessor`, another trait - Solana
 /// Program Runtime's `ForkGraph` - must be implemented, to tell the batch
 /// processor how to work across forks.
@@ -32,7 +47,8 @@ impl ForkGraph for RollupForkGraph {
 /// `TransactionBatchProcessor` for use within PayTube.
 ///
 /// We're simply configuring the mocked fork graph on the SVM API's program
-/// cache, then adding the System program to the processor's builtins.
+/// cache, then adding the System program and the BPF loader to the
+/// processor's builtins.
 pub(crate) fn create_transaction_batch_processor<CB: TransactionProcessingCallback>(
     callbacks: &CB,
     feature_set: &FeatureSet,
@@ -64,6 +80,14 @@ pub(crate) fn create_transaction_batch_processor<CB: TransactionProcessingCallba
         ProgramCacheEntry::new_builtin(0, b"system_program".len(), system_processor::Entrypoint::vm),
     );
 
+    // Add the BPF loader, genesis programs are deployed with it
+    processor.add_builtin(
+        callbacks,
+        bpf_loader::id(),
+        "solana_bpf_loader_program",
+        ProgramCacheEntry::new_builtin(0, b"solana_bpf_loader_program".len(), solana_bpf_loader_program::Entrypoint::vm),
+    );
+
     processor
 }
 
@@ -82,3 +106,94 @@ pub(crate) fn get_transaction_check_results(
         len
     ]
 }
+
+/// What executing a transaction produced.
+pub(crate) struct Execution {
+    /// The accounts it loaded, as they are after execution.
+    pub accounts: Vec<(Pubkey, AccountSharedData)>,
+    pub status: ExecutionStatus,
+}
+
+/// How a transaction executed, passed on to subscribers and plugins.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ExecutionStatus {
+    pub compute_units: u64,
+    /// Set if an instruction failed, the fee and nonce changes are still
+    /// committed.
+    pub error: Option<String>,
+    pub logs: Vec<String>,
+}
+
+/// Executes a single transaction against the accounts `callbacks` provides.
+/// The sequencer and replay both execute through here, so they can't diverge
+/// on the SVM setup.
+pub(crate) fn execute_transaction<CB: TransactionProcessingCallback>(
+    callbacks: &CB,
+    transaction: &Transaction,
+    rent_collector: &RentCollector,
+    lamports_per_signature: u64,
+) -> Result<Execution> {
+    let compute_budget = ComputeBudget::default();
+    let feature_set = FeatureSet::all_enabled();
+    let fork_graph = Arc::new(RwLock::new(RollupForkGraph {}));
+
+    let sanitized = SanitizedTransaction::try_from_legacy_transaction(transaction.clone(), &HashSet::new())
+        .map_err(|e| anyhow!("failed to sanitize {}: {}", transaction.signatures[0], e))?;
+    log::debug!("{:?}", sanitized);
+
+    let processor = create_transaction_batch_processor(callbacks, &feature_set, &compute_budget, Arc::clone(&fork_graph));
+
+    let checks = get_transaction_check_results(1, lamports_per_signature);
+    let processing_environment = TransactionProcessingEnvironment {
+        blockhash: Hash::default(),
+        epoch_total_stake: 0u64,
+        feature_set: Arc::new(feature_set),
+        blockhash_lamports_per_signature: lamports_per_signature,
+        fee_lamports_per_signature: lamports_per_signature,
+        rent_collector: Some(rent_collector),
+    };
+    let processing_config = TransactionProcessingConfig {
+        compute_budget: Some(compute_budget),
+        // Logs go out to logsSubscribe
+        recording_config: ExecutionRecordingConfig {
+            enable_cpi_recording: false,
+            enable_log_recording: true,
+            enable_return_data_recording: false,
+        },
+        ..Default::default()
+    };
+
+    let status = processor.load_and_execute_sanitized_transactions(
+        callbacks,
+        &[sanitized],
+        checks,
+        &processing_environment,
+        &processing_config,
+    );
+    log::debug!("{:#?}", status.processing_results);
+    log::debug!("error_metrics: {:#?}", status.error_metrics);
+
+    let result = status
+        .processing_results
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("no processing result for {}", transaction.signatures[0]))?;
+    match result.map_err(|e| anyhow!("{} was not processed: {}", transaction.signatures[0], e))? {
+        ProcessedTransaction::Executed(tx) => {
+            log::debug!("Executed transaction: {:?}", tx.loaded_transaction.accounts);
+            Ok(Execution {
+                accounts: tx.loaded_transaction.accounts,
+                status: ExecutionStatus {
+                    compute_units: tx.execution_details.executed_units,
+                    error: tx.execution_details.status.err().map(|e| e.to_string()),
+                    logs: tx.execution_details.log_messages.unwrap_or_default(),
+                },
+            })
+        }
+        ProcessedTransaction::FeesOnly(tx) => Err(anyhow!(
+            "{} failed to load, only fees were charged: {:?}",
+            transaction.signatures[0],
+            tx.load_error
+        )),
+    }
+}
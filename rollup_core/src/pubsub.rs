@@ -0,0 +1,348 @@
+//! WebSocket subscriptions on `/ws`, mirroring Solana's pubsub API:
+//! `accountSubscribe`, `signatureSubscribe`, `slotSubscribe` and
+//! `logsSubscribe`, and their unsubscribe methods.
+//!
+//! Whoever writes the rollup's state, RollupDB or a follower, publishes what
+//! it commits, seals and settles to the `PubsubHub`. Every connection gets
+//! all events and filters them for its own subscriptions. Commitment maps
+//! onto the rollup as `processed` and `confirmed` for executed by the
+//! sequencer, `finalized` for settled on L1. Account and log notifications
+//! go out when the sequencer commits.
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::{Closed, Message, MessageStream, Session};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::{json, Value};
+use solana_sdk::{
+    account::{AccountSharedData, ReadableAccount},
+    clock::Slot,
+    pubkey::Pubkey,
+    signature::Signature,
+};
+
+use crate::{
+    batch::BatchQuery,
+    block::BlockQuery,
+    settle::SettlementStatus,
+    state::RollupState,
+};
+
+/// Events a connection can fall behind by before it is closed.
+pub const PUBSUB_CAPACITY: usize = 4_096;
+
+/// A change to the rollup's state.
+#[derive(Debug)]
+pub enum PubsubEvent {
+    /// Accounts committed in the open block.
+    Accounts { slot: Slot, accounts: Vec<(Pubkey, AccountSharedData)> },
+    /// A transaction executed in the open block, withdrawals included.
+    Transaction { slot: Slot, signature: Signature, account_keys: Vec<Pubkey>, logs: Vec<String> },
+    /// A block was sealed.
+    Slot { slot: Slot },
+    /// A batch was confirmed on L1, its blocks up to `slot` are final.
+    Settled { slot: Slot, signatures: Vec<Signature> },
+}
+
+/// Fans events out to the open connections.
+#[derive(Default)]
+pub struct PubsubHub {
+    subscribers: Mutex<Vec<async_channel::Sender<Arc<PubsubEvent>>>>,
+    /// Last block a settled batch covers.
+    root: AtomicU64,
+}
+
+impl PubsubHub {
+    pub fn subscribe(&self) -> async_channel::Receiver<Arc<PubsubEvent>> {
+        let (sender, receiver) = async_channel::bounded(PUBSUB_CAPACITY);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Connections that are gone or fell too far behind are dropped.
+    pub fn publish(&self, event: PubsubEvent) {
+        if let PubsubEvent::Settled { slot, .. } = &event {
+            self.root.fetch_max(*slot, Ordering::Relaxed);
+        }
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let event = Arc::new(event);
+        subscribers.retain(|sender| sender.try_send(event.clone()).is_ok());
+    }
+
+    pub fn root(&self) -> Slot {
+        self.root.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+enum Subscription {
+    Account { pubkey: Pubkey },
+    /// Removed once it was notified.
+    Signature { signature: Signature, finalized: bool },
+    Slot,
+    /// `None` for every transaction.
+    Logs { mentions: Option<Pubkey> },
+}
+
+impl Subscription {
+    fn notification_method(&self) -> &'static str {
+        match self {
+            Self::Account { .. } => "accountNotification",
+            Self::Signature { .. } => "signatureNotification",
+            Self::Slot => "slotNotification",
+            Self::Logs { .. } => "logsNotification",
+        }
+    }
+}
+
+/// JSON-RPC error codes, as Solana's pubsub uses them.
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+struct RequestError(i64, String);
+
+fn invalid_params(message: impl Into<String>) -> RequestError {
+    RequestError(INVALID_PARAMS, message.into())
+}
+
+fn commitment_finalized(config: Option<&Value>) -> Result<bool, RequestError> {
+    match config.and_then(|config| config.get("commitment")).and_then(Value::as_str) {
+        // Solana's default
+        None | Some("finalized") => Ok(true),
+        Some("processed" | "confirmed") => Ok(false),
+        Some(other) => Err(invalid_params(format!("invalid commitment {:?}", other))),
+    }
+}
+
+fn parse_pubkey(value: Option<&Value>) -> Result<Pubkey, RequestError> {
+    value
+        .and_then(Value::as_str)
+        .and_then(|pubkey| Pubkey::from_str(pubkey).ok())
+        .ok_or_else(|| invalid_params("expected a base58 pubkey"))
+}
+
+fn account_json(account: &AccountSharedData) -> Value {
+    json!({
+        "lamports": account.lamports(),
+        "data": [BASE64.encode(account.data()), "base64"],
+        "owner": account.owner().to_string(),
+        "executable": account.executable(),
+        "rentEpoch": account.rent_epoch(),
+        "space": account.data().len(),
+    })
+}
+
+/// One WebSocket connection and its subscriptions.
+struct Connection {
+    session: Session,
+    state: Arc<RollupState>,
+    subscriptions: HashMap<u64, Subscription>,
+    next_id: u64,
+}
+
+impl Connection {
+    async fn run(mut self, mut messages: MessageStream, events: async_channel::Receiver<Arc<PubsubEvent>>) {
+        loop {
+            let result = tokio::select! {
+                message = messages.recv() => match message {
+                    Some(Ok(Message::Text(text))) => self.request(&text).await,
+                    Some(Ok(Message::Ping(bytes))) => self.session.pong(&bytes).await,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => Ok(()),
+                },
+                event = events.recv() => match event {
+                    Ok(event) => self.notify(&event).await,
+                    // Fell too far behind
+                    Err(_) => break,
+                },
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        let _ = self.session.close(None).await;
+    }
+
+    async fn request(&mut self, text: &str) -> Result<(), Closed> {
+        let request: Value = match serde_json::from_str(text) {
+            Ok(request) => request,
+            Err(e) => return self.respond(Value::Null, Err(RequestError(PARSE_ERROR, e.to_string()))).await,
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = request.get("method").and_then(Value::as_str).unwrap_or_default();
+        let params = request.get("params").and_then(Value::as_array).cloned().unwrap_or_default();
+        log::debug!("Pubsub request {} {:?}", method, params);
+
+        let result = match method {
+            "accountSubscribe" => match params.get(1).and_then(|config| config.get("encoding")).and_then(Value::as_str) {
+                None | Some("base64") => parse_pubkey(params.first()).map(|pubkey| Subscription::Account { pubkey }),
+                Some(encoding) => Err(invalid_params(format!("unsupported encoding {:?}, only base64 is", encoding))),
+            },
+            "signatureSubscribe" => params
+                .first()
+                .and_then(Value::as_str)
+                .and_then(|signature| Signature::from_str(signature).ok())
+                .ok_or_else(|| invalid_params("expected a base58 signature"))
+                .and_then(|signature| {
+                    Ok(Subscription::Signature {
+                        signature,
+                        finalized: commitment_finalized(params.get(1))?,
+                    })
+                }),
+            "slotSubscribe" => Ok(Subscription::Slot),
+            "logsSubscribe" => match params.first() {
+                Some(Value::String(filter)) if filter == "all" || filter == "allWithVotes" => {
+                    Ok(Subscription::Logs { mentions: None })
+                }
+                Some(Value::Object(filter)) => match filter.get("mentions").and_then(Value::as_array) {
+                    Some(mentions) if mentions.len() == 1 => {
+                        parse_pubkey(mentions.first()).map(|pubkey| Subscription::Logs { mentions: Some(pubkey) })
+                    }
+                    _ => Err(invalid_params("mentions takes exactly one pubkey")),
+                },
+                _ => Err(invalid_params("expected \"all\" or {\"mentions\": [<pubkey>]}")),
+            },
+            "accountUnsubscribe" | "signatureUnsubscribe" | "slotUnsubscribe" | "logsUnsubscribe" => {
+                let removed = params
+                    .first()
+                    .and_then(Value::as_u64)
+                    .and_then(|subscription| self.subscriptions.remove(&subscription))
+                    .is_some();
+                return self.respond(id, Ok(json!(removed))).await;
+            }
+            _ => Err(RequestError(METHOD_NOT_FOUND, format!("method {:?} not found", method))),
+        };
+
+        let subscription = match result {
+            Ok(subscription) => subscription,
+            Err(e) => return self.respond(id, Err(e)).await,
+        };
+        let subscription_id = self.next_id;
+        self.next_id += 1;
+        self.respond(id, Ok(json!(subscription_id))).await?;
+
+        // A signature that already reached the commitment is notified right away
+        if let Subscription::Signature { signature, finalized } = &subscription {
+            let slot = if *finalized {
+                self.state
+                    .batches(&BatchQuery::ByTransaction(*signature))
+                    .into_iter()
+                    .find(|batch| matches!(batch.status, SettlementStatus::Confirmed { .. }))
+                    .map(|batch| batch.last_slot)
+            } else {
+                self.state.blocks(&BlockQuery::ByTransaction(*signature)).first().map(|block| block.slot)
+            };
+            if let Some(slot) = slot {
+                let method = subscription.notification_method();
+                return self.session.text(notification(subscription_id, method, signature_result(slot))).await;
+            }
+        }
+        self.subscriptions.insert(subscription_id, subscription);
+        Ok(())
+    }
+
+    async fn notify(&mut self, event: &PubsubEvent) -> Result<(), Closed> {
+        let mut notifications = Vec::new();
+        for (id, subscription) in self.subscriptions.iter() {
+            let result = match (subscription, event) {
+                (Subscription::Account { pubkey }, PubsubEvent::Accounts { slot, accounts }) => accounts
+                    .iter()
+                    .rev()
+                    .find(|(written, _)| written == pubkey)
+                    .map(|(_, account)| json!({ "context": { "slot": slot }, "value": account_json(account) })),
+                (Subscription::Signature { signature, finalized: false }, PubsubEvent::Transaction { slot, signature: executed, .. })
+                    if executed == signature =>
+                {
+                    Some(signature_result(*slot))
+                }
+                (Subscription::Signature { signature, finalized: true }, PubsubEvent::Settled { slot, signatures })
+                    if signatures.contains(signature) =>
+                {
+                    Some(signature_result(*slot))
+                }
+                (Subscription::Slot, PubsubEvent::Slot { slot }) => Some(json!({
+                    "parent": slot.saturating_sub(1),
+                    "root": self.state.pubsub.root(),
+                    "slot": slot,
+                })),
+                (Subscription::Logs { mentions }, PubsubEvent::Transaction { slot, signature, account_keys, logs })
+                    if mentions.iter().all(|pubkey| account_keys.contains(pubkey)) =>
+                {
+                    Some(json!({
+                        "context": { "slot": slot },
+                        "value": { "signature": signature.to_string(), "err": null, "logs": logs },
+                    }))
+                }
+                _ => None,
+            };
+            if let Some(result) = result {
+                notifications.push((*id, result));
+            }
+        }
+
+        for (id, result) in notifications {
+            let method = self.subscriptions[&id].notification_method();
+            self.session.text(notification(id, method, result)).await?;
+            // Signature subscriptions are one-shot
+            if let Some(Subscription::Signature { .. }) = self.subscriptions.get(&id) {
+                self.subscriptions.remove(&id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn respond(&mut self, id: Value, result: Result<Value, RequestError>) -> Result<(), Closed> {
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(RequestError(code, message)) => {
+                json!({ "jsonrpc": "2.0", "error": { "code": code, "message": message }, "id": id })
+            }
+        };
+        self.session.text(response.to_string()).await
+    }
+}
+
+fn notification(subscription: u64, method: &str, result: Value) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": { "result": result, "subscription": subscription },
+    })
+    .to_string()
+}
+
+fn signature_result(slot: Slot) -> Value {
+    // Failed transactions are never committed
+    json!({ "context": { "slot": slot }, "value": { "err": null } })
+}
+
+/// Upgrades the request to a WebSocket speaking the pubsub protocol.
+pub async fn subscribe(
+    req: HttpRequest,
+    body: web::Payload,
+    rollup_state: web::Data<Arc<RollupState>>,
+) -> actix_web::Result<HttpResponse> {
+    log::info!("Pubsub connection from {:?}", req.peer_addr());
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+    let events = rollup_state.pubsub.subscribe();
+    let connection = Connection {
+        session,
+        state: rollup_state.get_ref().clone(),
+        subscriptions: HashMap::new(),
+        next_id: 0,
+    };
+    actix_web::rt::spawn(connection.run(messages, events));
+    Ok(response)
+}
@@ -0,0 +1,343 @@
+//! Re-executes journaled blocks from genesis or a snapshot and checks every
+//! block reaches the state root it recorded (`rollup_core replay`).
+//!
+//! The state transitions are shared with RollupDB: transactions execute
+//! through `processor::execute_transaction`, and unlocking and crediting go
+//! through the functions below, so a divergence can only come from the
+//! journaled history itself.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use solana_sdk::{
+    account::{AccountSharedData, ReadableAccount, WritableAccount},
+    clock::Slot,
+    hash::{hashv, Hash},
+    pubkey::Pubkey,
+};
+use solana_sdk_ids::system_program;
+
+use crate::{
+    batch::BatchRecord,
+    block::{BlockEntry, BlockJournal, BlockRecord},
+    escape_hatch::SettledState,
+    genesis::Genesis,
+    loader::{AccountOrigin, RollupAccountLoader},
+    merkle::account_leaf,
+    processor::{execute_transaction, RentCollector},
+    snapshot::StateSnapshot,
+    withdrawal::parse_withdrawal,
+};
+
+/// `account` with a deposit credited, a new system account if there was none.
+pub fn credit(account: Option<AccountSharedData>, lamports: u64) -> AccountSharedData {
+    let mut account = account.unwrap_or_else(|| AccountSharedData::new(0, 0, &system_program::id()));
+    account.checked_add_lamports(lamports).unwrap();
+    account
+}
+
+/// What an executed transaction commits: deposits that arrived while its
+/// accounts were locked are added, and L1 mirrors it only read stay on L1.
+pub fn unlock_writes(
+    executed: Vec<(Pubkey, AccountSharedData)>,
+    credits: &HashMap<Pubkey, u64>,
+    mirrors: &HashMap<Pubkey, AccountSharedData>,
+) -> Vec<(Pubkey, AccountSharedData)> {
+    executed
+        .into_iter()
+        .filter_map(|(pubkey, mut account)| {
+            if let Some(lamports) = credits.get(&pubkey) {
+                account.checked_add_lamports(*lamports).unwrap();
+            }
+            match mirrors.get(&pubkey) {
+                Some(mirrored) if same_state(mirrored, &account) => None,
+                _ => Some((pubkey, account)),
+            }
+        })
+        .collect()
+}
+
+/// What a block entry commits to: the leaves of the accounts it wrote, in
+/// pubkey order.
+pub fn writes_hash(writes: &[(Pubkey, AccountSharedData)]) -> Hash {
+    let mut leaves: Vec<(&Pubkey, Hash)> = writes
+        .iter()
+        .map(|(pubkey, account)| (pubkey, account_leaf(pubkey, account)))
+        .collect();
+    leaves.sort_by_key(|(pubkey, _)| **pubkey);
+    hashv(&leaves.iter().map(|(_, leaf)| leaf.as_ref()).collect::<Vec<_>>())
+}
+
+// Rent epoch aside, the SVM may bump it on accounts it only read
+pub fn same_state(a: &AccountSharedData, b: &AccountSharedData) -> bool {
+    a.lamports() == b.lamports() && a.owner() == b.owner() && a.executable() == b.executable() && a.data() == b.data()
+}
+
+/// The rollup's accounts, rebuilt entry by entry.
+#[derive(Clone)]
+pub struct Replayer {
+    accounts: HashMap<Pubkey, AccountSharedData>,
+    rent_collector: RentCollector,
+    lamports_per_signature: u64,
+}
+
+impl Replayer {
+    pub fn new(accounts: Vec<(Pubkey, AccountSharedData)>, rent_collector: RentCollector, lamports_per_signature: u64) -> Self {
+        Self {
+            accounts: accounts.into_iter().collect(),
+            rent_collector,
+            lamports_per_signature,
+        }
+    }
+
+    pub fn accounts(&self) -> &HashMap<Pubkey, AccountSharedData> {
+        &self.accounts
+    }
+
+    pub fn state_root(&self) -> Hash {
+        self.settled_state(0).state_root
+    }
+
+    pub fn settled_state(&self, batch_id: u64) -> SettledState {
+        let accounts = self.accounts.iter().map(|(pubkey, account)| (*pubkey, account.clone())).collect();
+        SettledState::new(batch_id, accounts)
+    }
+
+    /// Applies one entry, returning the accounts it wrote.
+    pub fn apply(&mut self, entry: &BlockEntry) -> Result<Vec<(Pubkey, AccountSharedData)>> {
+        let writes = match entry {
+            BlockEntry::Transaction { transaction, l1_accounts, credits, .. } => {
+                let l1_accounts: HashMap<Pubkey, (&AccountSharedData, &AccountOrigin)> = l1_accounts
+                    .iter()
+                    .map(|(pubkey, account, origin)| (*pubkey, (account, origin)))
+                    .collect();
+                let mut loader = RollupAccountLoader::new();
+                for pubkey in transaction.message.account_keys.iter() {
+                    let account = self
+                        .accounts
+                        .get(pubkey)
+                        .cloned()
+                        .or_else(|| l1_accounts.get(pubkey).map(|(account, _)| (*account).clone()));
+                    if let Some(account) = account {
+                        loader.add_account(*pubkey, account);
+                    }
+                }
+                let executed = execute_transaction(&loader, transaction, &self.rent_collector, self.lamports_per_signature)?.accounts;
+                let mirrors: HashMap<Pubkey, AccountSharedData> = l1_accounts
+                    .iter()
+                    .filter(|(_, (_, origin))| matches!(origin, AccountOrigin::Mirrored { .. }))
+                    .map(|(pubkey, (account, _))| (*pubkey, (*account).clone()))
+                    .collect();
+                let credits: HashMap<Pubkey, u64> = credits.iter().copied().collect();
+                unlock_writes(executed, &credits, &mirrors)
+            }
+            BlockEntry::Withdrawal { transaction, l1_account, payable, .. } => {
+                let withdrawal = parse_withdrawal(transaction)
+                    .ok_or_else(|| anyhow!("{} is journaled as a withdrawal but isn't one", transaction.signatures[0]))?;
+                let mut account = self
+                    .accounts
+                    .get(&withdrawal.owner)
+                    .cloned()
+                    .or_else(|| l1_account.as_ref().map(|(account, _)| account.clone()))
+                    .unwrap_or_default();
+                // A rejected withdrawal leaves the balance untouched
+                if withdrawal.amount > *payable {
+                    Vec::new()
+                } else {
+                    match account.checked_sub_lamports(withdrawal.amount) {
+                        Ok(()) => vec![(withdrawal.owner, account)],
+                        Err(_) => Vec::new(),
+                    }
+                }
+            }
+            BlockEntry::Deposit { owner, lamports, .. } | BlockEntry::Refund { owner, lamports, .. } => {
+                vec![(*owner, credit(self.accounts.get(owner).cloned(), *lamports))]
+            }
+        };
+        self.accounts.extend(writes.iter().cloned());
+        Ok(writes)
+    }
+
+    /// Applies the block's entries and returns the state root they lead to.
+    pub fn replay_block(&mut self, record: &BlockRecord) -> Result<Hash> {
+        for (index, entry) in record.entries.iter().enumerate() {
+            let writes = self
+                .apply(entry)
+                .with_context(|| format!("block {}: failed to replay {:?}", record.block.slot, entry.signature()))?;
+            if writes_hash(&writes) != entry.writes_hash() {
+                return Err(anyhow!(
+                    "block {}: entry {} ({:?}) wrote other accounts than it was journaled with",
+                    record.block.slot,
+                    index,
+                    entry.signature()
+                ));
+            }
+        }
+        Ok(self.state_root())
+    }
+}
+
+/// A replayer at the snapshot's accounts, or the genesis', with the slot
+/// and parent hash of the first block to replay on top.
+fn starting_point(genesis: &Genesis, fees_per_signature: u64, snapshot: Option<StateSnapshot>) -> (Replayer, Slot, Hash) {
+    match snapshot {
+        Some(snapshot) => {
+            let mut accounts: HashMap<Pubkey, AccountSharedData> = snapshot.accounts.into_iter().collect();
+            for (pubkey, lamports) in snapshot.pending_credits {
+                let account = credit(accounts.remove(&pubkey), lamports);
+                accounts.insert(pubkey, account);
+            }
+            let replayer = Replayer::new(accounts.into_iter().collect(), genesis.rent_collector(), fees_per_signature);
+            (replayer, snapshot.next_slot, snapshot.last_block_hash)
+        }
+        None => {
+            let replayer = Replayer::new(genesis.accounts.clone(), genesis.rent_collector(), fees_per_signature);
+            (replayer, 1, genesis.hash)
+        }
+    }
+}
+
+/// Replays the journaled blocks from `slot` on, each has to chain onto the
+/// previous one and reproduce its state root. `at_boundary` sees the state
+/// after every block, with the block that follows if there is one. Returns
+/// how many were replayed and the slot after the last one.
+fn replay_blocks(
+    replayer: &mut Replayer,
+    mut slot: Slot,
+    mut parent_hash: Hash,
+    records: BTreeMap<Slot, BlockRecord>,
+    journal: &Path,
+    mut at_boundary: impl FnMut(&Replayer, Slot, Option<&BlockRecord>),
+) -> Result<(usize, Slot)> {
+    let mut replayed = 0usize;
+    let from_slot = slot;
+    for record in records.into_values().filter(|record| record.block.slot >= from_slot) {
+        at_boundary(replayer, slot - 1, Some(&record));
+        let block = &record.block;
+        if block.slot != slot {
+            return Err(anyhow!("block {} is missing from {}", slot, journal.display()));
+        }
+        if block.parent_hash != parent_hash {
+            return Err(anyhow!("block {} has parent {}, expected {}", block.slot, block.parent_hash, parent_hash));
+        }
+        if !block.verify() {
+            return Err(anyhow!("block {} doesn't match its hash {}", block.slot, block.hash));
+        }
+        let signatures: Vec<_> = record.entries.iter().filter_map(BlockEntry::signature).collect();
+        if signatures != block.transactions {
+            return Err(anyhow!("block {} lists other transactions than it has entries for", block.slot));
+        }
+
+        let state_root = replayer.replay_block(&record)?;
+        if state_root != block.state_root {
+            return Err(anyhow!(
+                "block {} diverges: state root {} recorded, {} replayed",
+                block.slot,
+                block.state_root,
+                state_root
+            ));
+        }
+        log::info!("Block {} ({} entries) reproduces state root {}", block.slot, record.entries.len(), state_root);
+        replayed += 1;
+        slot = block.slot + 1;
+        parent_hash = block.hash;
+    }
+    at_boundary(replayer, slot - 1, None);
+    Ok((replayed, slot))
+}
+
+/// The state a batch was cut at, `replayer` being at the last block it
+/// published. Entries committed after that block but before the cut went
+/// into the next one, so a prefix of `next`'s entries is tried until the
+/// batch's state root comes out.
+fn batch_state(replayer: &Replayer, next: Option<&BlockRecord>, batch: &BatchRecord) -> Option<SettledState> {
+    let state = replayer.settled_state(batch.batch_id);
+    if state.state_root == batch.post_state_root {
+        return Some(state);
+    }
+    let mut replayer = replayer.clone();
+    for entry in next.into_iter().flat_map(|record| record.entries.iter()) {
+        replayer.apply(entry).ok()?;
+        let state = replayer.settled_state(batch.batch_id);
+        if state.state_root == batch.post_state_root {
+            return Some(state);
+        }
+    }
+    None
+}
+
+/// What a sequencer boots with, rebuilt from its journals.
+pub struct BootState {
+    pub accounts: Vec<(Pubkey, AccountSharedData)>,
+    /// Batch id -> the accounts the state root of a batch that was still
+    /// settling, or the last one that settled, commits to.
+    pub batch_states: HashMap<u64, SettledState>,
+}
+
+/// The accounts a node boots with: the snapshot's, or the genesis accounts,
+/// with every block journaled after them replayed on top. A block that
+/// doesn't reproduce fails the boot, the node must not carry on from
+/// accounts its journal disagrees with. The snapshot's accounts and pending
+/// credits are taken, its counters are left for RollupDB.
+///
+/// The states `batches` were cut at are picked up on the way, a batch cut
+/// before the snapshot can't be rebuilt and is left out.
+pub fn boot(
+    genesis: &Genesis,
+    fees_per_signature: u64,
+    snapshot: Option<&mut StateSnapshot>,
+    journal: &BlockJournal,
+    batches: &[BatchRecord],
+) -> Result<BootState> {
+    let snapshot = snapshot.map(|snapshot| StateSnapshot {
+        accounts: mem::take(&mut snapshot.accounts),
+        pending_credits: mem::take(&mut snapshot.pending_credits),
+        next_slot: snapshot.next_slot,
+        last_block_hash: snapshot.last_block_hash,
+        ..Default::default()
+    });
+    let (mut replayer, slot, parent_hash) = starting_point(genesis, fees_per_signature, snapshot);
+    let records = journal.load().context("failed to load the block journal")?;
+    let mut batch_states = HashMap::new();
+    let at_boundary = |replayer: &Replayer, last_slot: Slot, next: Option<&BlockRecord>| {
+        for batch in batches.iter().filter(|batch| batch.last_slot == last_slot) {
+            if let Some(state) = batch_state(replayer, next, batch) {
+                batch_states.insert(batch.batch_id, state);
+            }
+        }
+    };
+    let (replayed, _) = replay_blocks(&mut replayer, slot, parent_hash, records, journal.path(), at_boundary)
+        .context("the block journal doesn't reproduce, refusing to start")?;
+    if replayed > 0 {
+        log::info!("Replayed {} journaled blocks from slot {}, state root {}", replayed, slot, replayer.state_root());
+    }
+    for batch in batches.iter().filter(|batch| !batch_states.contains_key(&batch.batch_id)) {
+        log::warn!("Can't rebuild the state batch {} was cut at, exit proofs wait for a later batch", batch.batch_id);
+    }
+    Ok(BootState {
+        accounts: replayer.accounts.into_iter().collect(),
+        batch_states,
+    })
+}
+
+/// `rollup_core replay`: re-executes the block journal on top of the genesis,
+/// or of a snapshot, and fails at the first block that doesn't reproduce.
+pub fn run(genesis: &Genesis, fees_per_signature: u64, blocks: &Path, snapshot: Option<&Path>) -> Result<()> {
+    let snapshot = snapshot.map(StateSnapshot::read).transpose()?;
+    let (mut replayer, slot, parent_hash) = starting_point(genesis, fees_per_signature, snapshot);
+    let records = BlockJournal::new(blocks.to_path_buf()).load()?;
+    let (replayed, slot) = replay_blocks(&mut replayer, slot, parent_hash, records, blocks, |_, _, _| {})?;
+
+    log::info!(
+        "Replayed {} blocks up to slot {}, {} accounts, state root {}",
+        replayed,
+        slot.saturating_sub(1),
+        replayer.accounts().len(),
+        replayer.state_root()
+    );
+    Ok(())
+}
@@ -1,220 +1,761 @@
-use async_channel::{Receiver, Sender};
+use async_channel::Sender;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
-use solana_client::rpc_client::RpcClient;
 use solana_sdk::{
-    account::AccountSharedData, hash::Hash, pubkey::Pubkey, transaction::Transaction, // keccak::Hash -> hash::Hash
+    account::{AccountSharedData, WritableAccount}, clock::Slot, hash::Hash, pubkey::Pubkey, signature::Signature, transaction::Transaction, // keccak::Hash -> hash::Hash
 };
 
-use crossbeam::channel::{Receiver as CBReceiver, Sender as CBSender};
+use crossbeam::channel::{Receiver as CBReceiver, RecvTimeoutError, Sender as CBSender};
 use std::{
-    collections::{HashMap, HashSet},
-    default, sync::{Arc, RwLock},
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+use crate::settle::{PendingSettlement, SettleProof, SettlementStatus};
+use crate::batch::{BatchJournal, BatchRecord};
+use crate::block::{Block, BlockEntry, BlockJournal, BlockRecord, SealBlock};
+use crate::loader::AccountOrigin;
+use crate::metrics::{metrics, Worker, HEARTBEAT_INTERVAL};
+use crate::plugin::Plugins;
+use crate::processor::ExecutionStatus;
+use crate::pubsub::PubsubEvent;
+use crate::da::BatchData;
+use crate::deposit_watcher::Deposit;
+use crate::escape_hatch::SettledState;
+use crate::genesis::Genesis;
+use crate::replay::{credit, unlock_writes, writes_hash, BootState};
+use crate::snapshot::{SnapshotWriter, StateSnapshot};
+use crate::state::RollupState;
+use crate::withdrawal::{
+    parse_withdrawal, Withdrawal, WithdrawalJournal, WithdrawalQuery, WithdrawalStatus, MAX_WITHDRAWALS_PER_BATCH,
 };
-use tokio::sync::oneshot;
-use crate::{delegation_service::DelegationService, frontend::FrontendMessage, settle::settle_state};
 use crate::bundler::*;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct RollupDBMessage {
     pub lock_accounts: Option<Vec<Pubkey>>,
+    pub unlock_accounts: Option<Vec<Pubkey>>, // locked for a transaction that never executed
     pub add_processed_transaction: Option<Transaction>,
     pub add_new_data: Option<Vec<(Pubkey, AccountSharedData)>>,
-    pub frontend_get_tx: Option<Hash>,
-    pub add_settle_proof: Option<String>,
+    pub add_settle_proof: Option<SettleProof>,
     pub get_account: Option<Pubkey>,
-    // pub response: Option<bool>, 
-      //Testing purposes
-      pub bundle_tx: bool
+    pub credit_deposit: Option<Deposit>,
+    pub add_withdrawal: Option<Transaction>,
+    pub l1_accounts: Option<Vec<(Pubkey, AccountSharedData, AccountOrigin)>>, // read by the sequencer for lock_accounts or add_withdrawal
+    pub delegated_amount: Option<u64>, // of add_withdrawal's owner, read by the sequencer
+    pub ack_inbox: Option<u64>,
+    pub seal_block: Option<SealBlock>,
+    pub execution_status: Option<ExecutionStatus>, // how add_processed_transaction executed
+    pub bundle_tx: bool
 }
 
-#[derive(Serialize, Debug, Default)]
+/// The only writer of the rollup's state. It serializes locking, unlocking
+/// and batching, while queries read `RollupState` directly.
 pub struct RollupDB {
-    accounts_db: HashMap<Pubkey, AccountSharedData>,
-    locked_accounts: HashMap<Pubkey, AccountSharedData>,
-    transactions: HashMap<Hash, Transaction>,
-    pda_mappings: HashMap<Pubkey, Pubkey>,  // user -> pda mapping
-    // async_ver_recv: Receiver<Option<bool>>
+    state: Arc<RollupState>,
+    plugins: Arc<Plugins>,
+    locked_accounts: HashSet<Pubkey>, // accounts of an in-flight tx, readers still see their last committed state
+    settling_batches: HashMap<u64, Vec<Hash>>, // batch id -> tx hashes waiting for L1 confirmation
+    next_batch_id: u64,
+    last_state_root: Hash,
+    next_slot: Slot,
+    last_block_hash: Hash, // the genesis hash until the first block
+    credited_deposits: HashSet<Signature>, // L1 signatures of deposits already credited
+    pending_credits: HashMap<Pubkey, u64>, // deposits for accounts locked by an in-flight tx
+    settling_states: HashMap<u64, SettledState>, // batch id -> accounts its state root commits to
+    inbox_index: u64, // inbox entries below this index were sequenced
+    batched_inbox_index: u64, // inbox index of the last batch
+    locked_mirrors: HashMap<Pubkey, (Slot, AccountSharedData)>, // L1 mirrors locked by an in-flight tx, as read
+    pending_clones: Vec<(Pubkey, Slot)>, // accounts cloned from L1 since the last batch
+    locked_l1_accounts: Vec<(Pubkey, AccountSharedData, AccountOrigin)>, // what the in-flight tx read from L1
+    pending_entries: Vec<BlockEntry>, // state changes since the last block, in commit order
+    snapshot_due: bool, // taken at the next block boundary
+    batch_blocks: Vec<BlockRecord>, // sealed since the last batch, published with the next one
+    batched_slot: Slot, // last block published by a batch
+    settling_blocks: HashMap<u64, Vec<BlockRecord>>, // batch id -> blocks it published, until it settles
+    settlement_stopped: bool, // the settlement worker exited, no more batches are cut
 }
 
 impl RollupDB {
+    #[allow(clippy::too_many_arguments)]
     pub async fn run(
         rollup_db_receiver: CBReceiver<RollupDBMessage>,
-        frontend_sender: Sender<FrontendMessage>,
+        state: Arc<RollupState>,
         account_sender: Sender<Option<Vec<(Pubkey, AccountSharedData)>>>,
         sender_locked_accounts: Sender<bool>,
-        delegation_service: Arc<RwLock<DelegationService>>,
+        settlement_sender: CBSender<PendingSettlement>,
+        batch_journal: BatchJournal,
+        block_journal: BlockJournal,
+        withdrawal_journal: WithdrawalJournal,
+        snapshot_writer: SnapshotWriter,
+        restored: Option<StateSnapshot>,
+        boot: BootState,
+        genesis: Genesis,
+        plugins: Arc<Plugins>,
     ) {
         let mut db = RollupDB {
-            accounts_db: HashMap::new(),
-            locked_accounts: HashMap::new(),
-            transactions: HashMap::new(),
-            pda_mappings: HashMap::new(),
+            state,
+            plugins,
+            locked_accounts: HashSet::new(),
+            settling_batches: HashMap::new(),
+            next_batch_id: 0,
+            last_state_root: Hash::default(),
+            next_slot: 1,
+            last_block_hash: genesis.hash,
+            credited_deposits: HashSet::new(),
+            pending_credits: HashMap::new(),
+            settling_states: HashMap::new(),
+            inbox_index: 0,
+            batched_inbox_index: 0,
+            locked_mirrors: HashMap::new(),
+            pending_clones: Vec::new(),
+            locked_l1_accounts: Vec::new(),
+            pending_entries: Vec::new(),
+            snapshot_due: false,
+            batch_blocks: Vec::new(),
+            batched_slot: 0,
+            settling_blocks: HashMap::new(),
+            settlement_stopped: false,
         };
-        while let Ok(message) = rollup_db_receiver.recv() {
-            log::info!("Received RollupDBMessage");
+        let batches = batch_journal.load().unwrap_or_else(|e| {
+            log::error!("Failed to load batch journal: {}", e);
+            BTreeMap::new()
+        });
+        let blocks = block_journal.load().unwrap_or_else(|e| {
+            log::error!("Failed to load block journal: {}", e);
+            BTreeMap::new()
+        });
+        db.restore_settling(&batches, &blocks, boot.batch_states);
+        match withdrawal_journal.load() {
+            Ok(withdrawals) => db.restore_withdrawals(withdrawals, &blocks),
+            Err(e) => log::error!("Failed to load withdrawal journal: {}", e),
+        }
+        db.restore_transactions(&batches, &blocks);
+        db.restore_batches(batches);
+        db.restore_blocks(blocks);
+        // Replayed up to the last journaled block before the node started
+        let accounts_version = restored.as_ref().map_or(0, |snapshot| snapshot.accounts_version);
+        if let Some(snapshot) = restored {
+            db.restore_snapshot(snapshot);
+        }
+        db.state.accounts.restore(accounts_version, boot.accounts);
+        let _running = metrics().running(Worker::RollupDb);
+        loop {
+            // Beats while idle too, so only a hung RollupDB goes stale
+            let message = match rollup_db_receiver.recv_timeout(HEARTBEAT_INTERVAL) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    metrics().beat(Worker::RollupDb);
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            metrics().beat(Worker::RollupDb);
+            log::debug!("Received RollupDBMessage");
             if let Some(accounts_to_lock) = message.lock_accounts {
                 let mut information_to_send: Vec<(Pubkey, AccountSharedData)> = Vec::new();
-                log::info!("locking: {:?}", db.accounts_db);
-                // Lock accounts, by removing them from the accounts_db hashmap, and adding them to locked accounts
+                // Lock accounts, they stay readable at their committed state until the tx is processed
                 for pubkey in accounts_to_lock.iter() {
-                    if let Some(account) = db.accounts_db.get(pubkey) {
-                        db.locked_accounts
-                        .insert(pubkey.clone(), db.accounts_db.remove(pubkey).unwrap());
-                        log::info!("account was found");
-                    }
-                    else {
-                        let rpc_client_temp = RpcClient::new("https://api.devnet.solana.com".to_string());
-                        let account = rpc_client_temp.get_account(pubkey).unwrap();
-                        let data: AccountSharedData = account.into();
-                        db.locked_accounts
-                        .insert(pubkey.clone(), data);
-                        log::info!("account was not found");
+                    let (account, origin) = db.load_account(message.l1_accounts.as_deref(), pubkey);
+                    log::info!("{} loaded as {:?}", pubkey, origin);
+                    match origin {
+                        AccountOrigin::Cloned { slot } => db.record_clone(*pubkey, slot),
+                        AccountOrigin::Mirrored { slot } => {
+                            db.locked_mirrors.insert(*pubkey, (slot, account.clone().unwrap()));
+                        }
+                        AccountOrigin::Local | AccountOrigin::NotFound => {}
                     }
-
-                    if let Some(account) = db.locked_accounts.get(&pubkey) {
-                        // account_sender.send(Some(account.clone())).await.unwrap();
-                        information_to_send.push((*pubkey, account.clone()));
+                    if let (Some(account), AccountOrigin::Cloned { .. } | AccountOrigin::Mirrored { .. }) = (&account, origin) {
+                        db.locked_l1_accounts.push((*pubkey, account.clone(), origin));
                     }
-                    else {
-                        // account_sender.send(None).await.unwrap();
-                        panic!()
+                    // Accounts that weren't found are locked too, the SVM
+                    // creates them if the transaction funds them
+                    db.locked_accounts.insert(*pubkey);
+                    if let Some(account) = account {
+                        information_to_send.push((*pubkey, account));
                     }
-
                 }
-                log::info!("locking done: {:?}", db.accounts_db);
-                log::info!("locked accounts done: {:?}", db.locked_accounts);
+                log::debug!("locked accounts done: {:?}", db.locked_accounts);
 
                 
-                log::info!("information to send -> {:?}", information_to_send);
+                log::debug!("information to send -> {:?}", information_to_send);
                 account_sender.send(Some(information_to_send)).await.unwrap();
-                // log::info!("2: {:#?}", db.locked_accounts);
-            } else if let Some(get_this_hash_tx) = message.frontend_get_tx {
-                log::info!("Getting tx for frontend");
-                let req_tx = db.transactions.get(&get_this_hash_tx).unwrap();
-
-                frontend_sender
-                    .send(FrontendMessage {
-                        transaction: Some(req_tx.clone()),
-                        get_tx: None,
-                    })
-                    .await
-                    .unwrap();
             } else if let Some(tx) = message.add_processed_transaction {
 
                 let processed_data = message.add_new_data.unwrap();
 
-                // unlocking accounts
-                let locked_keys = tx.message.account_keys.clone(); // get the keys
-                log::info!("it is starting accounts_db{:#?}", db.accounts_db);
-                log::info!("it is starting locked_db{:#?}", db.locked_accounts);
-                for (pubkey, data) in processed_data.iter() {
-                    db.locked_accounts.remove(pubkey).unwrap();
-                    db.accounts_db.insert(*pubkey, data.clone());
-                    log::info!("it is final accounts_db{:#?}", db.accounts_db);
-                    log::info!("it is final locked_db{:#?}", db.locked_accounts);
-                    
-
+                // unlocking accounts, all of the tx's writes become visible in one version
+                let mut credits = HashMap::new();
+                let mut mirrors = HashMap::new();
+                let mut mirror_slots = HashMap::new();
+                for (pubkey, _) in processed_data.iter() {
+                    db.locked_accounts.remove(pubkey);
+                    // Deposits that arrived while the account was locked
+                    if let Some(lamports) = db.pending_credits.remove(pubkey) {
+                        credits.insert(*pubkey, lamports);
+                    }
+                    if let Some((slot, mirrored)) = db.locked_mirrors.remove(pubkey) {
+                        mirrors.insert(*pubkey, mirrored);
+                        mirror_slots.insert(*pubkey, slot);
+                    }
                 }
-                // send transaction to the db.transactions
+                // Mirrors the transaction only read stay on L1, changed
+                // ones become rollup state as of the slot they were read at
+                let writes = unlock_writes(processed_data, &credits, &mirrors);
+                for (pubkey, _) in writes.iter() {
+                    if let Some(slot) = mirror_slots.get(pubkey) {
+                        db.record_clone(*pubkey, *slot);
+                    }
+                }
+                db.pending_entries.push(BlockEntry::Transaction {
+                    transaction: tx.clone(),
+                    l1_accounts: std::mem::take(&mut db.locked_l1_accounts),
+                    credits: credits.into_iter().collect(),
+                    writes_hash: writes_hash(&writes),
+                });
+                let status = message.execution_status.unwrap_or_default();
+                db.plugins.update_accounts(db.next_slot, &writes);
+                db.plugins.notify_transaction(db.next_slot, &tx, &status);
+                db.state.pubsub.publish(PubsubEvent::Accounts { slot: db.next_slot, accounts: writes.clone() });
+                db.state.pubsub.publish(PubsubEvent::Transaction {
+                    slot: db.next_slot,
+                    signature: tx.signatures[0],
+                    account_keys: tx.message.account_keys.clone(),
+                    logs: status.logs,
+                });
+                let version = db.state.accounts.commit(writes.into_iter().map(|(pubkey, account)| (pubkey, Some(account))).collect());
+                log::info!("committed accounts version {}", version);
+                log::debug!("locked: {:#?}", db.locked_accounts);
 
-                db.transactions.insert(tx.message.hash(), tx.clone());
-                log::info!("locked: {:#?}", db.locked_accounts);
-                log::info!("43210: {:#?}", db.accounts_db);
+                // send transaction to the db.transactions
+                db.state.add_transaction(tx);
 
                 // communication channel with database 
                 // communcation with the frontend 
             }
+            else if let Some(accounts) = message.unlock_accounts {
+                // Nothing was written, the accounts stay at their committed state
+                for pubkey in accounts.iter() {
+                    db.locked_accounts.remove(pubkey);
+                    db.locked_mirrors.remove(pubkey);
+                }
+                db.locked_l1_accounts.clear();
+                let state = &db.state;
+                db.pending_clones
+                    .retain(|(pubkey, _)| !accounts.contains(pubkey) || state.accounts.get(pubkey).is_some());
+                for pubkey in accounts {
+                    if let Some(lamports) = db.pending_credits.remove(&pubkey) {
+                        db.credit(pubkey, lamports, |writes_hash| BlockEntry::Deposit {
+                            owner: pubkey,
+                            lamports,
+                            writes_hash,
+                        });
+                    }
+                }
+            }
+            else if let Some(seal) = message.seal_block {
+                // Every transaction of the block was processed before this
+                // message, so the state root is the one right after them
+                let state_root = db.snapshot(db.next_batch_id).state_root;
+                let block = Block::new(db.next_slot, db.last_block_hash, seal.transactions, state_root, seal.timestamp);
+                let record = BlockRecord {
+                    block,
+                    entries: std::mem::take(&mut db.pending_entries),
+                };
+                if let Err(e) = block_journal.append(&record) {
+                    log::error!("Failed to journal block {}: {}", record.block.slot, e);
+                }
+                db.state.publish_block(&record);
+                db.batch_blocks.push(record.clone());
+                let block = record.block;
+                log::info!("Sealed block {} ({}) with {} transactions", block.slot, block.hash, block.transactions.len());
+                db.next_slot = block.slot + 1;
+                db.last_block_hash = block.hash;
+                db.plugins.notify_block(&block);
+                metrics().block_slot.set(block.slot as i64);
+                db.state.pubsub.publish(PubsubEvent::Slot { slot: block.slot });
+                db.state.put_block(block);
+                if db.snapshot_due {
+                    db.take_snapshot(&snapshot_writer);
+                }
+            }
             else if message.bundle_tx {
+                if db.settlement_stopped {
+                    log::warn!("Settlement worker is gone, not bundling");
+                    continue;
+                }
                 log::info!("BUNDLING TX");
+                // Transactions of batches that are still settling are not bundled again
+                let settling: HashSet<Hash> = db.settling_batches.values().flatten().copied().collect();
+                let mut batch_hashes = Vec::new();
+                let mut batch_signatures = Vec::new();
                 let mut tx_bundler = TransferBundler::new();
-                for (_, tx) in db.transactions.clone() {
+                for (hash, tx) in db.state.transactions() {
+                    if settling.contains(&hash) {
+                        continue;
+                    }
+                    batch_hashes.push(hash);
+                    batch_signatures.push(tx.signatures[0]);
                     tx_bundler.bundle(tx);
                 }
                 let final_ixs = tx_bundler.generate_final();
+                let batch_id = db.next_batch_id;
+                let withdrawals = db.state.take_pending_withdrawals(batch_id, MAX_WITHDRAWALS_PER_BATCH);
+                let settling: Vec<Withdrawal> = withdrawals
+                    .iter()
+                    .map(|withdrawal| Withdrawal { status: WithdrawalStatus::Settling { batch_id }, ..withdrawal.clone() })
+                    .collect();
+                journal_withdrawals(&withdrawal_journal, &settling);
+                if final_ixs.is_empty()
+                    && withdrawals.is_empty()
+                    && db.inbox_index == db.batched_inbox_index
+                    && db.batch_blocks.is_empty()
+                {
+                    log::info!("Nothing to settle, skipping bundle");
+                    continue;
+                }
 
-                let pubkey_user = &final_ixs[0].accounts[0].pubkey;
-                let del_service = delegation_service.read().unwrap();
-                let user_keypair = del_service.get_keypair(pubkey_user).unwrap();
+                log::info!("\nFinal Transfer Ixs:");
+                for ix in final_ixs.iter() {
+                    TransferBundler::parse_instruction(ix);
+                }
 
-                settle_state(&final_ixs , user_keypair).await.unwrap();
+                // Hand the batch to the settlement worker, the transactions are
+                // only cleared once it reports the batch as confirmed on L1
+                db.next_batch_id += 1;
+                metrics().batch_transactions.observe(batch_hashes.len() as f64);
+                db.settling_batches.insert(batch_id, batch_hashes);
 
-                log::info!("\nFinal Transfer Ixs:");
-                for ix in final_ixs{
-                    if let Some((from, to, amount)) = TransferBundler::parse_instruction(&ix){
+                let settling_state = db.snapshot(batch_id);
+                let post_state_root = settling_state.state_root;
+                db.settling_states.insert(batch_id, settling_state);
+
+                // Published to L1 ahead of the settlement, anyone can rebuild the state from it
+                let blocks = std::mem::take(&mut db.batch_blocks);
+                let last_slot = blocks.last().map_or(db.batched_slot, |record| record.block.slot);
+                let data = BatchData {
+                    batch_id,
+                    pre_state_root: db.last_state_root,
+                    post_state_root,
+                    inbox_index: db.inbox_index,
+                    blocks: blocks.iter().cloned().map(Into::into).collect(),
+                };
+                let da_data = match data.encode() {
+                    Ok(encoded) => base64::engine::general_purpose::STANDARD.encode(encoded),
+                    Err(e) => {
+                        log::error!("Failed to encode batch {} data: {}", batch_id, e);
+                        String::new()
+                    }
+                };
+                db.batched_slot = last_slot;
+                db.settling_blocks.insert(batch_id, blocks);
+                let record = BatchRecord {
+                    batch_id,
+                    transactions: batch_signatures,
+                    pre_state_root: db.last_state_root,
+                    post_state_root,
+                    netted_instructions: final_ixs.clone(),
+                    withdrawals: withdrawals.clone(),
+                    inbox_index: db.inbox_index,
+                    cloned_accounts: std::mem::take(&mut db.pending_clones),
+                    last_slot,
+                    da: None,
+                    challenge_ends_at: None,
+                    settlement_signatures: Vec::new(),
+                    status: SettlementStatus::Pending,
+                };
+                db.last_state_root = post_state_root;
+                db.batched_inbox_index = db.inbox_index;
+                db.record_batch(&batch_journal, record);
+                // Snapshots are taken on a block boundary, so replay can carry
+                // on from them with the next block
+                if snapshot_writer.due(batch_id) {
+                    db.snapshot_due = true;
+                    if db.pending_entries.is_empty() {
+                        db.take_snapshot(&snapshot_writer);
                     }
                 }
-                log::info!("BUNDLING DONE");
-                db.transactions.clear();
-
 
+                let sent = settlement_sender
+                    .send(PendingSettlement {
+                        batch_id,
+                        state_root: post_state_root,
+                        instructions: final_ixs,
+                        withdrawals,
+                        inbox_index: db.inbox_index,
+                        da_data,
+                        da: None,
+                        attempts: 0,
+                        signatures: Vec::new(),
+                        settled_slot: None,
+                        status: SettlementStatus::Pending,
+                    });
+                if let Err(e) = sent {
+                    // The batch stays pending in the journal, nothing more is settled
+                    log::error!("Settlement worker exited, batch {} was not queued: {}", batch_id, e);
+                    db.settlement_stopped = true;
+                    continue;
+                }
+                log::info!("BUNDLING DONE, batch {} queued for settlement", batch_id);
+            }
+            else if let Some(proof) = message.add_settle_proof {
+                if let Some(mut record) = db.state.batch(proof.batch_id) {
+                    record.settlement_signatures = proof.signatures.clone();
+                    record.da = proof.da.clone();
+                    record.challenge_ends_at = proof.challenge_ends_at;
+                    record.status = proof.status.clone();
+                    db.plugins.notify_settlement(&record);
+                    db.record_batch(&batch_journal, record);
+                }
+                let Some(batch_hashes) = db.settling_batches.remove(&proof.batch_id) else {
+                    log::warn!("Settlement result for unknown batch {}", proof.batch_id);
+                    continue;
+                };
+                match proof.status {
+                    SettlementStatus::Confirmed { signature } => {
+                        log::info!("Batch {} settled on L1: {}", proof.batch_id, signature);
+                        if let Some(record) = db.state.batch(proof.batch_id) {
+                            db.state.pubsub.publish(PubsubEvent::Settled { slot: record.last_slot, signatures: record.transactions });
+                        }
+                        if let Some(state) = db.settling_states.remove(&proof.batch_id) {
+                            db.state.set_settled_state(state);
+                        }
+                        db.settling_blocks.remove(&proof.batch_id);
+                        db.state.remove_transactions(&batch_hashes);
+                        let paid = db.state.update_withdrawals(proof.batch_id, WithdrawalStatus::Paid {
+                            batch_id: proof.batch_id,
+                            l1_signature: signature,
+                        });
+                        journal_withdrawals(&withdrawal_journal, &paid);
+                    }
+                    status => {
+                        // Leave the transactions in place, they go into the next batch. The
+                        // withdrawals are refunded, retrying one the PDA can't pay would fail every batch
+                        log::error!("Batch {} was not settled: {:?}", proof.batch_id, status);
+                        let refunded = db.state.update_withdrawals(proof.batch_id, WithdrawalStatus::Refunded {
+                            batch_id: proof.batch_id,
+                        });
+                        journal_withdrawals(&withdrawal_journal, &refunded);
+                        for withdrawal in refunded {
+                            db.credit(withdrawal.owner, withdrawal.amount, |writes_hash| BlockEntry::Refund {
+                                withdrawal: withdrawal.l2_signature,
+                                owner: withdrawal.owner,
+                                lamports: withdrawal.amount,
+                                writes_hash,
+                            });
+                            log::info!("Refunded withdrawal {} of {} lamports", withdrawal.l2_signature, withdrawal.amount);
+                        }
+                        db.settling_states.remove(&proof.batch_id);
+                        // Its blocks are published again, so the settled batches still cover every block
+                        if let Some(mut blocks) = db.settling_blocks.remove(&proof.batch_id) {
+                            if let Some(first) = blocks.first() {
+                                db.batched_slot = db.batched_slot.min(first.block.slot - 1);
+                            }
+                            blocks.append(&mut db.batch_blocks);
+                            blocks.sort_by_key(|record| record.block.slot);
+                            db.batch_blocks = blocks;
+                        }
+                    }
+                }
+            }
+            else if let Some(deposit) = message.credit_deposit {
+                if !db.credited_deposits.insert(deposit.l1_signature) {
+                    log::info!("Deposit {} was already credited", deposit.l1_signature);
+                    continue;
+                }
+                db.credit(deposit.owner, deposit.amount, |writes_hash| BlockEntry::Deposit {
+                    owner: deposit.owner,
+                    lamports: deposit.amount,
+                    writes_hash,
+                });
+                log::info!("Credited {} lamports to {} (L1 {})", deposit.amount, deposit.owner, deposit.l1_signature);
+            }
+            else if let Some(tx) = message.add_withdrawal {
+                let Some(mut withdrawal) = parse_withdrawal(&tx) else {
+                    log::warn!("{} is not a withdrawal", tx.signatures[0]);
+                    continue;
+                };
+                if db.state.has_withdrawal(&withdrawal.l2_signature) {
+                    log::info!("Withdrawal {} was already requested", withdrawal.l2_signature);
+                    continue;
+                }
+                let (account, origin) = db.load_account(message.l1_accounts.as_deref(), &withdrawal.owner);
+                let l1_account = match (&account, origin) {
+                    (Some(account), AccountOrigin::Cloned { .. } | AccountOrigin::Mirrored { .. }) => Some((account.clone(), origin)),
+                    _ => None,
+                };
+                let mut account = account.unwrap_or_default();
+                // Paid out of the owner's own PDA, less what is already on its way out
+                let payable = message
+                    .delegated_amount
+                    .unwrap_or(0)
+                    .saturating_sub(db.state.unpaid_withdrawals(&withdrawal.owner));
+                // Burn the amount on L2, it is paid out of the PDA when the batch settles
+                let burned = if withdrawal.amount > payable {
+                    Err(format!("its delegation PDA can only pay out {} more lamports", payable))
+                } else {
+                    account
+                        .checked_sub_lamports(withdrawal.amount)
+                        .map_err(|e| format!("insufficient rollup balance: {}", e))
+                };
+                let writes = match burned {
+                    Ok(()) => vec![(withdrawal.owner, account.clone())],
+                    Err(_) => Vec::new(),
+                };
+                db.pending_entries.push(BlockEntry::Withdrawal {
+                    transaction: tx.clone(),
+                    l1_account,
+                    payable,
+                    writes_hash: writes_hash(&writes),
+                });
+                match burned {
+                    Ok(()) => {
+                        if let AccountOrigin::Cloned { slot } | AccountOrigin::Mirrored { slot } = origin {
+                            db.record_clone(withdrawal.owner, slot);
+                        }
+                        db.plugins.update_accounts(db.next_slot, &writes);
+                        db.plugins.notify_transaction(db.next_slot, &tx, &ExecutionStatus::default());
+                        db.state.pubsub.publish(PubsubEvent::Accounts { slot: db.next_slot, accounts: writes });
+                        db.state.pubsub.publish(PubsubEvent::Transaction {
+                            slot: db.next_slot,
+                            signature: tx.signatures[0],
+                            account_keys: tx.message.account_keys.clone(),
+                            logs: Vec::new(),
+                        });
+                        db.state.accounts.commit(vec![(withdrawal.owner, Some(account))]);
+                        db.state.add_transaction(tx.clone());
+                        log::info!("Burned {} lamports of {} for withdrawal", withdrawal.amount, withdrawal.owner);
+                    }
+                    Err(reason) => {
+                        log::warn!("Rejected withdrawal {}: {}", withdrawal.l2_signature, reason);
+                        withdrawal.status = WithdrawalStatus::Rejected { reason };
+                    }
+                }
+                journal_withdrawals(&withdrawal_journal, std::slice::from_ref(&withdrawal));
+                db.state.add_withdrawal(withdrawal);
+            }
+            else if let Some(index) = message.ack_inbox {
+                // Sequenced, whether or not the transaction executed
+                db.inbox_index = db.inbox_index.max(index + 1);
             }
             else if let Some(pubkey) = message.get_account {
-                if db.locked_accounts.contains_key(&pubkey) {
+                if db.locked_accounts.contains(&pubkey) {
                     sender_locked_accounts.send(true).await.unwrap();
                 } else {
                     sender_locked_accounts.send(false).await.unwrap();
                 }
-                // if 
-            //     log::info!("4321: {:#?}", db.locked_accounts);
-            //     if let Some(account) = db.locked_accounts.get(&pubkey) {
-            //         account_sender.send(Some(account.clone())).await.unwrap();
-            //     }
-            //     else {
-            //         account_sender.send(None).await.unwrap();
-            //     }
             }
         }
     }
 
-    pub fn register_pda(&mut self, user: Pubkey, pda: Pubkey) {
-        self.pda_mappings.insert(user, pda);
+    /// Every account the rollup currently holds, including the ones locked by
+    /// an in-flight transaction at their last committed state, and the state
+    /// root over them.
+    fn snapshot(&self, batch_id: u64) -> SettledState {
+        let snapshot = self.state.accounts.snapshot();
+        SettledState::new(batch_id, snapshot.accounts())
     }
 
-    pub fn get_pda_for_user(&self, user: &Pubkey) -> Option<&Pubkey> {
-        self.pda_mappings.get(user)
+    fn record_batch(&mut self, journal: &BatchJournal, record: BatchRecord) {
+        if let Err(e) = journal.append(&record) {
+            log::error!("Failed to journal batch {}: {}", record.batch_id, e);
+        }
+        self.state.put_batch(record);
     }
-}
 
+    fn restore_batches(&mut self, batches: BTreeMap<u64, BatchRecord>) {
+        for record in batches.into_values() {
+            self.next_batch_id = record.batch_id + 1;
+            self.last_state_root = record.post_state_root;
+            self.inbox_index = record.inbox_index;
+            self.batched_inbox_index = record.inbox_index;
+            // A failed batch's blocks were left for the next one
+            if !matches!(record.status, SettlementStatus::Failed { .. }) {
+                self.batched_slot = self.batched_slot.max(record.last_slot);
+            }
+            self.state.put_batch(record);
+        }
+    }
+
+    /// Batches that were still settling when the node stopped. The settlement
+    /// worker resumes them from its own journal, and their results need the
+    /// transactions, blocks and state they were cut with. A batch's blocks
+    /// are the ones after the previous batch that didn't fail. The last
+    /// settled batch's state serves exit proofs again.
+    fn restore_settling(
+        &mut self,
+        batches: &BTreeMap<u64, BatchRecord>,
+        blocks: &BTreeMap<Slot, BlockRecord>,
+        mut states: HashMap<u64, SettledState>,
+    ) {
+        let hashes: HashMap<Signature, Hash> = blocks
+            .values()
+            .flat_map(|record| record.entries.iter())
+            .filter_map(|entry| match entry {
+                BlockEntry::Transaction { transaction, .. } => Some((transaction.signatures[0], transaction.message.hash())),
+                _ => None,
+            })
+            .collect();
+        let mut batched_slot = 0;
+        for record in batches.values() {
+            let first_slot = batched_slot + 1;
+            match record.status {
+                SettlementStatus::Failed { .. } => continue,
+                SettlementStatus::Confirmed { .. } => {}
+                SettlementStatus::Pending | SettlementStatus::Submitted { .. } => {
+                    let batch_hashes = record.transactions.iter().filter_map(|signature| hashes.get(signature)).copied().collect();
+                    self.settling_batches.insert(record.batch_id, batch_hashes);
+                    let batch_blocks = blocks.range(first_slot..=record.last_slot).map(|(_, block)| block.clone()).collect();
+                    self.settling_blocks.insert(record.batch_id, batch_blocks);
+                }
+            }
+            batched_slot = batched_slot.max(record.last_slot);
+        }
+        if let Some(settled) = batches.values().rev().find(|record| matches!(record.status, SettlementStatus::Confirmed { .. })) {
+            if let Some(state) = states.remove(&settled.batch_id) {
+                self.state.set_settled_state(state);
+            }
+        }
+        self.settling_states = states;
+        if !self.settling_batches.is_empty() {
+            log::info!("Batches {:?} are still settling", self.settling_batches.keys().collect::<Vec<_>>());
+        }
+    }
+
+    /// Withdrawals whose request made it into a journaled block. The others
+    /// were never burned as far as the restored accounts go.
+    fn restore_withdrawals(&mut self, withdrawals: Vec<Withdrawal>, blocks: &BTreeMap<Slot, BlockRecord>) {
+        let journaled: HashSet<Signature> = blocks.values().flat_map(|record| record.block.transactions.iter().copied()).collect();
+        for withdrawal in withdrawals {
+            if !journaled.contains(&withdrawal.l2_signature) {
+                log::warn!("Dropping withdrawal {}, its block was never sealed", withdrawal.l2_signature);
+                continue;
+            }
+            self.state.add_withdrawal(withdrawal);
+        }
+    }
+
+    /// Transactions, and burned withdrawals, that no settled batch included
+    /// yet. They go into the next batch, or clear when a settling one settles.
+    fn restore_transactions(&mut self, batches: &BTreeMap<u64, BatchRecord>, blocks: &BTreeMap<Slot, BlockRecord>) {
+        let settled: HashSet<Signature> = batches
+            .values()
+            .filter(|record| matches!(record.status, SettlementStatus::Confirmed { .. }))
+            .flat_map(|record| record.transactions.iter().copied())
+            .collect();
+        for entry in blocks.values().flat_map(|record| record.entries.iter()) {
+            let transaction = match entry {
+                BlockEntry::Transaction { transaction, .. } => transaction,
+                BlockEntry::Withdrawal { transaction, .. } => {
+                    let burned = self
+                        .state
+                        .withdrawals(&WithdrawalQuery::BySignature(transaction.signatures[0]))
+                        .iter()
+                        .any(|withdrawal| !matches!(withdrawal.status, WithdrawalStatus::Rejected { .. }));
+                    if !burned {
+                        continue;
+                    }
+                    transaction
+                }
+                BlockEntry::Deposit { .. } | BlockEntry::Refund { .. } => continue,
+            };
+            if !settled.contains(&transaction.signatures[0]) {
+                self.state.add_transaction(transaction.clone());
+            }
+        }
+    }
+
+    fn restore_blocks(&mut self, blocks: BTreeMap<Slot, BlockRecord>) {
+        for record in blocks.into_values() {
+            // Not published yet, they go out with the next batch
+            if record.block.slot > self.batched_slot {
+                self.batch_blocks.push(record.clone());
+            }
+            let block = record.block;
+            self.next_slot = block.slot + 1;
+            self.last_block_hash = block.hash;
+            self.state.put_block(block);
+        }
+    }
+
+    fn take_snapshot(&mut self, snapshot_writer: &SnapshotWriter) {
+        snapshot_writer.spawn(self.snapshot_meta(), self.state.accounts.snapshot());
+        self.snapshot_due = false;
+    }
+
+    /// Everything but the accounts, which the snapshot writer reads off a
+    /// store snapshot.
+    fn snapshot_meta(&self) -> StateSnapshot {
+        StateSnapshot {
+            next_slot: self.next_slot,
+            last_block_hash: self.last_block_hash,
+            next_batch_id: self.next_batch_id,
+            last_state_root: self.last_state_root,
+            inbox_index: self.inbox_index,
+            credited_deposits: self.credited_deposits.iter().copied().collect(),
+            pending_credits: self.pending_credits.iter().map(|(pubkey, lamports)| (*pubkey, *lamports)).collect(),
+            ..Default::default()
+        }
+    }
 
+    /// Boots from a snapshot's counters, its accounts and pending credits
+    /// were replayed into the boot accounts. The batch and block journals
+    /// are loaded first, and their counters are kept if they are ahead of
+    /// the snapshot.
+    fn restore_snapshot(&mut self, snapshot: StateSnapshot) {
+        log::info!("Restoring snapshot at slot {}, next batch {}", snapshot.next_slot, snapshot.next_batch_id);
+        self.credited_deposits.extend(snapshot.credited_deposits);
+        if snapshot.next_slot >= self.next_slot {
+            self.next_slot = snapshot.next_slot;
+            self.last_block_hash = snapshot.last_block_hash;
+        }
+        if snapshot.next_batch_id >= self.next_batch_id {
+            self.next_batch_id = snapshot.next_batch_id;
+            self.last_state_root = snapshot.last_state_root;
+            self.inbox_index = snapshot.inbox_index;
+            self.batched_inbox_index = snapshot.inbox_index;
+        }
+    }
 
-                    // if let Some(account) = db.locked_accounts.remove(&pubkey) {
-                    //     let data_for_the_account = data.get(pubkey).unwrap()
-                    //     db.accounts_db.insert(pubkey, data.get(pubkey).unwrap()); // Unlock and restore
-                    // }
-// accounts_to_lock
-                // .iter()
-                // .map(|pubkey| {
-                //     // match db.locked_accounts.get(pubkey) {
-                //     //     Some(account) => {
-                //     //         db.locked_accounts
-                //     //     .insert(pubkey.clone(), db.accounts_db.remove(pubkey).unwrap());
-                //     //     }
-                //     //     None => {
-                //     //     let rpc_client_temp = RpcClient::new("https://api.devnet.solana.com".to_string());
-                //     //     let account = rpc_client_temp.get_account(pubkey).unwrap();
-                //     //     let data: AccountSharedData = account.into();
-                //     //     db.locked_accounts
-                //     //     .insert(pubkey.clone(), data);
-                //     //     }
-                //     // }
-                //     log::info!("99999999999999999999999999999999");
-                //     if let Some(account) = db.accounts_db.get(pubkey) {
-                //         db.locked_accounts
-                //         .insert(pubkey.clone(), db.accounts_db.remove(pubkey).unwrap());
-                //         log::info!("111111111111111111111112");
-                //     }
-                //     else {
-                //         let rpc_client_temp = RpcClient::new("https://api.devnet.solana.com".to_string());
-                //         let account = rpc_client_temp.get_account(pubkey).unwrap();
-                //         let data: AccountSharedData = account.into();
-                //         db.locked_accounts
-                //         .insert(pubkey.clone(), data);
-                //     log::info!("2222222222222222222222222");
-                //     }
-                // });
-
-
-                // let _ = accounts_to_lock.iter().map(|pubkey| {
-                //     db.locked_accounts
-                //         .insert(pubkey.clone(), db.accounts_db.remove(pubkey).unwrap())
-                // });
+    /// An account through the hierarchy: rollup state, then what the
+    /// sequencer read from L1 for the message.
+    fn load_account(&self, l1_accounts: Option<&[(Pubkey, AccountSharedData, AccountOrigin)]>, pubkey: &Pubkey) -> (Option<AccountSharedData>, AccountOrigin) {
+        if let Some(account) = self.state.accounts.get(pubkey) {
+            return (Some(account), AccountOrigin::Local);
+        }
+        l1_accounts
+            .unwrap_or_default()
+            .iter()
+            .find(|(key, _, _)| key == pubkey)
+            .map_or((None, AccountOrigin::NotFound), |(_, account, origin)| (Some(account.clone()), *origin))
+    }
+
+    /// Adds `lamports` to `owner`, journaled as the entry `entry` builds from
+    /// the writes hash. While a transaction has the account locked the credit
+    /// waits for its unlock, which journals it with the transaction instead.
+    fn credit(&mut self, owner: Pubkey, lamports: u64, entry: impl FnOnce(Hash) -> BlockEntry) {
+        if self.locked_accounts.contains(&owner) {
+            // The in-flight transaction would overwrite the credit, apply it on unlock
+            *self.pending_credits.entry(owner).or_default() += lamports;
+            return;
+        }
+        let account = credit(self.state.accounts.get(&owner), lamports);
+        self.pending_entries.push(entry(writes_hash(&[(owner, account.clone())])));
+        self.plugins.update_accounts(self.next_slot, &[(owner, account.clone())]);
+        self.state.pubsub.publish(PubsubEvent::Accounts { slot: self.next_slot, accounts: vec![(owner, account.clone())] });
+        self.state.accounts.commit(vec![(owner, Some(account))]);
+    }
+
+    fn record_clone(&mut self, pubkey: Pubkey, slot: Slot) {
+        log::info!("Cloned {} from L1 at slot {}", pubkey, slot);
+        self.pending_clones.push((pubkey, slot));
+    }
+}
+
+fn journal_withdrawals(journal: &WithdrawalJournal, withdrawals: &[Withdrawal]) {
+    if withdrawals.is_empty() {
+        return;
+    }
+    if let Err(e) = journal.append(withdrawals) {
+        log::error!("Failed to journal {} withdrawal(s): {}", withdrawals.len(), e);
+    }
+}
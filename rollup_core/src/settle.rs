@@ -1,44 +1,481 @@
-use anyhow::Result;
-use async_channel::Receiver;
-use solana_client::rpc_client::RpcClient;
-use solana_sdk::{hash::Hash, instruction::Instruction, message::Message, signature::Keypair, transaction::Transaction};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-// Settle the state on solana, called by sequencer
-pub async fn settle_state(vec_of_instructions: &Vec<Instruction>, signer: &Keypair) -> Result<String> {
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use crossbeam::channel::{Receiver as CBReceiver, RecvTimeoutError, Sender as CBSender};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    clock::Slot, commitment_config::CommitmentConfig, hash::{hashv, Hash}, instruction::{AccountMeta, Instruction}, pubkey::Pubkey, signature::Signature, transaction::Transaction,
+};
 
-    println!("Settling state");
+use crate::{
+    da::{DaCommitment, DataAvailability},
+    metrics::{metrics, Worker},
+    delegation::{create_post_state_root_instruction, create_settle_withdrawal_instruction, has_rollup_instructions}, l1_client::L1Client, rollupdb::RollupDBMessage,
+    signer::OperatorSigner, withdrawal::Withdrawal,
+};
 
-    let rpc_client = RpcClient::new("https://api.devnet.solana.com".to_string());
+pub const MEMO_PROGRAM_ID: Pubkey = solana_sdk::pubkey!("MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr");
 
-    let ix = vec_of_instructions[0].clone();
-    let payer = &ix.accounts[0].pubkey;
+/// Settings for the settlement worker. Everything that used to be hard-coded
+/// in `settle_state` lives here.
+#[derive(Debug, Clone)]
+pub struct SettlementConfig {
+    /// Commitment a settlement transaction must reach before the batch is
+    /// considered settled and cleared from RollupDB.
+    pub commitment: CommitmentConfig,
+    /// How many times a batch is (re)sent before it is given up on.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How often submitted transactions are polled for their status.
+    pub poll_interval: Duration,
+    /// L1 slots after its settlement landed during which a batch can be challenged.
+    pub challenge_period_slots: u64,
+    /// File the pending settlements are persisted to, so they survive a restart.
+    pub journal_path: PathBuf,
+}
+
+impl Default for SettlementConfig {
+    fn default() -> Self {
+        Self {
+            commitment: CommitmentConfig::confirmed(),
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            poll_interval: Duration::from_millis(500),
+            challenge_period_slots: 216_000,
+            journal_path: PathBuf::from("pending_settlements.json"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SettlementStatus {
+    /// Waiting to be (re)sent with a fresh blockhash.
+    Pending,
+    /// Sent to L1, waiting for the configured commitment.
+    Submitted { signature: String, last_valid_block_height: u64 },
+    Confirmed { signature: String },
+    Failed { reason: String },
+}
+
+impl SettlementStatus {
+    /// Handed to the settlement worker, no result yet.
+    pub fn is_settling(&self) -> bool {
+        matches!(self, Self::Pending | Self::Submitted { .. })
+    }
+}
+
+/// A batch that still has to land on L1. The netted instructions are the
+/// batch's transfers, what is posted on L1 is a commitment to them and to the
+/// post state root, signed by the operator, together with the payouts of the
+/// batch's withdrawals.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingSettlement {
+    pub batch_id: u64,
+    #[serde(default)]
+    pub state_root: Hash,
+    pub instructions: Vec<Instruction>,
+    #[serde(default)]
+    pub withdrawals: Vec<Withdrawal>,
+    #[serde(default)]
+    pub inbox_index: u64,
+    /// The batch's encoded data, base64, published before the settlement transaction.
+    #[serde(default)]
+    pub da_data: String,
+    /// Where the data was published, set once it is.
+    #[serde(default)]
+    pub da: Option<DaCommitment>,
+    pub attempts: u32,
+    /// Every signature the batch has been sent with so far.
+    #[serde(default)]
+    pub signatures: Vec<String>,
+    /// L1 slot the settlement landed in, once confirmed.
+    #[serde(default)]
+    pub settled_slot: Option<Slot>,
+    pub status: SettlementStatus,
+}
+
+/// Sent back to RollupDB once a batch reached a final status.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SettleProof {
+    pub batch_id: u64,
+    #[serde(default)]
+    pub da: Option<DaCommitment>,
+    /// Last L1 slot the batch's state root can be challenged in.
+    #[serde(default)]
+    pub challenge_ends_at: Option<Slot>,
+    pub signatures: Vec<String>,
+    pub status: SettlementStatus,
+}
+
+/// Drives pending settlements to L1 on its own thread, so RPC hiccups never
+/// stall or panic the RollupDB loop.
+pub struct SettlementWorker {
+    config: SettlementConfig,
+    l1_client: Arc<dyn L1Client>,
+    pending: BTreeMap<u64, PendingSettlement>,
+    retry_at: HashMap<u64, Instant>,
+    queued_at: HashMap<u64, Instant>, // batch id -> when it was queued, or resumed after a restart
+    operator: OperatorSigner,
+    data_availability: Arc<dyn DataAvailability>,
+    rollupdb_sender: CBSender<RollupDBMessage>,
+}
+
+impl SettlementWorker {
+    pub fn new(
+        config: SettlementConfig,
+        l1_client: Arc<dyn L1Client>,
+        operator: OperatorSigner,
+        data_availability: Arc<dyn DataAvailability>,
+        rollupdb_sender: CBSender<RollupDBMessage>,
+    ) -> Self {
+        Self {
+            l1_client,
+            config,
+            pending: BTreeMap::new(),
+            retry_at: HashMap::new(),
+            queued_at: HashMap::new(),
+            operator,
+            data_availability,
+            rollupdb_sender,
+        }
+    }
+
+    /// Blocking loop, meant to be run on a dedicated thread.
+    pub fn run(
+        settlement_receiver: CBReceiver<PendingSettlement>,
+        rollupdb_sender: CBSender<RollupDBMessage>,
+        l1_client: Arc<dyn L1Client>,
+        operator: OperatorSigner,
+        data_availability: Arc<dyn DataAvailability>,
+        config: SettlementConfig,
+    ) {
+        let mut worker = Self::new(config, l1_client, operator, data_availability, rollupdb_sender);
+        let _running = metrics().running(Worker::Settlement);
+        if let Err(e) = worker.load_journal() {
+            log::error!("Failed to load settlement journal: {}", e);
+        }
+
+        loop {
+            metrics().beat(Worker::Settlement);
+            match settlement_receiver.recv_timeout(worker.config.poll_interval) {
+                Ok(settlement) => {
+                    log::info!("Queued batch {} for settlement", settlement.batch_id);
+                    worker.queued_at.insert(settlement.batch_id, Instant::now());
+                    worker.pending.insert(settlement.batch_id, settlement);
+                    worker.persist_journal();
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    if worker.pending.is_empty() {
+                        break;
+                    }
+                    std::thread::sleep(worker.config.poll_interval);
+                }
+            }
+            worker.drive();
+        }
+    }
+
+    fn load_journal(&mut self) -> Result<()> {
+        if !self.config.journal_path.exists() {
+            return Ok(());
+        }
+        let bytes = fs::read(&self.config.journal_path)?;
+        let pending: Vec<PendingSettlement> = serde_json::from_slice(&bytes)?;
+        log::info!("Resuming {} pending settlement(s)", pending.len());
+        for settlement in pending {
+            self.queued_at.insert(settlement.batch_id, Instant::now());
+            self.pending.insert(settlement.batch_id, settlement);
+        }
+        Ok(())
+    }
+
+    fn persist_journal(&self) {
+        let pending: Vec<&PendingSettlement> = self.pending.values().collect();
+        let tmp_path = self.config.journal_path.with_extension("tmp");
+        let result = serde_json::to_vec_pretty(&pending)
+            .map_err(|e| anyhow!(e))
+            .and_then(|bytes| fs::write(&tmp_path, bytes).map_err(|e| anyhow!(e)))
+            .and_then(|_| fs::rename(&tmp_path, &self.config.journal_path).map_err(|e| anyhow!(e)));
+        if let Err(e) = result {
+            log::error!("Failed to persist settlement journal: {}", e);
+        }
+    }
 
-    let ixs = vec_of_instructions.as_slice();
-    let recent_blockhash = rpc_client.get_latest_blockhash().unwrap();
-    log::info!("Almost settled stateee: {:?}", recent_blockhash);
+    /// Batches are settled strictly in order, so only the oldest one is driven.
+    fn drive(&mut self) {
+        self.drive_at(Instant::now());
+    }
 
+    /// `drive` with the clock at `now`, backoffs are timed from it.
+    fn drive_at(&mut self, now: Instant) {
+        let Some(batch_id) = self.pending.keys().next().copied() else {
+            return;
+        };
+        if let Some(retry_at) = self.retry_at.get(&batch_id) {
+            if now < *retry_at {
+                return;
+            }
+        }
 
-    let tx = Transaction::new_signed_with_payer(
-        ixs, 
-        Some(payer), 
-        &[signer], 
-        recent_blockhash,
+        let mut settlement = self.pending.get(&batch_id).unwrap().clone();
+        let step = match &settlement.status {
+            SettlementStatus::Pending => self.submit(&mut settlement),
+            SettlementStatus::Submitted { .. } => self.check(&mut settlement),
+            SettlementStatus::Confirmed { .. } | SettlementStatus::Failed { .. } => Ok(()),
+        };
+
+        if let Err(e) = step {
+            log::warn!("Settlement of batch {} hit an RPC error: {}", batch_id, e);
+            settlement.attempts += 1;
+            self.back_off(batch_id, settlement.attempts, now);
+        }
+        if settlement.attempts >= self.config.max_attempts
+            && settlement.status == SettlementStatus::Pending
+        {
+            settlement.status = SettlementStatus::Failed {
+                reason: format!("gave up after {} attempts", settlement.attempts),
+            };
+        }
+
+        match &settlement.status {
+            SettlementStatus::Confirmed { .. } | SettlementStatus::Failed { .. } => {
+                self.finish(settlement);
+            }
+            _ => {
+                self.pending.insert(batch_id, settlement);
+            }
+        }
+        self.persist_journal();
+    }
+
+    fn back_off(&mut self, batch_id: u64, attempts: u32, now: Instant) {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        let delay = self.config.initial_backoff.saturating_mul(factor).min(self.config.max_backoff);
+        self.retry_at.insert(batch_id, now + delay);
+    }
+
+    fn submit(&self, settlement: &mut PendingSettlement) -> Result<()> {
+        let operator = self.operator.try_pubkey()?;
+        // The data goes first, a settled batch is always available
+        if settlement.da.is_none() && !settlement.da_data.is_empty() {
+            let data = base64::engine::general_purpose::STANDARD.decode(&settlement.da_data)?;
+            let commitment = self.data_availability.publish(settlement.batch_id, &data)?;
+            log::info!("Published batch {} data ({} bytes) to {}", settlement.batch_id, data.len(), commitment);
+            settlement.da = Some(commitment);
+        }
+
+        let ixs = settlement_instructions(&operator, settlement);
+
+        let (recent_blockhash, last_valid_block_height) = self.l1_client.get_latest_blockhash()?;
+
+        let mut tx = Transaction::new_with_payer(&ixs, Some(&operator));
+        tx.try_sign(&[&self.operator], recent_blockhash)?;
+
+        log::info!("Settling batch {} (attempt {})", settlement.batch_id, settlement.attempts + 1);
+        let signature = self.l1_client.send_transaction(&tx)?;
+        settlement.attempts += 1;
+        settlement.signatures.push(signature.to_string());
+        settlement.status = SettlementStatus::Submitted {
+            signature: signature.to_string(),
+            last_valid_block_height,
+        };
+        Ok(())
+    }
+
+    fn check(&self, settlement: &mut PendingSettlement) -> Result<()> {
+        let SettlementStatus::Submitted { signature, last_valid_block_height } = &settlement.status else {
+            return Ok(());
+        };
+        let parsed: Signature = signature.parse()?;
+
+        if let Some(status) = self.l1_client.get_signature_status(&parsed)? {
+            if let Some(err) = &status.err {
+                settlement.status = SettlementStatus::Failed { reason: err.to_string() };
+            } else if status.satisfies(self.config.commitment) {
+                log::info!("Settled batch {}: {}", settlement.batch_id, signature);
+                settlement.settled_slot = Some(status.slot);
+                settlement.status = SettlementStatus::Confirmed { signature: signature.clone() };
+            }
+            return Ok(());
+        }
+
+        // Not seen yet, resend with a fresh blockhash once the old one expired
+        let block_height = self.l1_client.get_block_height()?;
+        if block_height > *last_valid_block_height {
+            log::warn!("Blockhash for batch {} expired, resending", settlement.batch_id);
+            settlement.status = SettlementStatus::Pending;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, settlement: PendingSettlement) {
+        self.pending.remove(&settlement.batch_id);
+        self.retry_at.remove(&settlement.batch_id);
+        let queued_at = self.queued_at.remove(&settlement.batch_id);
+        match &settlement.status {
+            SettlementStatus::Failed { reason } => {
+                log::error!("Settlement of batch {} failed: {}", settlement.batch_id, reason);
+                metrics().settlements.with_label_values(&["failed"]).inc();
+            }
+            _ => {
+                if let Some(queued_at) = queued_at {
+                    metrics().settlement_seconds.observe(queued_at.elapsed().as_secs_f64());
+                }
+                metrics().settlements.with_label_values(&["confirmed"]).inc();
+            }
+        }
+        let message = RollupDBMessage {
+            add_settle_proof: Some(SettleProof {
+                batch_id: settlement.batch_id,
+                da: settlement.da,
+                challenge_ends_at: settlement
+                    .settled_slot
+                    .map(|slot| slot + self.config.challenge_period_slots),
+                signatures: settlement.signatures,
+                status: settlement.status,
+            }),
+            ..Default::default()
+        };
+        if self.rollupdb_sender.send(message).is_err() {
+            log::error!("RollupDB is gone, dropping settlement result");
+        }
+    }
+}
+
+/// The L1 side of a settlement. The batch's netted transfers are committed
+/// to, not executed: L2 accounts can't sign on L1 and the program can't move
+/// lamports between PDAs. Every PDA keeps what its owner deposited, so
+/// withdrawals and forced exits are paid out of the owner's own PDA and are
+/// capped at it, and received lamports don't add to the allowance.
+///
+/// The transaction starts with an operator-signed memo that commits to the
+/// batch id, the post state root, a digest of the netted instructions and
+/// withdrawals, and where the batch data was published. It then posts the
+/// state root to the delegation program, which forced exits are proven
+/// against, and pays out each withdrawal. Without the program's rollup
+/// instructions it is only the memo, and the sequencer takes no withdrawals.
+pub fn settlement_instructions(operator: &Pubkey, settlement: &PendingSettlement) -> Vec<Instruction> {
+    let netted = bincode::serialize(&settlement.instructions).unwrap();
+    let payouts: Vec<(Pubkey, u64)> = settlement
+        .withdrawals
+        .iter()
+        .map(|withdrawal| (withdrawal.owner, withdrawal.amount))
+        .collect();
+    let payouts_bytes = bincode::serialize(&payouts).unwrap();
+    let memo = format!(
+        "rollup-batch:{}:{}:{}:{}",
+        settlement.batch_id,
+        settlement.state_root,
+        hashv(&[&netted, &payouts_bytes]),
+        settlement.da.as_ref().map(ToString::to_string).unwrap_or_default(),
+    );
+    let mut ixs = vec![Instruction {
+        program_id: MEMO_PROGRAM_ID,
+        accounts: vec![AccountMeta::new_readonly(*operator, true)],
+        data: memo.into_bytes(),
+    }];
+    if !has_rollup_instructions() {
+        return ixs;
+    }
+    ixs.push(create_post_state_root_instruction(
+        operator,
+        settlement.batch_id,
+        settlement.state_root.to_bytes(),
+        settlement.inbox_index,
+    ));
+    ixs.extend(
+        payouts
+            .iter()
+            .map(|(owner, amount)| create_settle_withdrawal_instruction(operator, owner, *amount)),
     );
+    ixs
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::signature::Keypair;
 
-    log::info!("Almost settled state: {:?}", tx);
+    use super::*;
+    use crate::{da::FileDa, mock_l1::MockL1};
 
-    // let tx = Transaction::new(from_keypairs, message, recent_blockhash)
+    fn pending(batch_id: u64) -> PendingSettlement {
+        PendingSettlement {
+            batch_id,
+            state_root: Hash::default(),
+            instructions: Vec::new(),
+            withdrawals: Vec::new(),
+            inbox_index: 0,
+            da_data: base64::engine::general_purpose::STANDARD.encode(b"batch"),
+            da: None,
+            attempts: 0,
+            signatures: Vec::new(),
+            settled_slot: None,
+            status: SettlementStatus::Pending,
+        }
+    }
 
-    // let message = Message::new_with_compiled_instructions(num_required_signatures, num_readonly_signed_accounts, num_readonly_unsigned_accounts, account_keys, recent_blockhash, instructions)
+    #[test]
+    fn backs_off_between_attempts_and_gives_up() {
+        let dir = std::env::temp_dir().join(format!("rollup-settle-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // Publishing the batch data fails on every attempt
+        let da_dir = dir.join("da");
+        fs::write(&da_dir, b"not a directory").unwrap();
+        let config = SettlementConfig {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(80),
+            journal_path: dir.join("pending_settlements.json"),
+            ..Default::default()
+        };
+        let (rollupdb_sender, rollupdb_receiver) = crossbeam::channel::unbounded();
+        let mut worker = SettlementWorker::new(
+            config,
+            Arc::new(MockL1::new()),
+            Arc::new(Keypair::new()),
+            Arc::new(FileDa::new(da_dir)),
+            rollupdb_sender,
+        );
+        worker.pending.insert(0, pending(0));
 
-    // Create proof transaction, calling the right function in the contract
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        worker.drive_at(at(0));
+        assert_eq!(worker.pending[&0].attempts, 1);
+        assert_eq!(worker.retry_at[&0], at(50));
+        // Not retried before the backoff is over
+        worker.drive_at(at(49));
+        assert_eq!(worker.pending[&0].attempts, 1);
 
-    // Send transaction to contract on chain
-    let settle_tx_hash = rpc_client
-        .send_and_confirm_transaction(&tx)
-        // .await?;
-        .unwrap();
+        worker.drive_at(at(50));
+        assert_eq!(worker.pending[&0].attempts, 2);
+        // Doubled, capped at the max backoff
+        assert_eq!(worker.retry_at[&0], at(130));
+        worker.drive_at(at(129));
+        assert_eq!(worker.pending[&0].attempts, 2);
+        assert!(rollupdb_receiver.is_empty());
 
-    log::info!("Settled state: {}", settle_tx_hash.to_string());
-    Ok(settle_tx_hash.to_string())
+        worker.drive_at(at(130));
+        assert!(worker.pending.is_empty());
+        let proof = rollupdb_receiver.try_recv().unwrap().add_settle_proof.unwrap();
+        assert_eq!(proof.batch_id, 0);
+        assert!(proof.signatures.is_empty());
+        assert_eq!(
+            proof.status,
+            SettlementStatus::Failed {
+                reason: "gave up after 3 attempts".to_string()
+            }
+        );
+    }
 }
@@ -0,0 +1,232 @@
+//! Where the operator's signing key comes from. The node only ever signs
+//! with its own operator key, users sign their own transactions.
+//!
+//! Three sources are supported:
+//! - a Solana CLI keypair file,
+//! - a local keystore, the keypair encrypted with a password,
+//! - a remote signer, an HTTP service that holds the key and signs on request.
+
+use std::{fs, path::Path, sync::Arc};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::rand_core::RngCore,
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use solana_sdk::{
+    pubkey::Pubkey,
+    signature::{read_keypair_file, Keypair, Signature},
+    signer::{Signer, SignerError},
+};
+
+/// The operator's signer, shared between the threads that need to sign.
+pub type OperatorSigner = Arc<dyn Signer + Send + Sync>;
+
+const KEYSTORE_VERSION: u32 = 1;
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+/// On-disk format of an encrypted keypair.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeystoreFile {
+    pub version: u32,
+    pub pubkey: String,
+    pub kdf: String,
+    pub rounds: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+impl KeystoreFile {
+    pub fn encrypt(keypair: &Keypair, password: &str) -> Result<Self> {
+        Self::encrypt_with_rounds(keypair, password, PBKDF2_ROUNDS)
+    }
+
+    fn encrypt_with_rounds(keypair: &Keypair, password: &str, rounds: u32) -> Result<Self> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = keystore_cipher(password, &salt, rounds)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, keypair.to_bytes().as_ref())
+            .map_err(|_| anyhow!("failed to encrypt keypair"))?;
+
+        Ok(Self {
+            version: KEYSTORE_VERSION,
+            pubkey: keypair.pubkey().to_string(),
+            kdf: "pbkdf2-sha256".to_string(),
+            rounds,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    pub fn decrypt(&self, password: &str) -> Result<Keypair> {
+        if self.version != KEYSTORE_VERSION || self.kdf != "pbkdf2-sha256" {
+            return Err(anyhow!("unsupported keystore version {} ({})", self.version, self.kdf));
+        }
+        let cipher = keystore_cipher(password, &hex::decode(&self.salt)?, self.rounds)?;
+        let nonce = hex::decode(&self.nonce)?;
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&nonce), hex::decode(&self.ciphertext)?.as_ref())
+            .map_err(|_| anyhow!("wrong keystore password"))?;
+        let keypair = Keypair::try_from(plaintext.as_slice())?;
+        if keypair.pubkey().to_string() != self.pubkey {
+            return Err(anyhow!("keystore pubkey does not match its keypair"));
+        }
+        Ok(keypair)
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read keystore {}", path.display()))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+fn keystore_cipher(password: &str, salt: &[u8], rounds: u32) -> Result<Aes256Gcm> {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut key);
+    Aes256Gcm::new_from_slice(&key).map_err(|_| anyhow!("invalid keystore key length"))
+}
+
+// Remote signer protocol: POST <url>/sign with a `SignRequest`, the service
+// answers with a `SignResponse` containing the signature for `pubkey`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignRequest {
+    pub pubkey: String,
+    /// Hex encoded message bytes
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignResponse {
+    /// Base58 encoded signature
+    pub signature: String,
+}
+
+/// Signs through a remote service, the key never touches this node.
+///
+/// Uses a blocking HTTP client, so it must not be called from inside an
+/// async context.
+pub struct RemoteSigner {
+    url: String,
+    pubkey: Pubkey,
+    client: reqwest::blocking::Client,
+}
+
+impl RemoteSigner {
+    pub fn new(url: &str, pubkey: Pubkey) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            pubkey,
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+}
+
+impl Signer for RemoteSigner {
+    fn try_pubkey(&self) -> std::result::Result<Pubkey, SignerError> {
+        Ok(self.pubkey)
+    }
+
+    fn try_sign_message(&self, message: &[u8]) -> std::result::Result<Signature, SignerError> {
+        let response: SignResponse = self
+            .client
+            .post(format!("{}/sign", self.url))
+            .json(&SignRequest {
+                pubkey: self.pubkey.to_string(),
+                message: hex::encode(message),
+            })
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| SignerError::Connection(e.to_string()))?
+            .json()
+            .map_err(|e| SignerError::Protocol(e.to_string()))?;
+        let signature: Signature = response
+            .signature
+            .parse()
+            .map_err(|_| SignerError::Protocol("invalid signature".to_string()))?;
+        if !signature.verify(self.pubkey.as_ref(), message) {
+            return Err(SignerError::Protocol("remote signer returned a bad signature".to_string()));
+        }
+        Ok(signature)
+    }
+
+    fn is_interactive(&self) -> bool {
+        false
+    }
+}
+
+pub fn keypair_file_signer(path: &Path) -> Result<OperatorSigner> {
+    let keypair = read_keypair_file(path)
+        .map_err(|e| anyhow!("failed to read keypair {}: {}", path.display(), e))?;
+    Ok(Arc::new(keypair))
+}
+
+pub fn keystore_signer(path: &Path, password: &str) -> Result<OperatorSigner> {
+    Ok(Arc::new(KeystoreFile::read(path)?.decrypt(password)?))
+}
+
+pub fn remote_signer(url: &str, pubkey: Pubkey) -> OperatorSigner {
+    Arc::new(RemoteSigner::new(url, pubkey))
+}
+
+/// `rollup_core keystore`: encrypts a keypair file with the password from
+/// ROLLUP_KEYSTORE_PASSWORD.
+pub fn create_keystore(keypair_path: &Path, out: &Path) -> Result<()> {
+    let password = std::env::var("ROLLUP_KEYSTORE_PASSWORD")
+        .context("set ROLLUP_KEYSTORE_PASSWORD to the keystore password")?;
+    let keypair = read_keypair_file(keypair_path)
+        .map_err(|e| anyhow!("failed to read keypair {}: {}", keypair_path.display(), e))?;
+    KeystoreFile::encrypt(&keypair, &password)?.write(out)?;
+    println!("Wrote keystore for {} to {}", keypair.pubkey(), out.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fewer rounds than a real keystore, decrypt reads them from the file
+    const TEST_ROUNDS: u32 = 1000;
+
+    #[test]
+    fn keystore_round_trips_through_its_file() {
+        let keypair = Keypair::new();
+        let path = std::env::temp_dir().join(format!("rollup-keystore-{}.json", std::process::id()));
+        KeystoreFile::encrypt_with_rounds(&keypair, "hunter2", TEST_ROUNDS).unwrap().write(&path).unwrap();
+
+        let keystore = KeystoreFile::read(&path).unwrap();
+        assert_eq!(keystore.pubkey, keypair.pubkey().to_string());
+        assert_eq!(keystore.decrypt("hunter2").unwrap().to_bytes(), keypair.to_bytes());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn keystore_rejects_a_wrong_password_or_a_swapped_pubkey() {
+        let keystore = KeystoreFile::encrypt_with_rounds(&Keypair::new(), "hunter2", TEST_ROUNDS).unwrap();
+        assert_eq!(keystore.decrypt("hunter3").unwrap_err().to_string(), "wrong keystore password");
+
+        let swapped = KeystoreFile {
+            pubkey: Keypair::new().pubkey().to_string(),
+            ..keystore
+        };
+        assert!(swapped.decrypt("hunter2").is_err());
+
+        let unsupported = KeystoreFile {
+            version: KEYSTORE_VERSION + 1,
+            ..swapped
+        };
+        assert!(unsupported.decrypt("hunter2").unwrap_err().to_string().starts_with("unsupported keystore version"));
+    }
+}
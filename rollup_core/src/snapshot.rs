@@ -0,0 +1,247 @@
+//! Point-in-time snapshots of the rollup's state. An archive is a short
+//! uncompressed header (magic and format version) followed by the
+//! zstd-compressed bincode of a `StateSnapshot`. A fresh node booted from one
+//! (`--restore-snapshot`) starts out with the snapshot's accounts and counters.
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{account::AccountSharedData, clock::Slot, hash::Hash, pubkey::Pubkey, signature::Signature};
+
+use crate::state::Snapshot;
+
+const MAGIC: &[u8; 4] = b"RSNP";
+pub const FORMAT_VERSION: u32 = 2;
+const COMPRESSION_LEVEL: i32 = 3;
+const FILE_PREFIX: &str = "snapshot-";
+const FILE_EXTENSION: &str = "rsnap";
+
+/// Everything a node needs to carry on from where the snapshot was taken.
+/// The rollup executes with a fixed blockhash, so there is no blockhash
+/// queue to capture, blocks carry on from `last_block_hash`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StateSnapshot {
+    /// Version of the account store the accounts were read at.
+    pub accounts_version: u64,
+    pub next_slot: Slot,
+    pub last_block_hash: Hash,
+    pub next_batch_id: u64,
+    pub last_state_root: Hash,
+    pub inbox_index: u64,
+    /// L1 signatures of deposits already credited.
+    pub credited_deposits: Vec<Signature>,
+    /// Deposits for accounts that were locked, not yet in `accounts`.
+    pub pending_credits: Vec<(Pubkey, u64)>,
+    pub accounts: Vec<(Pubkey, AccountSharedData)>,
+}
+
+impl StateSnapshot {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        // Written next to the target and renamed, a crash never leaves a
+        // truncated archive under the final name
+        let tmp_path = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        let mut encoder = zstd::Encoder::new(file, COMPRESSION_LEVEL)?;
+        bincode::serialize_into(&mut encoder, self)?;
+        encoder.finish()?.flush()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut file = BufReader::new(
+            File::open(path).with_context(|| format!("failed to open snapshot {}", path.display()))?,
+        );
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(anyhow!("{} is not a rollup snapshot", path.display()));
+        }
+        let mut version = [0u8; 4];
+        file.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(anyhow!(
+                "snapshot {} has format version {}, this node reads version {}",
+                path.display(),
+                version,
+                FORMAT_VERSION
+            ));
+        }
+        let decoder = zstd::Decoder::new(file)?;
+        bincode::deserialize_from(decoder).with_context(|| format!("corrupt snapshot {}", path.display()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    pub dir: PathBuf,
+    /// A snapshot is taken every this many batches, 0 disables them.
+    pub interval_batches: u64,
+    /// Snapshots kept in `dir`, older ones are deleted.
+    pub keep: usize,
+}
+
+/// Takes snapshots for RollupDB. Reading the accounts, compressing and
+/// writing happen on a separate thread, off a store snapshot, so RollupDB
+/// keeps committing meanwhile.
+pub struct SnapshotWriter {
+    config: SnapshotConfig,
+}
+
+impl SnapshotWriter {
+    pub fn new(config: SnapshotConfig) -> Self {
+        Self { config }
+    }
+
+    /// Whether a snapshot is due after bundling `batch_id`.
+    pub fn due(&self, batch_id: u64) -> bool {
+        self.config.interval_batches > 0 && (batch_id + 1).is_multiple_of(self.config.interval_batches)
+    }
+
+    /// Writes `snapshot` with the accounts of `accounts` filled in.
+    pub fn spawn(&self, mut snapshot: StateSnapshot, accounts: Snapshot) {
+        let config = self.config.clone();
+        thread::spawn(move || {
+            snapshot.accounts_version = accounts.version();
+            snapshot.accounts = accounts.accounts();
+            drop(accounts);
+            let path = config
+                .dir
+                .join(format!("{}{:020}.{}", FILE_PREFIX, snapshot.next_slot, FILE_EXTENSION));
+            let result = fs::create_dir_all(&config.dir)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| snapshot.write(&path));
+            match result {
+                Ok(()) => {
+                    log::info!(
+                        "Snapshot of {} accounts at slot {} written to {}",
+                        snapshot.accounts.len(),
+                        snapshot.next_slot,
+                        path.display()
+                    );
+                    if let Err(e) = prune(&config.dir, config.keep) {
+                        log::warn!("Failed to delete old snapshots: {}", e);
+                    }
+                }
+                Err(e) => log::error!("Failed to write snapshot {}: {}", path.display(), e),
+            }
+        });
+    }
+}
+
+// File names are zero-padded by slot, so they sort oldest first
+fn prune(dir: &Path, keep: usize) -> Result<()> {
+    let mut snapshots: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension().is_some_and(|extension| extension == FILE_EXTENSION)
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(FILE_PREFIX))
+        })
+        .collect();
+    snapshots.sort();
+    let excess = snapshots.len().saturating_sub(keep);
+    for path in snapshots.into_iter().take(excess) {
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rollup-snapshot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn snapshot() -> StateSnapshot {
+        StateSnapshot {
+            accounts_version: 7,
+            next_slot: 42,
+            last_block_hash: Hash::new_unique(),
+            next_batch_id: 3,
+            last_state_root: Hash::new_unique(),
+            inbox_index: 5,
+            credited_deposits: vec![Signature::new_unique()],
+            pending_credits: vec![(Pubkey::new_unique(), 100)],
+            accounts: (1..=3)
+                .map(|lamports| (Pubkey::new_unique(), AccountSharedData::new(lamports, 16, &Pubkey::new_unique())))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn snapshots_round_trip_through_their_archive() {
+        let dir = temp_dir("round-trip");
+        let path = dir.join("state.rsnap");
+        let snapshot = snapshot();
+        snapshot.write(&path).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let read = StateSnapshot::read(&path).unwrap();
+        assert_eq!(bincode::serialize(&read).unwrap(), bincode::serialize(&snapshot).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_foreign_and_newer_archives() {
+        let dir = temp_dir("rejects");
+        let path = dir.join("state.rsnap");
+        snapshot().write(&path).unwrap();
+        let archive = fs::read(&path).unwrap();
+
+        let mut foreign = archive.clone();
+        foreign[..4].copy_from_slice(b"NOPE");
+        fs::write(&path, &foreign).unwrap();
+        assert!(StateSnapshot::read(&path).unwrap_err().to_string().contains("is not a rollup snapshot"));
+
+        let mut newer = archive.clone();
+        newer[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, &newer).unwrap();
+        assert!(StateSnapshot::read(&path).unwrap_err().to_string().contains("format version"));
+
+        fs::write(&path, &archive[..archive.len() / 2]).unwrap();
+        assert!(StateSnapshot::read(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prunes_the_oldest_snapshots() {
+        let dir = temp_dir("prune");
+        for slot in [3u64, 10, 200] {
+            fs::write(dir.join(format!("{}{:020}.{}", FILE_PREFIX, slot, FILE_EXTENSION)), b"").unwrap();
+        }
+        fs::write(dir.join("other.rsnap"), b"").unwrap();
+        prune(&dir, 2).unwrap();
+
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            vec![
+                "other.rsnap".to_string(),
+                format!("{}{:020}.{}", FILE_PREFIX, 10, FILE_EXTENSION),
+                format!("{}{:020}.{}", FILE_PREFIX, 200, FILE_EXTENSION),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
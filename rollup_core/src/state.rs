@@ -0,0 +1,510 @@
+//! The rollup's state, shared between RollupDB, which writes it, and the RPC
+//! frontend, which reads it without going through RollupDB's message loop.
+//!
+//! Accounts live in a sharded, multi-version store: every commit creates a
+//! new version, and a `Snapshot` keeps reading the version it was taken at
+//! while later commits go on, so queries and batch state roots see a
+//! consistent state without blocking execution.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, VecDeque},
+    hash::{Hash as _, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use solana_sdk::{account::AccountSharedData, clock::Slot, hash::Hash, pubkey::Pubkey, signature::Signature, transaction::Transaction};
+
+use crate::{
+    batch::{BatchQuery, BatchRecord},
+    block::{Block, BlockQuery, BlockRecord},
+    escape_hatch::{ExitProof, SettledState},
+    pubsub::PubsubHub,
+    withdrawal::{Withdrawal, WithdrawalQuery, WithdrawalStatus},
+};
+
+pub const ACCOUNT_SHARDS: usize = 16;
+/// Sealed blocks a follower's stream can fall behind by before it is dropped.
+pub const BLOCK_FEED_CAPACITY: usize = 1_024;
+
+// Versions of one account, oldest first. `None` marks a deleted account.
+type Versions = VecDeque<(u64, Option<AccountSharedData>)>;
+
+/// Sharded multi-version account map. Writers lock only the shards they
+/// touch, readers read at a version and never wait for a commit to finish.
+pub struct AccountStore {
+    shards: Vec<RwLock<HashMap<Pubkey, Versions>>>,
+    /// Latest version whose writes are all visible.
+    committed: AtomicU64,
+    /// Versions open snapshots read at, and how many read at each. Older
+    /// versions they still need are kept until they are dropped.
+    readers: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl Default for AccountStore {
+    fn default() -> Self {
+        Self {
+            shards: (0..ACCOUNT_SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            committed: AtomicU64::new(0),
+            readers: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl AccountStore {
+    fn shard_index(pubkey: &Pubkey) -> usize {
+        let mut hasher = DefaultHasher::new();
+        pubkey.hash(&mut hasher);
+        hasher.finish() as usize % ACCOUNT_SHARDS
+    }
+
+    pub fn version(&self) -> u64 {
+        self.committed.load(Ordering::Acquire)
+    }
+
+    /// Latest committed state of an account.
+    pub fn get(&self, pubkey: &Pubkey) -> Option<AccountSharedData> {
+        // The version is read under the shard lock. Pruning needs the write
+        // lock and never drops the newest version at or below `committed`, so
+        // the one read here can't disappear before it is looked up
+        let shard = self.shards[Self::shard_index(pubkey)].read().unwrap();
+        Self::latest_at(shard.get(pubkey)?, self.version())
+    }
+
+    fn latest_at(versions: &Versions, version: u64) -> Option<AccountSharedData> {
+        versions
+            .iter()
+            .rev()
+            .find(|(written_at, _)| *written_at <= version)
+            .and_then(|(_, account)| account.clone())
+    }
+
+    /// A consistent view of the latest committed state.
+    pub fn snapshot(self: &Arc<Self>) -> Snapshot {
+        let mut readers = self.readers.lock().unwrap();
+        let version = self.version();
+        *readers.entry(version).or_default() += 1;
+        Snapshot {
+            store: self.clone(),
+            version,
+        }
+    }
+
+    /// Applies the writes as one new version, `None` deletes an account.
+    /// Commits are expected from a single writer, RollupDB or the follower.
+    pub fn commit(&self, writes: Vec<(Pubkey, Option<AccountSharedData>)>) -> u64 {
+        if writes.is_empty() {
+            return self.version();
+        }
+        let version = self.version() + 1;
+        let mut by_shard: BTreeMap<usize, Vec<(Pubkey, Option<AccountSharedData>)>> = BTreeMap::new();
+        for (pubkey, account) in writes {
+            by_shard.entry(Self::shard_index(&pubkey)).or_default().push((pubkey, account));
+        }
+
+        // Invisible to readers until `committed` moves, so shards can be
+        // written one at a time
+        let mut written = Vec::new();
+        for (index, writes) in by_shard {
+            let mut shard = self.shards[index].write().unwrap();
+            for (pubkey, account) in writes {
+                shard.entry(pubkey).or_default().push_back((version, account));
+                written.push(pubkey);
+            }
+        }
+
+        let oldest_read = {
+            let readers = self.readers.lock().unwrap();
+            self.committed.store(version, Ordering::Release);
+            readers.keys().next().copied().unwrap_or(version).min(version)
+        };
+        self.prune(&written, oldest_read);
+        version
+    }
+
+    /// Loads restored accounts into a store nothing was committed to yet, as
+    /// of `version`.
+    pub fn restore(&self, version: u64, accounts: Vec<(Pubkey, AccountSharedData)>) {
+        for (pubkey, account) in accounts {
+            let mut shard = self.shards[Self::shard_index(&pubkey)].write().unwrap();
+            shard.insert(pubkey, VecDeque::from([(version, Some(account))]));
+        }
+        self.committed.store(version, Ordering::Release);
+    }
+
+    /// Drops the versions of `pubkeys` no reader at or after `oldest_read`
+    /// can see anymore.
+    fn prune(&self, pubkeys: &[Pubkey], oldest_read: u64) {
+        for pubkey in pubkeys {
+            let mut shard = self.shards[Self::shard_index(pubkey)].write().unwrap();
+            let Some(versions) = shard.get_mut(pubkey) else {
+                continue;
+            };
+            while versions.len() > 1 && versions[1].0 <= oldest_read {
+                versions.pop_front();
+            }
+            if versions.len() == 1 && versions[0].1.is_none() && versions[0].0 <= oldest_read {
+                shard.remove(pubkey);
+            }
+        }
+    }
+
+    fn accounts_at(&self, version: u64) -> Vec<(Pubkey, AccountSharedData)> {
+        let mut accounts = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            accounts.extend(
+                shard
+                    .iter()
+                    .filter_map(|(pubkey, versions)| Some((*pubkey, Self::latest_at(versions, version)?))),
+            );
+        }
+        accounts
+    }
+}
+
+/// The account store as of one version.
+pub struct Snapshot {
+    store: Arc<AccountStore>,
+    version: u64,
+}
+
+impl Snapshot {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Every account, in no particular order.
+    pub fn accounts(&self) -> Vec<(Pubkey, AccountSharedData)> {
+        self.store.accounts_at(self.version)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut readers = self.store.readers.lock().unwrap();
+        if let Some(count) = readers.get_mut(&self.version) {
+            *count -= 1;
+            if *count == 0 {
+                readers.remove(&self.version);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct BatchIndex {
+    batches: BTreeMap<u64, BatchRecord>,
+    tx_batches: HashMap<Signature, u64>, // tx signature -> batch that contained it
+}
+
+#[derive(Default)]
+struct BlockIndex {
+    blocks: BTreeMap<Slot, Block>,
+    tx_blocks: HashMap<Signature, Slot>, // tx signature -> block that contained it
+}
+
+#[derive(Default)]
+struct WithdrawalIndex {
+    withdrawals: BTreeMap<u64, Withdrawal>, // in the order they were requested
+    ids: HashMap<Signature, u64>, // L2 signature -> withdrawal
+}
+
+/// Everything the frontend can query. Each part has its own lock, held only
+/// for the duration of a single read or write.
+#[derive(Default)]
+pub struct RollupState {
+    pub accounts: Arc<AccountStore>,
+    transactions: RwLock<HashMap<Hash, Transaction>>,
+    batches: RwLock<BatchIndex>,
+    blocks: RwLock<BlockIndex>,
+    withdrawals: RwLock<WithdrawalIndex>,
+    settled_state: RwLock<Arc<SettledState>>, // state of the last batch confirmed on L1
+    block_feed: Mutex<Vec<async_channel::Sender<BlockRecord>>>, // streams of sealed blocks to followers
+    pub pubsub: PubsubHub, // commit, block and settlement events for WebSocket subscribers
+}
+
+impl RollupState {
+    pub fn transaction(&self, hash: &Hash) -> Option<Transaction> {
+        self.transactions.read().unwrap().get(hash).cloned()
+    }
+
+    /// Transactions not yet confirmed on L1, by message hash.
+    pub fn transactions(&self) -> Vec<(Hash, Transaction)> {
+        self.transactions
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(hash, tx)| (*hash, tx.clone()))
+            .collect()
+    }
+
+    pub fn add_transaction(&self, tx: Transaction) {
+        self.transactions.write().unwrap().insert(tx.message.hash(), tx);
+    }
+
+    pub fn remove_transactions(&self, hashes: &[Hash]) {
+        let mut transactions = self.transactions.write().unwrap();
+        for hash in hashes {
+            transactions.remove(hash);
+        }
+    }
+
+    pub fn batches(&self, query: &BatchQuery) -> Vec<BatchRecord> {
+        let index = self.batches.read().unwrap();
+        match query {
+            BatchQuery::ById(batch_id) => index.batches.get(batch_id).cloned().into_iter().collect(),
+            BatchQuery::ByTransaction(signature) => index
+                .tx_batches
+                .get(signature)
+                .and_then(|batch_id| index.batches.get(batch_id))
+                .cloned()
+                .into_iter()
+                .collect(),
+            BatchQuery::Latest(limit) => index.batches.values().rev().take(*limit).cloned().collect(),
+        }
+    }
+
+    pub fn batch(&self, batch_id: u64) -> Option<BatchRecord> {
+        self.batches.read().unwrap().batches.get(&batch_id).cloned()
+    }
+
+    pub fn put_batch(&self, record: BatchRecord) {
+        let mut index = self.batches.write().unwrap();
+        for signature in record.transactions.iter() {
+            index.tx_batches.insert(*signature, record.batch_id);
+        }
+        index.batches.insert(record.batch_id, record);
+    }
+
+    pub fn blocks(&self, query: &BlockQuery) -> Vec<Block> {
+        let index = self.blocks.read().unwrap();
+        match query {
+            BlockQuery::BySlot(slot) => index.blocks.get(slot).cloned().into_iter().collect(),
+            BlockQuery::ByTransaction(signature) => index
+                .tx_blocks
+                .get(signature)
+                .and_then(|slot| index.blocks.get(slot))
+                .cloned()
+                .into_iter()
+                .collect(),
+            BlockQuery::Latest(limit) => index.blocks.values().rev().take(*limit).cloned().collect(),
+        }
+    }
+
+    pub fn put_block(&self, block: Block) {
+        let mut index = self.blocks.write().unwrap();
+        for signature in block.transactions.iter() {
+            index.tx_blocks.insert(*signature, block.slot);
+        }
+        index.blocks.insert(block.slot, block);
+    }
+
+    pub fn withdrawals(&self, query: &WithdrawalQuery) -> Vec<Withdrawal> {
+        let index = self.withdrawals.read().unwrap();
+        match query {
+            WithdrawalQuery::BySignature(signature) => index
+                .ids
+                .get(signature)
+                .and_then(|id| index.withdrawals.get(id))
+                .cloned()
+                .into_iter()
+                .collect(),
+            WithdrawalQuery::ByOwner(owner) => index
+                .withdrawals
+                .values()
+                .filter(|withdrawal| withdrawal.owner == *owner)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn has_withdrawal(&self, l2_signature: &Signature) -> bool {
+        self.withdrawals.read().unwrap().ids.contains_key(l2_signature)
+    }
+
+    pub fn add_withdrawal(&self, withdrawal: Withdrawal) {
+        let mut index = self.withdrawals.write().unwrap();
+        let id = index.withdrawals.keys().next_back().map_or(0, |id| id + 1);
+        index.ids.insert(withdrawal.l2_signature, id);
+        index.withdrawals.insert(id, withdrawal);
+    }
+
+    /// Up to `limit` pending withdrawals, oldest first, marked as settling
+    /// in `batch_id`.
+    pub fn take_pending_withdrawals(&self, batch_id: u64, limit: usize) -> Vec<Withdrawal> {
+        let mut index = self.withdrawals.write().unwrap();
+        index
+            .withdrawals
+            .values_mut()
+            .filter(|withdrawal| withdrawal.status == WithdrawalStatus::Pending)
+            .take(limit)
+            .map(|withdrawal| {
+                let pending = withdrawal.clone();
+                withdrawal.status = WithdrawalStatus::Settling { batch_id };
+                pending
+            })
+            .collect()
+    }
+
+    /// Moves the withdrawals settling in `batch_id` to `status`, returning them.
+    pub fn update_withdrawals(&self, batch_id: u64, status: WithdrawalStatus) -> Vec<Withdrawal> {
+        let mut updated = Vec::new();
+        for withdrawal in self.withdrawals.write().unwrap().withdrawals.values_mut() {
+            if withdrawal.status == (WithdrawalStatus::Settling { batch_id }) {
+                withdrawal.status = status.clone();
+                updated.push(withdrawal.clone());
+            }
+        }
+        updated
+    }
+
+    /// Lamports of `owner`'s withdrawals that were burned but not paid out yet.
+    pub fn unpaid_withdrawals(&self, owner: &Pubkey) -> u64 {
+        let index = self.withdrawals.read().unwrap();
+        index
+            .withdrawals
+            .values()
+            .filter(|withdrawal| withdrawal.owner == *owner)
+            .filter(|withdrawal| matches!(withdrawal.status, WithdrawalStatus::Pending | WithdrawalStatus::Settling { .. }))
+            .map(|withdrawal| withdrawal.amount)
+            .sum()
+    }
+
+    pub fn exit_proof(&self, owner: &Pubkey) -> Option<ExitProof> {
+        // The proof is built outside the lock, off the shared state
+        let settled_state = self.settled_state.read().unwrap().clone();
+        settled_state.exit_proof(owner)
+    }
+
+    pub fn set_settled_state(&self, state: SettledState) {
+        *self.settled_state.write().unwrap() = Arc::new(state);
+    }
+
+    /// Blocks sealed from now on, with their entries.
+    pub fn subscribe_blocks(&self) -> async_channel::Receiver<BlockRecord> {
+        let (sender, receiver) = async_channel::bounded(BLOCK_FEED_CAPACITY);
+        self.block_feed.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Hands a sealed block to every subscriber. One that is closed or fell
+    /// too far behind is dropped, a follower reconnects and catches up from
+    /// the journal.
+    pub fn publish_block(&self, record: &BlockRecord) {
+        self.block_feed
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.try_send(record.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use solana_sdk::account::ReadableAccount;
+    use solana_sdk_ids::system_program;
+
+    use super::*;
+
+    fn account(lamports: u64) -> AccountSharedData {
+        AccountSharedData::new(lamports, 0, &system_program::id())
+    }
+
+    fn lamports(account: Option<AccountSharedData>) -> Option<u64> {
+        account.map(|account| account.lamports())
+    }
+
+    fn versions_kept(store: &AccountStore, pubkey: &Pubkey) -> usize {
+        let shard = store.shards[AccountStore::shard_index(pubkey)].read().unwrap();
+        shard.get(pubkey).map_or(0, |versions| versions.len())
+    }
+
+    #[test]
+    fn snapshots_read_the_version_they_were_taken_at() {
+        let store = Arc::new(AccountStore::default());
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        assert_eq!(store.commit(vec![(a, Some(account(1))), (b, Some(account(10)))]), 1);
+        let before = store.snapshot();
+
+        assert_eq!(store.commit(vec![(a, Some(account(2)))]), 2);
+        assert_eq!(store.commit(vec![(b, None)]), 3);
+        assert_eq!(store.commit(Vec::new()), 3);
+
+        assert_eq!(lamports(store.get(&a)), Some(2));
+        assert_eq!(store.get(&b), None);
+        assert_eq!(before.version(), 1);
+        let mut accounts: Vec<u64> = before.accounts().into_iter().map(|(_, account)| account.lamports()).collect();
+        accounts.sort();
+        assert_eq!(accounts, vec![1, 10]);
+        assert_eq!(store.snapshot().accounts(), vec![(a, account(2))]);
+    }
+
+    #[test]
+    fn readers_never_see_a_partial_commit() {
+        // Spread over shards, so a commit writes several before it is visible
+        let pubkeys: Vec<Pubkey> = (0..64).map(|_| Pubkey::new_unique()).collect();
+        let store = Arc::new(AccountStore::default());
+        store.commit(pubkeys.iter().map(|pubkey| (*pubkey, Some(account(0)))).collect());
+
+        let writer = {
+            let (store, pubkeys) = (store.clone(), pubkeys.clone());
+            thread::spawn(move || {
+                for lamports in 1..=200 {
+                    store.commit(pubkeys.iter().map(|pubkey| (*pubkey, Some(account(lamports)))).collect());
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || loop {
+                    let snapshot = store.snapshot();
+                    let accounts = snapshot.accounts();
+                    assert_eq!(accounts.len(), 64);
+                    // Every account was written by every commit, so all of
+                    // them hold the version's value
+                    let expected = snapshot.version() - 1;
+                    assert!(accounts.iter().all(|(_, account)| account.lamports() == expected));
+                    if expected == 200 {
+                        break;
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn prunes_versions_no_snapshot_reads() {
+        let store = Arc::new(AccountStore::default());
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        store.commit(vec![(a, Some(account(1))), (b, Some(account(1)))]);
+        store.commit(vec![(a, Some(account(2)))]);
+        assert_eq!(versions_kept(&store, &a), 1);
+
+        // Kept while a snapshot reads the older version
+        let snapshot = store.snapshot();
+        store.commit(vec![(a, Some(account(3))), (b, None)]);
+        assert_eq!(versions_kept(&store, &a), 2);
+        assert_eq!(versions_kept(&store, &b), 2);
+        let mut read = snapshot.accounts();
+        read.sort_by_key(|(pubkey, _)| *pubkey != a);
+        assert_eq!(read, vec![(a, account(2)), (b, account(1))]);
+
+        // The next commit after the snapshot is dropped prunes what it
+        // touches, deleted accounts go entirely
+        drop(snapshot);
+        store.commit(vec![(a, Some(account(4))), (b, None)]);
+        assert_eq!(versions_kept(&store, &a), 1);
+        assert_eq!(versions_kept(&store, &b), 0);
+        assert_eq!(lamports(store.get(&a)), Some(4));
+        assert_eq!(store.get(&b), None);
+    }
+}
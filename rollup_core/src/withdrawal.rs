@@ -0,0 +1,127 @@
+//! Withdrawals from the rollup back to L1. A user signs an L2 transaction with
+//! a single delegation program `withdraw` instruction, the amount is burned
+//! from their rollup balance, and the next batch pays it out of their
+//! delegation PDA on L1 when it settles. Only what the PDA still holds can
+//! be withdrawn, and a batch that fails to settle refunds its withdrawals.
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{pubkey::Pubkey, signature::Signature, transaction::Transaction};
+
+use crate::delegation::{find_delegation_pda, get_delegation_program_id, DelegationInstruction, WithdrawArgs};
+
+/// Payouts per settlement transaction, the rest waits for the next batch so
+/// the transaction stays under the packet size limit.
+pub const MAX_WITHDRAWALS_PER_BATCH: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum WithdrawalStatus {
+    /// Burned on L2, waiting to be included in a batch.
+    Pending,
+    /// Included in a batch that is being settled.
+    Settling { batch_id: u64 },
+    /// Paid out on L1 by the batch's settlement transaction.
+    Paid { batch_id: u64, l1_signature: String },
+    /// Never burned, the balance was left untouched.
+    Rejected { reason: String },
+    /// Its batch failed to settle, the amount was credited back on L2.
+    Refunded { batch_id: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Withdrawal {
+    pub owner: Pubkey,
+    pub amount: u64,
+    /// Signature of the L2 transaction that requested it.
+    pub l2_signature: Signature,
+    pub status: WithdrawalStatus,
+}
+
+// message format used by the frontend to look up withdrawals
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WithdrawalQuery {
+    BySignature(Signature),
+    ByOwner(Pubkey),
+}
+
+/// The withdrawal requested by an L2 transaction, if it is one: a single
+/// `withdraw` instruction for the signer's own delegation PDA.
+pub fn parse_withdrawal(tx: &Transaction) -> Option<Withdrawal> {
+    let [ix] = tx.message.instructions.as_slice() else {
+        return None;
+    };
+    let account_keys = &tx.message.account_keys;
+    if account_keys.get(ix.program_id_index as usize) != Some(&get_delegation_program_id()) {
+        return None;
+    }
+    let Ok(DelegationInstruction::Withdraw(WithdrawArgs { amount })) = DelegationInstruction::decode(&ix.data) else {
+        return None;
+    };
+
+    let owner_index = *ix.accounts.first()?;
+    if !tx.message.is_signer(owner_index as usize) {
+        return None;
+    }
+    let owner = *account_keys.get(owner_index as usize)?;
+    let pda = *account_keys.get(*ix.accounts.get(1)? as usize)?;
+    if pda != find_delegation_pda(&owner).0 {
+        return None;
+    }
+
+    Some(Withdrawal {
+        owner,
+        amount,
+        l2_signature: tx.signatures[0],
+        status: WithdrawalStatus::Pending,
+    })
+}
+
+/// Append-only JSON-lines file of withdrawals. A line is appended every time
+/// a withdrawal's status changes, the last line for a signature wins on load.
+pub struct WithdrawalJournal {
+    path: PathBuf,
+}
+
+impl WithdrawalJournal {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn append(&self, withdrawals: &[Withdrawal]) -> Result<()> {
+        let mut lines = Vec::new();
+        for withdrawal in withdrawals {
+            serde_json::to_writer(&mut lines, withdrawal)?;
+            lines.push(b'\n');
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(&lines)?;
+        Ok(())
+    }
+
+    /// The withdrawals in the order they were requested, at their last status.
+    pub fn load(&self) -> Result<Vec<Withdrawal>> {
+        let mut withdrawals = Vec::new();
+        if !self.path.exists() {
+            return Ok(withdrawals);
+        }
+        let mut ids = HashMap::new();
+        for line in fs::read_to_string(&self.path)?.lines().filter(|line| !line.trim().is_empty()) {
+            let withdrawal: Withdrawal = serde_json::from_str(line)?;
+            match ids.get(&withdrawal.l2_signature) {
+                Some(&id) => withdrawals[id] = withdrawal,
+                None => {
+                    ids.insert(withdrawal.l2_signature, withdrawals.len());
+                    withdrawals.push(withdrawal);
+                }
+            }
+        }
+        Ok(withdrawals)
+    }
+}